@@ -0,0 +1,234 @@
+use crate::mbc::{self, MbcState, MBC};
+use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The memory bank controller used by a cartridge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapperKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Mmm01,
+}
+
+/// Whether the checksums in the cartridge header match the ROM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checksums {
+    /// Checked by the boot ROM, and on load unless the checksum check is skipped
+    pub header: bool,
+    /// Not checked by the hardware, so many working ROMs get it wrong
+    pub global: bool,
+}
+
+/// A cartridge: the ROM, its mapper and the cartridge RAM, independent of a running machine
+pub struct Cartridge {
+    pub(crate) mbc: Box<dyn MBC>,
+}
+
+impl Cartridge {
+    pub fn from_bytes(data: Vec<u8>, skip_checksum: bool) -> StrResult<Cartridge> {
+        mbc::get_mbc(data, skip_checksum).map(|mbc| Cartridge { mbc })
+    }
+
+    /// Battery backed RAM is loaded from and saved to a `.gbsave` file next to the ROM
+    #[cfg(feature = "file-io")]
+    pub fn from_file(romname: &str, skip_checksum: bool) -> StrResult<Cartridge> {
+        let mbc = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
+        Ok(Cartridge { mbc: Box::new(mbc) })
+    }
+
+    /// Like `from_file`, but with the save file at `savepath` instead of next to the ROM
+    #[cfg(feature = "file-io")]
+    pub fn from_file_with_save(
+        romname: &str,
+        savepath: &std::path::Path,
+        skip_checksum: bool,
+    ) -> StrResult<Cartridge> {
+        let data = mbc::read_rom(romname.as_ref())?;
+        Cartridge::from_bytes_with_save(data, savepath, skip_checksum)
+    }
+
+    /// Battery backed RAM is loaded from and saved to `savepath`
+    #[cfg(feature = "file-io")]
+    pub fn from_bytes_with_save(
+        data: Vec<u8>,
+        savepath: &std::path::Path,
+        skip_checksum: bool,
+    ) -> StrResult<Cartridge> {
+        let mbc = mbc::FileBackedMBC::with_save(data, savepath.into(), skip_checksum)?;
+        Ok(Cartridge { mbc: Box::new(mbc) })
+    }
+
+    pub(crate) fn fork(&self) -> Cartridge {
+        Cartridge {
+            mbc: self.mbc.fork(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> MbcState {
+        self.mbc.state()
+    }
+
+    pub(crate) fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        self.mbc.load_state(state)
+    }
+
+    /// The global checksum from the header at 0x14E-0x14F, which is not checked by the hardware
+    pub fn global_checksum(&self) -> u16 {
+        let header = self.header_start();
+        u16::from_be_bytes([self.rom()[header + 0x14E], self.rom()[header + 0x14F]])
+    }
+
+    pub fn verify_checksums(&self) -> Checksums {
+        let header = self.header_start();
+        let rom = self.rom();
+        let sum = rom
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != header + 0x14E && i != header + 0x14F)
+            .fold(0u16, |sum, (_, &v)| sum.wrapping_add(v as u16));
+        Checksums {
+            header: rom[header + 0x14D] == mbc::header_checksum(&rom[header..]),
+            global: self.global_checksum() == sum,
+        }
+    }
+
+    pub fn title(&self) -> String {
+        self.mbc.romname()
+    }
+
+    /// The cartridge header, 0x100-0x14F, or the one of the menu of an MMM01 compilation
+    pub fn header(&self) -> &[u8] {
+        let header = self.header_start();
+        &self.rom()[header + 0x100..header + 0x150]
+    }
+
+    fn header_start(&self) -> usize {
+        self.mbc.header_start()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        self.mbc.rom()
+    }
+
+    /// Whether the header marks the game as using color features, with or without support for
+    /// the classic Gameboy
+    pub fn supports_color(&self) -> bool {
+        self.header()[0x43] & 0x80 == 0x80
+    }
+
+    pub fn kind(&self) -> MapperKind {
+        // Other values are rejected when the cartridge is loaded
+        match self.header()[0x47] {
+            0x01..=0x03 => MapperKind::Mbc1,
+            0x05..=0x06 => MapperKind::Mbc2,
+            0x0B..=0x0D => MapperKind::Mmm01,
+            0x0F..=0x13 => MapperKind::Mbc3,
+            0x19..=0x1E => MapperKind::Mbc5,
+            _ => MapperKind::RomOnly,
+        }
+    }
+
+    pub fn is_battery_backed(&self) -> bool {
+        self.mbc.is_battery_backed()
+    }
+
+    pub fn load_ram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+        self.mbc.loadram(ramdata)
+    }
+
+    pub fn dump_ram(&self) -> Vec<u8> {
+        self.mbc.dumpram()
+    }
+
+    /// Writes battery backed RAM to the save file of a cartridge from `from_file`
+    pub fn flush(&mut self) -> StrResult<()> {
+        self.mbc.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cartridge, Checksums, MapperKind};
+    use crate::mbc::header_checksum;
+
+    fn rom_with_checksums() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13C].copy_from_slice(b"TESTCART");
+        rom[0x14D] = header_checksum(&rom);
+        let sum = rom.iter().fold(0u16, |sum, &v| sum.wrapping_add(v as u16));
+        rom[0x14E..0x150].copy_from_slice(&sum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn bad_global_checksum_loads() {
+        let cart = Cartridge::from_bytes(rom_with_checksums(), false).unwrap();
+        let good = Checksums {
+            header: true,
+            global: true,
+        };
+        assert_eq!(cart.verify_checksums(), good);
+
+        let mut rom = rom_with_checksums();
+        rom[0x4000] = 0x01;
+        let cart = Cartridge::from_bytes(rom, false).unwrap();
+        assert!(cart.verify_checksums().header);
+        assert!(!cart.verify_checksums().global);
+    }
+
+    #[test]
+    fn bad_header_checksum_fails() {
+        let mut rom = rom_with_checksums();
+        rom[0x14D] ^= 0xFF;
+        assert!(Cartridge::from_bytes(rom.clone(), false).is_err());
+
+        let cart = Cartridge::from_bytes(rom, true).unwrap();
+        assert!(!cart.verify_checksums().header);
+    }
+
+    #[cfg(feature = "file-io")]
+    #[test]
+    fn explicit_save_path() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let dir = std::env::temp_dir().join(format!("rboy_save_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rompath = dir.join("game.gb");
+        let savepath = dir.join("other.sav");
+        std::fs::write(&rompath, rom).unwrap();
+
+        let romname = rompath.to_str().unwrap();
+        let mut cart = Cartridge::from_file_with_save(romname, &savepath, true).unwrap();
+        cart.load_ram(&[0x42; 0x2000]).unwrap();
+        cart.flush().unwrap();
+        drop(cart);
+        assert!(!dir.join("game.gbsave").exists());
+        assert_eq!(std::fs::read(&savepath).unwrap(), vec![0x42; 0x2000]);
+
+        let cart = Cartridge::from_file_with_save(romname, &savepath, true).unwrap();
+        assert_eq!(cart.dump_ram()[0], 0x42);
+        drop(cart);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inspect_cartridge() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13C].copy_from_slice(b"TESTCART");
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let cart = Cartridge::from_bytes(rom, true).unwrap();
+
+        assert_eq!(cart.title(), "TESTCART");
+        assert_eq!(cart.kind(), MapperKind::Mbc1);
+        assert_eq!(cart.header().len(), 0x50);
+        assert!(cart.is_battery_backed());
+        assert_eq!(cart.dump_ram(), vec![0; 0x2000]);
+    }
+}
@@ -0,0 +1,42 @@
+use alloc::boxed::Box;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of wall clock time, used by the cartridge real time clock
+pub trait Clock: Send {
+    /// The time since the unix epoch
+    fn now(&self) -> Duration;
+}
+
+#[cfg(feature = "std")]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(t) => t,
+            Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
+        }
+    }
+}
+
+// Without `std` there is no clock to read until the host provides one
+#[cfg(not(feature = "std"))]
+struct StoppedClock;
+
+#[cfg(not(feature = "std"))]
+impl Clock for StoppedClock {
+    fn now(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// The system clock, or a clock that stands still at the unix epoch without `std`
+pub fn default_clock() -> Box<dyn Clock> {
+    #[cfg(feature = "std")]
+    return Box::new(SystemClock);
+    #[cfg(not(feature = "std"))]
+    return Box::new(StoppedClock);
+}
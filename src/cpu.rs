@@ -1,61 +1,284 @@
-use crate::mbc;
-use crate::mmu::MMU;
+use crate::cartridge::Cartridge;
+use crate::gbmode::Model;
+use crate::mmu::{MmuState, MMU};
 use crate::register::CpuFlag::{C, H, N, Z};
 use crate::register::Registers;
 use crate::serial::SerialCallback;
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
 
 /// The LR35902 frequency in MHz
 pub const CPU_FREQUENCY: f64 = 4_194_304.0;
 
+/// A snapshot of the interrupt registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InterruptState {
+    /// The IE register at 0xFFFF
+    pub enabled: u8,
+    /// The IF register at 0xFF0F
+    pub requested: u8,
+    /// The interrupt master enable flag
+    pub ime: bool,
+}
+
+/// What the CPU does with the opcodes that do not exist, like 0xD3
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IllegalOpcodePolicy {
+    /// Stop executing until reset, like the hardware does
+    Hang,
+    /// Skip the opcode
+    Nop,
+    /// Stay on the opcode and report it through `Device::illegal_opcode_hit`
+    Break,
+}
+
+/// The registers and the upcoming code, taken right before an instruction runs
+///
+/// Displays in the format used by Gameboy Doctor, with every value in upper case hexadecimal and
+/// PCMEM holding the four bytes starting at PC:
+///
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceLine {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub pcmem: [u8; 4],
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+             PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.a,
+            self.f,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.sp,
+            self.pc,
+            self.pcmem[0],
+            self.pcmem[1],
+            self.pcmem[2],
+            self.pcmem[3]
+        )
+    }
+}
+
+pub type TraceLogger = Box<dyn FnMut(&TraceLine) + Send>;
+
+/// The registers and the whole machine behind the CPU, for save states
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    reg: Registers,
+    halted: bool,
+    stopped: bool,
+    hung: bool,
+    ime: bool,
+    setdi: u32,
+    setei: u32,
+    mmu: MmuState,
+}
+
 pub struct CPU<'a> {
     reg: Registers,
     pub mmu: MMU<'a>,
     halted: bool,
+    stopped: bool,
+    hung: bool,
+    pub illegal_opcode_policy: IllegalOpcodePolicy,
+    pub illegal_opcode_hit: Option<u16>,
+    pub trace_logger: Option<TraceLogger>,
     ime: bool,
     setdi: u32,
     setei: u32,
+    last_ticks: u32,
 }
 
 impl<'a> CPU<'a> {
-    pub fn new(
-        cart: Box<dyn mbc::MBC + 'static>,
+    pub fn new(cart: Cartridge, serial_callback: Option<SerialCallback<'a>>) -> StrResult<CPU<'a>> {
+        CPU::new_with_model(cart, serial_callback, Model::Dmg)
+    }
+
+    pub fn new_cgb(
+        cart: Cartridge,
         serial_callback: Option<SerialCallback<'a>>,
     ) -> StrResult<CPU<'a>> {
-        let cpu_mmu = MMU::new(cart, serial_callback)?;
-        let registers = Registers::new(cpu_mmu.gbmode);
-        Ok(CPU {
-            reg: registers,
-            halted: false,
-            ime: true,
-            setdi: 0,
-            setei: 0,
-            mmu: cpu_mmu,
-        })
+        CPU::new_with_model(cart, serial_callback, Model::Cgb)
     }
 
-    pub fn new_cgb(
-        cart: Box<dyn mbc::MBC + 'static>,
+    pub fn new_with_model(
+        cart: Cartridge,
         serial_callback: Option<SerialCallback<'a>>,
+        model: Model,
     ) -> StrResult<CPU<'a>> {
-        let cpu_mmu = MMU::new_cgb(cart, serial_callback)?;
-        let registers = Registers::new(cpu_mmu.gbmode);
+        let cpu_mmu = MMU::new_with_model(cart, serial_callback, model)?;
+        let registers = Registers::new(model, cpu_mmu.gbmode);
         Ok(CPU {
             reg: registers,
             halted: false,
+            stopped: false,
+            hung: false,
+            illegal_opcode_policy: IllegalOpcodePolicy::Hang,
+            illegal_opcode_hit: None,
+            trace_logger: None,
             ime: true,
             setdi: 0,
             setei: 0,
+            last_ticks: 0,
             mmu: cpu_mmu,
         })
     }
 
+    pub fn fork(&self) -> CPU<'a> {
+        CPU {
+            mmu: self.mmu.fork(),
+            trace_logger: None,
+            ..*self
+        }
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            reg: self.reg,
+            halted: self.halted,
+            stopped: self.stopped,
+            hung: self.hung,
+            ime: self.ime,
+            setdi: self.setdi,
+            setei: self.setei,
+            mmu: self.mmu.state(),
+        }
+    }
+
+    pub fn load_state(&mut self, state: CpuState) -> StrResult<()> {
+        self.mmu.load_state(state.mmu)?;
+        self.reg = state.reg;
+        self.halted = state.halted;
+        self.stopped = state.stopped;
+        self.hung = state.hung;
+        self.ime = state.ime;
+        self.setdi = state.setdi;
+        self.setei = state.setei;
+        Ok(())
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+
+    /// Whether HALT is waiting for an interrupt
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether STOP is waiting for a button press
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn interrupt_state(&self) -> InterruptState {
+        InterruptState {
+            enabled: self.mmu.inte,
+            requested: self.mmu.intf,
+            ime: self.ime,
+        }
+    }
+
+    /// Restarts execution at 0x0000 in the given boot ROM, which sets up the registers itself
+    pub fn start_boot_rom(&mut self, rom: Vec<u8>) {
+        self.mmu.map_boot_rom(rom);
+        self.reg.pc = 0x0000;
+        self.set_ime(false);
+    }
+
+    /// Starts a stand-in for the boot ROM that waits `frames` frames and then hands over to the
+    /// cartridge with the registers unchanged
+    pub fn start_logo_wait(&mut self, frames: u8) {
+        let r = self.reg;
+        let [af_lo, af_hi] = r.af().to_le_bytes();
+        let [bc_lo, bc_hi] = r.bc().to_le_bytes();
+        let [de_lo, de_hi] = r.de().to_le_bytes();
+        let [hl_lo, hl_hi] = r.hl().to_le_bytes();
+        let [sp_lo, sp_hi] = r.sp.to_le_bytes();
+        let code = [
+            0x06, frames, // LD B,frames
+            0xF0, 0x44, // LDH A,(LY)
+            0xFE, 0x90, // CP 144
+            0x20, 0xFA, // JR NZ,-6
+            0xF0, 0x44, // LDH A,(LY)
+            0xFE, 0x90, // CP 144
+            0x28, 0xFA, // JR Z,-6
+            0x05, // DEC B
+            0x20, 0xF1, // JR NZ,-15
+            0x31, sp_lo, sp_hi, // LD SP,sp
+            0x01, af_lo, af_hi, // LD BC,af
+            0xC5,  // PUSH BC
+            0xF1,  // POP AF
+            0x01, bc_lo, bc_hi, // LD BC,bc
+            0x11, de_lo, de_hi, // LD DE,de
+            0x21, hl_lo, hl_hi, // LD HL,hl
+        ];
+        let mut rom = vec![0; 0x100];
+        rom[..code.len()].copy_from_slice(&code);
+        // LDH (0xFF50),A unmaps the boot ROM, which works as A is odd after every boot ROM
+        rom[0xFE..].copy_from_slice(&[0xE0, 0x50]);
+
+        self.mmu.map_boot_rom(rom);
+        self.reg.pc = 0x0000;
+    }
+
+    /// Also cancels a pending EI or DI
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+        self.setei = 0;
+        self.setdi = 0;
+    }
+
+    /// Runs one instruction and returns the time it took in cycles of `CPU_FREQUENCY`, so half
+    /// of the CPU cycles in double speed mode, plus any HDMA stall
     pub fn do_cycle(&mut self) -> u32 {
         let ticks = self.docycle() * 4;
+        self.last_ticks = ticks;
+        if self.stopped {
+            return self.mmu.stopped_cycle(ticks);
+        }
         return self.mmu.do_cycle(ticks);
     }
 
+    /// The cycles of the CPU clock used by the last instruction, which do not depend on the speed
+    pub fn last_instruction_cycles(&self) -> u32 {
+        self.last_ticks
+    }
+
     fn docycle(&mut self) -> u32 {
+        if self.hung {
+            // Not even interrupts get the CPU going again
+            return 1;
+        }
+        if self.stopped {
+            // Only a pressed button in a selected row wakes the CPU up
+            if self.mmu.keypad.rb() & 0x0F == 0x0F {
+                return 1;
+            }
+            self.stopped = false;
+        }
+
         self.updateime();
         match self.handleinterrupt() {
             0 => {}
@@ -66,10 +289,42 @@ impl<'a> CPU<'a> {
             // Emulate an noop instruction
             1
         } else {
+            if self.trace_logger.is_some() {
+                self.trace();
+            }
             self.call()
         }
     }
 
+    fn trace(&mut self) {
+        let line = self.registers();
+        if let Some(logger) = self.trace_logger.as_mut() {
+            logger(&line);
+        }
+    }
+
+    pub fn registers(&self) -> TraceLine {
+        let r = self.reg;
+        let [_, f] = r.af().to_be_bytes();
+        let mut pcmem = [0; 4];
+        for (i, v) in pcmem.iter_mut().enumerate() {
+            *v = self.mmu.peek(r.pc.wrapping_add(i as u16));
+        }
+        TraceLine {
+            a: r.a,
+            f,
+            b: r.b,
+            c: r.c,
+            d: r.d,
+            e: r.e,
+            h: r.h,
+            l: r.l,
+            sp: r.sp,
+            pc: r.pc,
+            pcmem,
+        }
+    }
+
     fn fetchbyte(&mut self) -> u8 {
         let b = self.mmu.rb(self.reg.pc);
         self.reg.pc = self.reg.pc.wrapping_add(1);
@@ -209,7 +464,11 @@ impl<'a> CPU<'a> {
                 1
             }
             0x10 => {
-                self.mmu.switch_speed();
+                // The divider is reset, with or without a speed switch
+                self.mmu.timer.wb(0xFF04, 0);
+                if !self.mmu.switch_speed() {
+                    self.stopped = true;
+                }
                 1
             } // STOP
             0x11 => {
@@ -1236,10 +1495,22 @@ impl<'a> CPU<'a> {
                 self.reg.pc = 0x38;
                 4
             }
-            other => panic!("Instruction {:2X} is not implemented", other),
+            _ => self.illegal_opcode(),
         }
     }
 
+    fn illegal_opcode(&mut self) -> u32 {
+        match self.illegal_opcode_policy {
+            IllegalOpcodePolicy::Hang => self.hung = true,
+            IllegalOpcodePolicy::Nop => {}
+            IllegalOpcodePolicy::Break => {
+                self.reg.pc = self.reg.pc.wrapping_sub(1);
+                self.illegal_opcode_hit = Some(self.reg.pc);
+            }
+        }
+        1
+    }
+
     fn call_cb(&mut self) -> u32 {
         let opcode = self.fetchbyte();
         match opcode {
@@ -2525,10 +2796,12 @@ impl<'a> CPU<'a> {
     }
 }
 
-#[cfg(test)]
+// The test roms are loaded from disk
+#[cfg(all(test, feature = "file-io"))]
 mod test {
     use super::CPU;
-    use crate::mbc;
+    use crate::cartridge::Cartridge;
+    use crate::register::CpuFlag::{C, H, N, Z};
 
     const CPUINSTRS: &'static str = "roms/cpu_instrs.gb";
     const CPU_SERIAL: &'static [u8] = b"cpu_instrs\n\n01:ok  02:ok  03:ok  04:ok  05:ok  06:ok  07:ok  08:ok  09:ok  10:ok  11:ok  \n\nPassed all tests\n";
@@ -2545,8 +2818,8 @@ mod test {
                 output.push(v);
                 None
             };
-            let cart = mbc::FileBackedMBC::new(CPUINSTRS.into(), false).unwrap();
-            let mut c = match CPU::new(Box::new(cart), Some(Box::new(serial))) {
+            let cart = Cartridge::from_file(CPUINSTRS, false).unwrap();
+            let mut c = match CPU::new(cart, Some(Box::new(serial))) {
                 Err(message) => {
                     panic!("{}", message);
                 }
@@ -2582,8 +2855,8 @@ mod test {
                 output.push(v);
                 None
             };
-            let cart = mbc::FileBackedMBC::new(CPUINSTRS.into(), false).unwrap();
-            let mut c = match CPU::new_cgb(Box::new(cart), Some(Box::new(serial))) {
+            let cart = Cartridge::from_file(CPUINSTRS, false).unwrap();
+            let mut c = match CPU::new_cgb(cart, Some(Box::new(serial))) {
                 Err(message) => {
                     panic!("{}", message);
                 }
@@ -2608,4 +2881,59 @@ mod test {
             "GPU did not produce expected graphics"
         );
     }
+
+    fn bcd(v: u32) -> u8 {
+        (((v / 10) << 4) | (v % 10)) as u8
+    }
+
+    #[test]
+    fn daa() {
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        for x in 0..100 {
+            for y in 0..100 {
+                for &carry in [false, true].iter() {
+                    let carry_in = carry as u32;
+                    c.reg.a = bcd(x);
+                    c.reg.flag(C, carry);
+                    c.alu_add(bcd(y), true);
+                    c.alu_daa();
+                    let sum = (x + y + carry_in) % 100;
+                    assert_eq!(c.reg.a, bcd(sum), "{} + {}", x, y);
+                    assert_eq!(c.reg.getflag(C), x + y + carry_in >= 100);
+                    assert_eq!(c.reg.getflag(Z), sum == 0);
+                    assert!(!c.reg.getflag(H));
+
+                    c.reg.a = bcd(x);
+                    c.reg.flag(C, carry);
+                    c.alu_sub(bcd(y), true);
+                    c.alu_daa();
+                    let diff = (100 + x - y - carry_in) % 100;
+                    assert_eq!(c.reg.a, bcd(diff), "{} - {}", x, y);
+                    assert_eq!(c.reg.getflag(C), x < y + carry_in);
+                    assert!(c.reg.getflag(N));
+                }
+            }
+        }
+
+        // Values that no BCD operation produces, from the truth table
+        for &(a, n, h, carry, result, carry_out) in [
+            (0x9A, false, false, false, 0x00, true),
+            (0x0F, false, false, false, 0x15, false),
+            (0xA0, false, false, false, 0x00, true),
+            (0x00, false, true, true, 0x66, true),
+            (0x00, true, true, true, 0x9A, true),
+            (0x0F, true, false, false, 0x0F, false),
+        ]
+        .iter()
+        {
+            c.reg.a = a;
+            c.reg.flag(N, n);
+            c.reg.flag(H, h);
+            c.reg.flag(C, carry);
+            c.alu_daa();
+            assert_eq!(c.reg.a, result, "DAA of {:02X}", a);
+            assert_eq!(c.reg.getflag(C), carry_out);
+        }
+    }
 }
@@ -1,20 +1,96 @@
-use std::time::Duration;
+use core::time::Duration;
 
-use crate::cpu::CPU;
-use crate::gbmode::GbMode;
-use crate::keypad::KeypadKey;
-use crate::mbc;
-use crate::printer::GbPrinter;
+use crate::cartridge::Cartridge;
+use crate::clock::Clock;
+#[cfg(feature = "std")]
+use crate::cpu::CPU_FREQUENCY;
+use crate::cpu::{CpuState, IllegalOpcodePolicy, InterruptState, TraceLine, TraceLogger, CPU};
+use crate::gbmode::{self, GbMode, GbSpeed, Model};
+use crate::gpu::{Frame, FrameCallback, Layer, LcdPowerCallback, PpuDebug, ScanlineCallback};
+use crate::infrared::InfraredCallback;
+use crate::keypad::{KeypadKey, KeypadState};
+use crate::mmu::{IoAccess, IoTrapCallback, WatchKind};
+use crate::overlay::DebugOverlay;
+use crate::palette::DmgPalette;
+#[cfg(feature = "std")]
+use crate::printer::{GbPrinter, PrintedImage, PrinterStatus};
+#[cfg(feature = "std")]
+use crate::ringbuffer::PullAudioPlayer;
+#[cfg(feature = "std")]
 use crate::sound;
+use crate::statedump::{StateDump, STACK_BYTES};
+use crate::timer::TimerState;
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
 pub const REFRESH_RATE: u64 = 60;
 pub const FRAME_DURATION: Duration = core::time::Duration::from_nanos(1_000_000_000 / REFRESH_RATE);
 
+// Number of cycles the GPU takes to draw one frame
+const FRAME_TICKS: u32 = 70224;
+
+// The real boot ROMs finish in less than three seconds
+const BOOT_TIMEOUT: u64 = FRAME_TICKS as u64 * 60 * 10;
+
+/// Everything needed to continue a game from the point it was captured at, except the ROM,
+/// which is only recorded by its title and checksum. Watchpoints, callbacks, the palette and
+/// the audio player belong to the host and are kept on restore. With the `serde` feature it
+/// can be stored in any format serde supports.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EmulatorState {
+    title: String,
+    global_checksum: u16,
+    total_cycles: u64,
+    frame_ticks: u32,
+    cpu: CpuState,
+}
+
+impl EmulatorState {
+    /// The title of the game the state was captured from
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn global_checksum(&self) -> u16 {
+        self.global_checksum
+    }
+}
+
 pub struct Device {
     pub cpu: CPU<'static>,
+    #[cfg(feature = "std")]
+    printer: Option<Arc<Mutex<GbPrinter>>>,
+    total_cycles: u64,
+    frame_ticks: u32,
+    ram_pokes: Vec<(u16, u8)>,
+    // The frame after which a real boot ROM is fast-forwarded
+    boot_logo_end: Option<u64>,
+    speed_multiplier: f32,
+    // When `run_one_frame_paced` should return from the current frame
+    #[cfg(feature = "std")]
+    frame_deadline: Option<Instant>,
+    #[cfg(feature = "std")]
+    pulled_audio: Option<PulledAudio>,
+}
+
+/// The samples waiting for `tick_audio_to`
+#[cfg(feature = "std")]
+struct PulledAudio {
+    buffer: Arc<Mutex<VecDeque<(f32, f32)>>>,
+    sample_rate: u32,
+    // Cycles times the sample rate that passed with the sound off, short of a whole sample
+    silence: u64,
 }
 
+#[cfg(feature = "std")]
 fn stdoutprinter(v: u8) -> Option<u8> {
     use std::io::Write;
 
@@ -25,31 +101,289 @@ fn stdoutprinter(v: u8) -> Option<u8> {
 }
 
 impl Device {
+    #[cfg(feature = "file-io")]
     pub fn new(romname: &str, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
-        CPU::new(Box::new(cart), None).map(|cpu| Device { cpu: cpu })
+        Device::new_from_cartridge(Cartridge::from_file(romname, skip_checksum)?)
     }
 
+    #[cfg(feature = "file-io")]
     pub fn new_cgb(romname: &str, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
-        CPU::new_cgb(Box::new(cart), None).map(|cpu| Device { cpu: cpu })
+        Device::new_cgb_from_cartridge(Cartridge::from_file(romname, skip_checksum)?)
+    }
+
+    /// Fails for a game that needs a color model on one of the other models
+    #[cfg(feature = "file-io")]
+    pub fn new_with_model(romname: &str, model: Model, skip_checksum: bool) -> StrResult<Device> {
+        Device::new_from_cartridge_with_model(Cartridge::from_file(romname, skip_checksum)?, model)
     }
 
     pub fn new_from_buffer(romdata: Vec<u8>, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::get_mbc(romdata, skip_checksum)?;
-        CPU::new(cart, None).map(|cpu| Device { cpu: cpu })
+        Device::new_from_cartridge(Cartridge::from_bytes(romdata, skip_checksum)?)
     }
 
     pub fn new_cgb_from_buffer(romdata: Vec<u8>, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::get_mbc(romdata, skip_checksum)?;
-        CPU::new_cgb(cart, None).map(|cpu| Device { cpu: cpu })
+        Device::new_cgb_from_cartridge(Cartridge::from_bytes(romdata, skip_checksum)?)
+    }
+
+    pub fn new_from_cartridge(cart: Cartridge) -> StrResult<Device> {
+        CPU::new(cart, None).map(Device::from_cpu)
+    }
+
+    pub fn new_cgb_from_cartridge(cart: Cartridge) -> StrResult<Device> {
+        CPU::new_cgb(cart, None).map(Device::from_cpu)
+    }
+
+    pub fn new_from_cartridge_with_model(cart: Cartridge, model: Model) -> StrResult<Device> {
+        CPU::new_with_model(cart, None, model).map(Device::from_cpu)
+    }
+
+    /// Runs color games in color mode and all other games in classic mode
+    pub fn new_auto_from_cartridge(cart: Cartridge) -> StrResult<Device> {
+        match cart.supports_color() {
+            true => Device::new_cgb_from_cartridge(cart),
+            false => Device::new_from_cartridge(cart),
+        }
+    }
+
+    fn from_cpu(cpu: CPU<'static>) -> Device {
+        Device {
+            cpu,
+            #[cfg(feature = "std")]
+            printer: None,
+            total_cycles: 0,
+            frame_ticks: 0,
+            ram_pokes: Vec::new(),
+            boot_logo_end: None,
+            speed_multiplier: 1.0,
+            #[cfg(feature = "std")]
+            frame_deadline: None,
+            #[cfg(feature = "std")]
+            pulled_audio: None,
+        }
+    }
+
+    /// An independent copy of the whole machine, for example to try different inputs from the
+    /// same point. The ROM is shared instead of copied. The sound hardware is copied but the copy
+    /// is not heard. Callbacks and an attached printer are not carried over, battery backed RAM
+    /// is not saved by the copy and the real time clock of the copy reads the system clock until
+    /// `set_clock_source` is used.
+    pub fn fork(&self) -> Device {
+        Device {
+            cpu: self.cpu.fork(),
+            #[cfg(feature = "std")]
+            printer: None,
+            ram_pokes: self.ram_pokes.clone(),
+            #[cfg(feature = "std")]
+            pulled_audio: None,
+            ..*self
+        }
+    }
+
+    pub fn capture_state(&self) -> EmulatorState {
+        EmulatorState {
+            title: self.cartridge().title(),
+            global_checksum: self.cartridge().global_checksum(),
+            total_cycles: self.total_cycles,
+            frame_ticks: self.frame_ticks,
+            cpu: self.cpu.state(),
+        }
+    }
+
+    /// Fails without changing anything when the state belongs to another game or to a
+    /// different Gameboy mode. The sound state is only restored while audio is enabled.
+    pub fn restore_state(&mut self, state: EmulatorState) -> StrResult<()> {
+        if state.title != self.cartridge().title()
+            || state.global_checksum != self.cartridge().global_checksum()
+        {
+            return Err("Save state belongs to a different game");
+        }
+        self.cpu.load_state(state.cpu)?;
+        self.total_cycles = state.total_cycles;
+        self.frame_ticks = state.frame_ticks;
+        Ok(())
     }
 
+    /// Runs one instruction, or one step of 4 cycles while halted, and returns the time it took
+    /// in T-cycles at the normal speed, of which `CPU_FREQUENCY` make up a second. In double speed
+    /// mode that is half of the CPU cycles, see `last_instruction_cycles`. An HDMA transfer that
+    /// stalls the CPU adds its time as well.
     pub fn do_cycle(&mut self) -> u32 {
-        self.cpu.do_cycle()
+        self.cpu.mmu.clear_watchpoint_hit();
+        self.cpu.illegal_opcode_hit = None;
+        let frame = self.cpu.mmu.gpu.frame_count;
+        let cycles = self.cpu.do_cycle();
+        self.total_cycles += cycles as u64;
+        if self.cpu.mmu.gpu.frame_count != frame {
+            self.apply_ram_pokes();
+            self.cpu.mmu.keypad.next_frame();
+            if matches!(self.boot_logo_end, Some(end) if self.frame_count() >= end) {
+                self.boot_logo_end = None;
+                // The game starts like normal if the boot ROM gets stuck
+                let _ = self.finish_boot_rom();
+            }
+        }
+        cycles
+    }
+
+    /// The T-cycles of the CPU clock used by the last instruction: 4 for a NOP, in double speed
+    /// mode as well
+    pub fn last_instruction_cycles(&self) -> u32 {
+        self.cpu.last_instruction_cycles()
+    }
+
+    /// Runs whole instructions until at least `budget` cycles have passed, and returns the number
+    /// of cycles actually run. A halted CPU still uses up the budget. Stops early when a
+    /// watchpoint or an illegal opcode under `IllegalOpcodePolicy::Break` is hit.
+    pub fn run_cycles(&mut self, budget: u32) -> u32 {
+        let mut cycles = 0;
+        while cycles < budget {
+            cycles += self.do_cycle();
+            if self.break_hit() {
+                break;
+            }
+        }
+        cycles
+    }
+
+    /// The number of cycles run since the device was created
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// The number of frames the GPU has started to display since the device was created
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.mmu.gpu.frame_count
+    }
+
+    /// Writes the given values every time a frame completes, which keeps them frozen from the
+    /// point of view of the game. An empty list disables this.
+    pub fn set_ram_pokes(&mut self, pokes: Vec<(u16, u8)>) {
+        self.ram_pokes = pokes;
     }
 
+    fn apply_ram_pokes(&mut self) {
+        for &(address, value) in self.ram_pokes.iter() {
+            self.cpu.mmu.wb(address, value);
+        }
+    }
+
+    /// Runs until the next frame has been drawn, or for one frame's worth of cycles while the
+    /// LCD is off. Returns whether a new frame is available from `get_gpu_data`. Stops early
+    /// when a watchpoint or an illegal opcode under `IllegalOpcodePolicy::Break` is hit.
+    pub fn step_frame(&mut self) -> bool {
+        let mut ticks = 0;
+        while ticks < FRAME_TICKS {
+            ticks += self.do_cycle();
+            if self.check_and_reset_gpu_updated() {
+                return true;
+            }
+            if self.break_hit() {
+                break;
+            }
+        }
+        false
+    }
+
+    /// Runs exactly one frame's worth of cycles and returns the screen. Cycles run past the end
+    /// of the frame by the last instruction are taken off the next frame.
+    pub fn run_one_frame(&mut self) -> &[u8] {
+        while self.frame_ticks < FRAME_TICKS {
+            self.frame_ticks += self.do_cycle();
+        }
+        self.frame_ticks -= FRAME_TICKS;
+        self.get_gpu_data()
+    }
+
+    /// Like `run_one_frame`, but then sleeps until the frame is due according to
+    /// `set_speed_multiplier`. A host that falls more than a frame behind starts counting again
+    /// instead of running frames back to back to catch up. Not available on
+    /// `wasm32-unknown-unknown`, which has no clock or sleep.
+    #[cfg(feature = "std")]
+    pub fn run_one_frame_paced(&mut self) -> &[u8] {
+        self.run_one_frame();
+        if let Some(target) = self.frame_target() {
+            let now = Instant::now();
+            let deadline = match self.frame_deadline {
+                Some(deadline) if deadline + target >= now => deadline + target,
+                _ => now + target,
+            };
+            self.frame_deadline = Some(deadline);
+            std::thread::sleep(deadline.saturating_duration_since(now));
+        }
+        self.get_gpu_data()
+    }
+
+    /// How fast `run_one_frame_paced` runs compared to a real Gameboy, where 2.0 is twice as
+    /// fast and 0.0 does not wait at all. Negative values count as 0.0. Audio stays at the
+    /// same pitch: above full speed, chunks of samples are skipped so the player receives about
+    /// as many as it plays, and while uncapped, samples are only sent when the player has run
+    /// out. Below full speed the player will run out, so `sync_audio` is worth calling when
+    /// going back to 1.0.
+    pub fn set_speed_multiplier(&mut self, multiplier: f32) {
+        self.speed_multiplier = multiplier.max(0.0);
+        #[cfg(feature = "std")]
+        {
+            self.frame_deadline = None;
+            if let Some(ref mut sound) = self.cpu.mmu.sound {
+                sound.set_speed(self.speed_multiplier);
+            }
+        }
+    }
+
+    pub fn speed_multiplier(&self) -> f32 {
+        self.speed_multiplier
+    }
+
+    /// The real time one frame should take at the current speed multiplier, or `None` while
+    /// the speed is uncapped
+    pub fn frame_target(&self) -> Option<Duration> {
+        match self.speed_multiplier {
+            m if m > 0.0 => Some(FRAME_DURATION.div_f32(m)),
+            _ => None,
+        }
+    }
+
+    /// Runs until the GPU enters vertical blank and requests the VBlank interrupt, so the
+    /// screen is complete and `current_scanline` is 144. Runs for one frame's worth of cycles
+    /// while the LCD is off. Stops early when a watchpoint or an illegal opcode under
+    /// `IllegalOpcodePolicy::Break` is hit.
+    pub fn run_to_vblank(&mut self) -> &[u8] {
+        let frame = self.frame_count();
+        let mut ticks = 0;
+        while ticks < FRAME_TICKS && self.frame_count() == frame {
+            ticks += self.do_cycle();
+            if self.break_hit() {
+                break;
+            }
+        }
+        self.get_gpu_data()
+    }
+
+    /// The line the GPU is currently drawing, the value of LY (0xFF44). 144 to 153 are the
+    /// vertical blank.
+    pub fn current_scanline(&self) -> u8 {
+        self.cpu.mmu.gpu.line
+    }
+
+    pub fn ppu_debug(&self) -> PpuDebug {
+        self.cpu.mmu.gpu.debug_state()
+    }
+
+    /// Plays back recorded input: every entry holds the buttons for that many frames. Returns
+    /// the screen after the last frame.
+    pub fn play_sequence(&mut self, script: &[(u32, KeypadState)]) -> Vec<u8> {
+        for &(frames, state) in script {
+            self.set_keypad_state(state);
+            for _ in 0..frames {
+                self.run_one_frame();
+            }
+        }
+        self.get_gpu_data().to_vec()
+    }
+
+    #[cfg(feature = "std")]
     pub fn set_stdout(&mut self, output: bool) {
+        self.printer = None;
         if output {
             self.cpu.mmu.serial.set_callback(Box::new(stdoutprinter));
         } else {
@@ -57,12 +391,42 @@ impl Device {
         }
     }
 
+    #[cfg(feature = "std")]
     pub fn attach_printer(&mut self) {
-        let mut printer = GbPrinter::new();
+        let printer = Arc::new(Mutex::new(GbPrinter::new()));
+        let serial_printer = printer.clone();
 
-        let printfun = move |v: u8| -> Option<u8> { Some(printer.send(v)) };
+        let printfun = move |v: u8| -> Option<u8> { Some(serial_printer.lock().unwrap().send(v)) };
 
         self.cpu.mmu.serial.set_callback(Box::new(printfun));
+        self.printer = Some(printer);
+    }
+
+    #[cfg(feature = "std")]
+    /// Returns `None` when no printer is attached
+    pub fn printer_status(&self) -> Option<PrinterStatus> {
+        self.printer.as_ref().map(|p| p.lock().unwrap().status())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn take_printed_image(&mut self) -> Option<PrintedImage> {
+        self.printer
+            .as_ref()
+            .and_then(|p| p.lock().unwrap().take_printed_image())
+    }
+
+    /// Sets whether light is arriving at the CGB infrared receiver
+    pub fn set_ir_input(&mut self, receiving: bool) {
+        self.cpu.mmu.infrared.set_input(receiving);
+    }
+
+    /// The callback is called whenever the game turns the infrared emitter on or off
+    pub fn set_ir_output_callback(&mut self, cb: InfraredCallback) {
+        self.cpu.mmu.infrared.set_callback(cb);
+    }
+
+    pub fn unset_ir_output_callback(&mut self) {
+        self.cpu.mmu.infrared.unset_callback();
     }
 
     pub fn check_and_reset_gpu_updated(&mut self) -> bool {
@@ -71,10 +435,106 @@ impl Device {
         result
     }
 
+    /// Whether a new frame was drawn, without clearing the flag like
+    /// `check_and_reset_gpu_updated` does
+    pub fn is_frame_ready(&self) -> bool {
+        self.cpu.mmu.gpu.updated
+    }
+
+    /// Whether VRAM was written since the last `reset_vram_dirty`, so a tile viewer only has to
+    /// redraw when something changed
+    pub fn vram_dirty(&self) -> bool {
+        self.cpu.mmu.gpu.vram_dirty
+    }
+
+    pub fn reset_vram_dirty(&mut self) {
+        self.cpu.mmu.gpu.vram_dirty = false;
+    }
+
     pub fn get_gpu_data(&self) -> &[u8] {
         &self.cpu.mmu.gpu.data
     }
 
+    /// The values for the debug overlay, except for the frame rate which is left at zero
+    pub fn debug_overlay(&self) -> DebugOverlay {
+        DebugOverlay {
+            fps: 0.0,
+            rom_bank: self.current_rom_bank(),
+            ly: self.ppu_debug().line,
+            pc: self.cpu.pc(),
+        }
+    }
+
+    /// A copy of `get_gpu_data` with the debug overlay drawn on top
+    pub fn get_gpu_data_with_overlay(&self, fps: f64) -> Vec<u8> {
+        let mut data = self.get_gpu_data().to_vec();
+        DebugOverlay {
+            fps,
+            ..self.debug_overlay()
+        }
+        .draw(&mut data);
+        data
+    }
+
+    /// The callback receives the same data as `get_gpu_data`, every time a frame completes
+    pub fn set_frame_callback(&mut self, cb: FrameCallback) {
+        self.cpu.mmu.gpu.set_frame_callback(cb);
+    }
+
+    pub fn unset_frame_callback(&mut self) {
+        self.cpu.mmu.gpu.unset_frame_callback();
+    }
+
+    /// The callback receives every line when it is drawn, before the game can change the
+    /// palettes or scrolling for the next one
+    pub fn set_scanline_callback(&mut self, cb: ScanlineCallback) {
+        self.cpu.mmu.gpu.set_scanline_callback(cb);
+    }
+
+    pub fn unset_scanline_callback(&mut self) {
+        self.cpu.mmu.gpu.unset_scanline_callback();
+    }
+
+    /// The callback receives `true` when the game turns the LCD on and `false` when it turns it
+    /// off. While the LCD is off the screen is blank.
+    pub fn set_lcd_power_callback(&mut self, cb: LcdPowerCallback) {
+        self.cpu.mmu.gpu.set_lcd_power_callback(cb);
+    }
+
+    pub fn unset_lcd_power_callback(&mut self) {
+        self.cpu.mmu.gpu.unset_lcd_power_callback();
+    }
+
+    /// The logger is called before every instruction the CPU runs, see `TraceLine` for the format.
+    /// Nothing is logged while the CPU is halted or an interrupt is being dispatched.
+    pub fn set_trace_logger(&mut self, logger: TraceLogger) {
+        self.cpu.trace_logger = Some(logger);
+    }
+
+    pub fn unset_trace_logger(&mut self) {
+        self.cpu.trace_logger = None;
+    }
+
+    /// Sets the colours used in classic mode. Has no effect on color games.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.cpu.mmu.gpu.dmg_palette = palette;
+    }
+
+    pub fn dmg_palette(&self) -> DmgPalette {
+        self.cpu.mmu.gpu.dmg_palette
+    }
+
+    /// Hides or shows the background, the window or the sprites independent of what the game
+    /// selects, to see what is drawn on which layer. All layers are shown by default.
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.cpu.mmu.gpu.set_layer_enabled(layer, enabled);
+    }
+
+    pub fn frame(&self) -> Frame<'_> {
+        Frame::new(&self.cpu.mmu.gpu.data)
+    }
+
+    #[cfg(feature = "std")]
     pub fn enable_audio(&mut self, player: Box<dyn sound::AudioPlayer>) {
         match self.cpu.mmu.gbmode {
             GbMode::Classic => {
@@ -84,14 +544,336 @@ impl Device {
                 self.cpu.mmu.sound = Some(sound::Sound::new_cgb(player));
             }
         };
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_speed(self.speed_multiplier);
+        }
+        self.pulled_audio = None;
+    }
+
+    /// Enables audio for `tick_audio_to`, for hosts that pull samples from an audio callback
+    /// instead of having them pushed to an `AudioPlayer`
+    #[cfg(feature = "std")]
+    pub fn enable_pull_audio(&mut self, sample_rate: u32) {
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let player = PullAudioPlayer {
+            buffer: buffer.clone(),
+            sample_rate,
+        };
+        self.enable_audio(Box::new(player));
+        self.pulled_audio = Some(PulledAudio {
+            buffer,
+            sample_rate,
+            silence: 0,
+        });
+    }
+
+    /// Runs the emulation until `target_samples` stereo frames are available and returns exactly
+    /// that many, with the left and right samples interleaved. Samples beyond that are kept for
+    /// the next call.
+    #[cfg(feature = "std")]
+    pub fn tick_audio_to(&mut self, target_samples: usize) -> StrResult<Vec<f32>> {
+        let mut pulled = self
+            .pulled_audio
+            .take()
+            .ok_or("Pull audio is not enabled")?;
+        while pulled.buffer.lock().unwrap().len() < target_samples {
+            let cycles = self.do_cycle();
+            // The sound hardware produces nothing while it is off or the CPU is in STOP, which the
+            // host hears as silence
+            let sound_on = matches!(self.cpu.mmu.sound, Some(ref sound) if sound.is_on());
+            if !sound_on || self.is_stopped() {
+                pulled.silence += cycles as u64 * pulled.sample_rate as u64;
+                let frames = pulled.silence / CPU_FREQUENCY as u64;
+                pulled.silence %= CPU_FREQUENCY as u64;
+                let mut buffer = pulled.buffer.lock().unwrap();
+                buffer.extend(core::iter::repeat_n((0.0, 0.0), frames as usize));
+            }
+        }
+        let samples = pulled
+            .buffer
+            .lock()
+            .unwrap()
+            .drain(..target_samples)
+            .flat_map(|(l, r)| [l, r])
+            .collect();
+        self.pulled_audio = Some(pulled);
+        Ok(samples)
     }
 
+    /// Drops the audio that was generated but not played yet, so the sound is in step with the
+    /// game again. Call it when the game ran faster than the player, as after fast forwarding,
+    /// or did not run for a while. A player without `AudioPlayer::clear` plays what it has
+    /// first, and gets no new samples until it runs out.
+    #[cfg(feature = "std")]
     pub fn sync_audio(&mut self) {
         if let Some(ref mut sound) = self.cpu.mmu.sound {
             sound.sync();
         }
     }
 
+    #[cfg(feature = "std")]
+    pub fn set_audio_buffer_target(&mut self, frames: usize) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_buffer_target(frames);
+        }
+    }
+
+    /// Drops the audio the player did not play yet, so pausing does not leave it looping over
+    /// old samples
+    #[cfg(feature = "std")]
+    pub fn clear_audio(&mut self) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.clear_player();
+        }
+    }
+
+    /// Keeps the audio player running, but only feeds it silence
+    #[cfg(feature = "std")]
+    pub fn set_audio_muted(&mut self, muted: bool) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_muted(muted);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn audio_muted(&self) -> bool {
+        self.cpu
+            .mmu
+            .sound
+            .as_ref()
+            .is_some_and(|sound| sound.is_muted())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn set_highpass(&mut self, enabled: bool) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_highpass(enabled);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    /// Channels are numbered 1 to 4. Taps only receive samples while audio is enabled.
+    pub fn set_channel_tap(&mut self, channel: u8, tap: sound::ChannelTap) -> StrResult<()> {
+        if !(1..=4).contains(&channel) {
+            return Err("Sound channels are numbered 1 to 4");
+        }
+        match self.cpu.mmu.sound {
+            Some(ref mut sound) => {
+                sound.set_channel_tap(channel as usize - 1, tap);
+                Ok(())
+            }
+            None => Err("Audio is not enabled"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn unset_channel_tap(&mut self, channel: u8) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            if (1..=4).contains(&channel) {
+                sound.unset_channel_tap(channel as usize - 1);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    /// Returns `None` while audio is disabled
+    pub fn sound_state(&self) -> Option<sound::SoundState> {
+        self.cpu.mmu.sound.as_ref().map(|sound| sound.save_state())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_sound_state(&mut self, state: &sound::SoundState) -> StrResult<()> {
+        match self.cpu.mmu.sound {
+            Some(ref mut sound) => sound.load_state(state),
+            None => Err("Audio is not enabled"),
+        }
+    }
+
+    /// Setting both `on_read` and `on_write` to false removes the watchpoint
+    pub fn add_watchpoint(&mut self, addr: u16, on_read: bool, on_write: bool) {
+        self.cpu.mmu.add_watchpoint(addr, on_read, on_write);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.cpu.mmu.remove_watchpoint(addr);
+    }
+
+    /// Lets the host emulate a peripheral at `addr`: the address no longer reaches the
+    /// hardware, reads return the value of `set_io_trap_value`, 0xFF at first, and every
+    /// trapped access is passed to the IO trap callback and kept for `take_io_accesses`
+    pub fn add_io_trap(&mut self, addr: u16, on_read: bool, on_write: bool) {
+        self.cpu.mmu.add_io_trap(addr, on_read, on_write);
+    }
+
+    pub fn remove_io_trap(&mut self, addr: u16) {
+        self.cpu.mmu.remove_io_trap(addr);
+    }
+
+    pub fn set_io_trap_value(&mut self, addr: u16, value: u8) {
+        self.cpu.mmu.set_io_trap_value(addr, value);
+    }
+
+    /// The trapped accesses since the last call, oldest first. Only the first 1024 are kept.
+    pub fn take_io_accesses(&mut self) -> Vec<IoAccess> {
+        self.cpu.mmu.take_io_accesses()
+    }
+
+    /// The callback is called during each trapped access, before the instruction goes on. What
+    /// it returns for a read is what the game reads.
+    pub fn set_io_trap_callback(&mut self, cb: IoTrapCallback) {
+        self.cpu.mmu.set_io_trap_callback(cb);
+    }
+
+    pub fn unset_io_trap_callback(&mut self) {
+        self.cpu.mmu.unset_io_trap_callback();
+    }
+
+    /// The first watched access made by the last call to `do_cycle`
+    pub fn watchpoint_hit(&self) -> Option<(u16, WatchKind)> {
+        self.cpu.mmu.watchpoint_hit()
+    }
+
+    /// The default is `IllegalOpcodePolicy::Hang`
+    pub fn set_illegal_opcode_policy(&mut self, policy: IllegalOpcodePolicy) {
+        self.cpu.illegal_opcode_policy = policy;
+    }
+
+    /// The address of the illegal opcode the last call to `do_cycle` stopped at, with
+    /// `IllegalOpcodePolicy::Break`
+    pub fn illegal_opcode_hit(&self) -> Option<u16> {
+        self.cpu.illegal_opcode_hit
+    }
+
+    fn break_hit(&self) -> bool {
+        self.watchpoint_hit().is_some() || self.illegal_opcode_hit().is_some()
+    }
+
+    pub fn interrupt_state(&self) -> InterruptState {
+        self.cpu.interrupt_state()
+    }
+
+    /// The registers, as the trace logger receives them
+    pub fn registers(&self) -> TraceLine {
+        self.cpu.registers()
+    }
+
+    /// Registers, interrupts, banks and the top of the stack, printable for a post-mortem
+    pub fn state_dump(&self) -> StateDump {
+        let registers = self.registers();
+        let mut stack = [0; STACK_BYTES];
+        for (i, v) in stack.iter_mut().enumerate() {
+            *v = self.cpu.mmu.peek(registers.sp.wrapping_add(i as u16));
+        }
+        StateDump {
+            registers,
+            interrupts: self.interrupt_state(),
+            halted: self.is_halted(),
+            rom_bank: self.current_rom_bank(),
+            ram_bank: self.current_ram_bank(),
+            wram_bank: self.wram_bank(),
+            vram_bank: self.vram_bank(),
+            ly: self.current_scanline(),
+            stack,
+        }
+    }
+
+    /// Whether the CPU executed HALT and waits for an interrupt. `do_cycle` keeps the rest of
+    /// the machine running meanwhile, so a host can run the emulator less eagerly.
+    pub fn is_halted(&self) -> bool {
+        self.cpu.is_halted()
+    }
+
+    /// A conservative estimate of how many cycles can pass before the timer or serial port
+    /// requests an interrupt or the GPU changes mode, at most one frame. Buttons can interrupt
+    /// sooner. 0 when an enabled interrupt is already waiting.
+    pub fn cycles_until_next_event(&self) -> u32 {
+        let mmu = &self.cpu.mmu;
+        if mmu.inte & mmu.intf & 0x1F != 0 {
+            return 0;
+        }
+        // Nothing but a button ends STOP
+        if self.is_stopped() {
+            return FRAME_TICKS;
+        }
+        // The timer and serial port count at the CPU clock, which is doubled in double speed
+        let speed = mmu.speed() as u32;
+        let cpu_events = [
+            mmu.timer.cycles_until_interrupt(),
+            mmu.serial.cycles_until_interrupt(),
+        ];
+        cpu_events
+            .iter()
+            .flatten()
+            .map(|cycles| cycles / speed)
+            .chain(mmu.gpu.cycles_until_mode_change())
+            .fold(FRAME_TICKS, u32::min)
+    }
+
+    /// Whether the CPU executed STOP without a speed switch, and waits for a button press
+    pub fn is_stopped(&self) -> bool {
+        self.cpu.is_stopped()
+    }
+
+    pub fn timer_state(&self) -> TimerState {
+        self.cpu.mmu.timer.state()
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.cpu.set_ime(ime);
+    }
+
+    /// Starts over in the given boot ROM, which hands over to the cartridge at 0x0100. It is
+    /// 256 bytes in classic mode and 2304 bytes in color mode.
+    pub fn start_boot_rom(&mut self, rom: Vec<u8>) -> StrResult<()> {
+        let size = match self.cpu.mmu.gbmode {
+            GbMode::Classic => 0x100,
+            GbMode::Color | GbMode::ColorAsClassic => 0x900,
+        };
+        if rom.len() != size {
+            return Err("Boot ROM has the wrong size");
+        }
+        self.cpu.start_boot_rom(rom);
+        Ok(())
+    }
+
+    /// Runs the boot ROM started with `start_boot_rom` to completion without waiting for its
+    /// animation, so the game starts with the state the boot ROM leaves behind
+    pub fn skip_boot_animation(&mut self) -> StrResult<()> {
+        self.finish_boot_rom()?;
+        self.check_and_reset_gpu_updated();
+        Ok(())
+    }
+
+    fn finish_boot_rom(&mut self) -> StrResult<()> {
+        let mut ticks = 0;
+        while self.cpu.mmu.boot_rom_mapped() {
+            if ticks > BOOT_TIMEOUT {
+                // A real boot ROM locks up when the logo in the header is wrong
+                return Err("Boot ROM did not finish");
+            }
+            ticks += self.do_cycle() as u64;
+        }
+        Ok(())
+    }
+
+    /// Shows the logo for `frames` frames before the game starts. A boot ROM started with
+    /// `start_boot_rom` runs for that many frames and then finishes without showing the rest
+    /// of its animation. Otherwise the logo from the cartridge header is shown on its own.
+    pub fn show_boot_logo(&mut self, frames: u8) {
+        if self.cpu.mmu.boot_rom_mapped() {
+            self.boot_logo_end = Some(self.frame_count() + frames as u64);
+            return;
+        }
+        if frames == 0 {
+            return;
+        }
+
+        let logo: Vec<u8> = self.cartridge().rom()[0x104..0x134].to_vec();
+        self.cpu.mmu.gpu.load_boot_logo(&logo);
+        // Interrupts are disabled in IE, so leaving IME as it is does no harm
+        self.cpu.start_logo_wait(frames);
+    }
+
     pub fn keyup(&mut self, key: KeypadKey) {
         self.cpu.mmu.keypad.keyup(key);
     }
@@ -100,23 +882,1324 @@ impl Device {
         self.cpu.mmu.keypad.keydown(key);
     }
 
+    /// Replaces the state of all buttons at once
+    pub fn set_keypad_state(&mut self, state: KeypadState) {
+        self.cpu.mmu.keypad.set_state(state);
+    }
+
+    /// Holds A, B, Select and Start for the next `frames` frames, which makes many games reset.
+    /// The buttons held by the player are left alone.
+    pub fn inject_soft_reset_combo(&mut self, frames: u32) {
+        let combo = KeypadState::A | KeypadState::B | KeypadState::SELECT | KeypadState::START;
+        self.cpu.mmu.keypad.inject(combo, frames);
+    }
+
+    pub fn keypad_state(&self) -> KeypadState {
+        self.cpu.mmu.keypad.state()
+    }
+
+    /// What the CPU reads from P1 (0xFF00): the selected button groups, with pressed buttons as
+    /// 0 bits
+    pub fn joypad_register(&self) -> u8 {
+        self.cpu.mmu.keypad.rb()
+    }
+
+    /// Selects the button groups that P1 reads, like a game writing to the register would
+    pub fn set_joypad_select(&mut self, action: bool, direction: bool) {
+        let value = if action { 0 } else { 0x20 } | if direction { 0 } else { 0x10 };
+        self.cpu.mmu.keypad.wb(value);
+    }
+
+    /// While `key` is held, it is pressed for `frames_on` frames and released for `frames_off`
+    /// frames. A `frames_off` of 0 turns autofire off again.
+    pub fn set_autofire(&mut self, key: KeypadKey, frames_on: u8, frames_off: u8) {
+        self.cpu.mmu.keypad.set_autofire(key, frames_on, frames_off);
+    }
+
+    /// When enabled, which is the default, the CPU cannot access VRAM and OAM while the GPU is
+    /// using them. Some games only work with the lax behavior.
+    pub fn set_strict_vram_access(&mut self, strict: bool) {
+        self.cpu.mmu.strict_vram_access = strict;
+    }
+
+    pub fn model(&self) -> Model {
+        self.cpu.mmu.model
+    }
+
+    pub fn speed(&self) -> GbSpeed {
+        self.cpu.mmu.speed()
+    }
+
+    /// The clock the CPU currently runs at, which doubles in CGB double speed mode
+    pub fn cpu_frequency(&self) -> f64 {
+        gbmode::cpu_frequency(self.speed())
+    }
+
+    pub fn wram_bank(&self) -> usize {
+        self.cpu.mmu.wram_bank()
+    }
+
+    /// Video RAM bank 0 or 1, without the access restrictions of reads through the CPU
+    pub fn vram(&self, bank: u8) -> &[u8] {
+        self.cpu.mmu.gpu.vram(bank)
+    }
+
+    /// Work RAM bank 0-7, 0xC000-0xCFFF is bank 0
+    pub fn wram(&self, bank: u8) -> &[u8] {
+        self.cpu.mmu.wram(bank)
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        self.cpu.mmu.gpu.oam()
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        self.cpu.mmu.hram()
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.cpu.mmu.gpu.vram_bank()
+    }
+
+    /// Replaces the system clock used by the cartridge real time clock
+    pub fn set_clock_source(&mut self, clock: Box<dyn Clock>) {
+        self.cpu.mmu.cartridge.mbc.set_clock(clock);
+    }
+
+    /// By default the real time clock of a loaded save includes the time the emulator was
+    /// closed. Disabling this resumes the clock where the save left it, for deterministic runs.
+    pub fn set_rtc_catch_up(&mut self, enabled: bool) {
+        self.cpu.mmu.cartridge.mbc.set_rtc_catch_up(enabled);
+    }
+
+    /// `Some(true)` makes the cartridge RAM accessible and `Some(false)` blocks it, whatever the
+    /// game wrote to the RAM enable register. `None` gives control back to the game.
+    pub fn set_ram_enable_override(&mut self, enabled: Option<bool>) {
+        self.cpu.mmu.cartridge.mbc.set_ram_override(enabled);
+    }
+
+    pub fn current_rom_bank(&self) -> u16 {
+        self.cpu.mmu.cartridge.mbc.rom_bank()
+    }
+
+    pub fn current_ram_bank(&self) -> u8 {
+        self.cpu.mmu.cartridge.mbc.ram_bank()
+    }
+
+    pub fn ram_enabled(&self) -> bool {
+        self.cpu.mmu.cartridge.mbc.ram_enabled()
+    }
+
+    /// Uses `banks` 8 KiB banks of cartridge RAM instead of the size given in the header, for
+    /// ROMs that declare the wrong size. Clears the RAM, and reloads an existing save file.
+    pub fn override_ram_size(&mut self, banks: usize) -> StrResult<()> {
+        self.cpu.mmu.cartridge.mbc.set_ram_banks(banks)
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cpu.mmu.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cpu.mmu.cartridge
+    }
+
     pub fn romname(&self) -> String {
-        self.cpu.mmu.mbc.romname()
+        self.cpu.mmu.cartridge.title()
     }
 
     pub fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
-        self.cpu.mmu.mbc.loadram(ramdata)
+        self.cpu.mmu.cartridge.load_ram(ramdata)
     }
 
     pub fn dumpram(&self) -> Vec<u8> {
-        self.cpu.mmu.mbc.dumpram()
+        self.cpu.mmu.cartridge.dump_ram()
+    }
+
+    /// The contents of the cartridge RAM, also for cartridges without a battery. Unlike
+    /// `dumpram`, this does not include the clock data of MBC3 saves.
+    pub fn dump_ram(&self) -> Vec<u8> {
+        self.cpu.mmu.cartridge.mbc.ram().to_vec()
+    }
+
+    pub fn load_ram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+        let ram = self.cpu.mmu.cartridge.mbc.ram_mut();
+        if ramdata.len() != ram.len() {
+            return Err("Loaded RAM has incorrect length");
+        }
+        ram.copy_from_slice(ramdata);
+        Ok(())
     }
 
     pub fn ram_is_battery_backed(&self) -> bool {
-        self.cpu.mmu.mbc.is_battery_backed()
+        self.cpu.mmu.cartridge.is_battery_backed()
+    }
+
+    /// Writes battery backed RAM to the save file of a game loaded with `new` or `new_cgb`.
+    /// Dropping the device does the same, but a process that gets killed never gets there, so
+    /// call this before quitting or every now and then.
+    pub fn on_shutdown(&mut self) -> StrResult<()> {
+        self.cpu.mmu.cartridge.flush()
     }
 
     pub fn check_and_reset_ram_updated(&mut self) -> bool {
-        self.cpu.mmu.mbc.check_and_reset_ram_updated()
+        self.cpu.mmu.cartridge.mbc.check_and_reset_ram_updated()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Device;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::IllegalOpcodePolicy;
+    use crate::gbmode::{GbMode, Model};
+    use crate::gpu::{Frame, Layer};
+    use crate::keypad::{KeypadKey, KeypadState};
+    use crate::mmu::{IoAccess, WatchKind};
+    use crate::ringbuffer::RingBufferAudioPlayer;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn step_frame_from_buffer() {
+        // A cartridge without an MBC that only runs NOPs
+        let rom = vec![0; 0x8000];
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+
+        assert!(device.step_frame());
+        assert_eq!(
+            device.get_gpu_data().len(),
+            crate::SCREEN_W * crate::SCREEN_H * 3
+        );
+    }
+
+    #[test]
+    fn run_cycles_budget() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+
+        let cycles = device.run_cycles(1000);
+        assert!(cycles >= 1000);
+        assert_eq!(device.total_cycles(), cycles as u64);
+
+        let more = device.run_cycles(1);
+        assert_eq!(device.total_cycles(), (cycles + more) as u64);
+    }
+
+    #[test]
+    fn ram_pokes_freeze_values() {
+        // Increments 0xC000 in a loop: INC (HL), JR -3
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+
+        device.set_ram_pokes(vec![(0xC000, 0x63)]);
+        for _ in 0..3 {
+            device.step_frame();
+            assert_eq!(device.cpu.mmu.rb(0xC000), 0x63);
+        }
+
+        device.set_ram_pokes(Vec::new());
+        device.step_frame();
+        assert_ne!(device.cpu.mmu.rb(0xC000), 0x63);
+    }
+
+    #[test]
+    fn interrupt_state_reports_registers() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.set_ime(false);
+        device.cpu.mmu.wb(0xFFFF, 0x01);
+        device.cpu.mmu.wb(0xFF0F, 0x01);
+
+        let state = device.interrupt_state();
+        assert_eq!(state.enabled & 0x01, 0x01);
+        assert_eq!(state.requested & 0x01, 0x01);
+        assert!(!state.ime);
+
+        device.set_ime(true);
+        assert!(device.interrupt_state().ime);
+    }
+
+    #[test]
+    fn io_trap_records_accesses() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.add_io_trap(0xC000, true, true);
+        device.set_io_trap_value(0xC000, 0x12);
+        device.cpu.mmu.wb(0xC000, 0x34);
+        assert_eq!(device.cpu.mmu.rb(0xC000), 0x12);
+        let access = |kind, value| IoAccess {
+            address: 0xC000,
+            kind,
+            value,
+        };
+        assert_eq!(
+            device.take_io_accesses(),
+            vec![
+                access(WatchKind::Write, 0x34),
+                access(WatchKind::Read, 0x12)
+            ]
+        );
+        assert!(device.take_io_accesses().is_empty());
+
+        // The write never reached work RAM
+        device.remove_io_trap(0xC000);
+        assert_ne!(device.cpu.mmu.rb(0xC000), 0x34);
+        assert!(device.take_io_accesses().is_empty());
+    }
+
+    #[test]
+    fn io_trap_callback_runs_during_access() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.add_io_trap(0xFF80, true, true);
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        device.set_io_trap_callback(Box::new(move |access| {
+            log.lock().unwrap().push(access);
+            Some(0x56)
+        }));
+        device.cpu.mmu.wb(0xFF80, 0x34);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(device.cpu.mmu.rb(0xFF80), 0x56);
+        assert_eq!(seen.lock().unwrap()[1].value, 0xFF);
+        assert_eq!(device.take_io_accesses()[1].value, 0x56);
+
+        // The list stops growing when nobody takes it
+        for _ in 0..2000 {
+            device.cpu.mmu.rb(0xFF80);
+        }
+        assert_eq!(device.take_io_accesses().len(), 1024);
+    }
+
+    #[test]
+    fn state_dump_does_not_trip_watchpoints() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.cpu.mmu.wb(0xFFFE, 0x12);
+        device.add_watchpoint(0xFFFE, true, false);
+        assert_eq!(device.state_dump().stack[0], 0x12);
+        assert_eq!(device.watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn write_watchpoint_trips() {
+        // LD HL,0xC000; NOP; NOP; LD (HL),A
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x107].copy_from_slice(&[0x21, 0x00, 0xC0, 0x00, 0x00, 0x77, 0x00]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+
+        device.add_watchpoint(0xC000, false, true);
+        // Stops right after the store
+        assert_eq!(device.run_cycles(1000), 12 + 4 + 4 + 8);
+        assert_eq!(device.watchpoint_hit(), Some((0xC000, WatchKind::Write)));
+
+        device.do_cycle();
+        assert_eq!(device.watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn rom_bank_follows_writes() {
+        // MBC1 with 4 ROM banks and one RAM bank
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x02;
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        assert_eq!(device.current_rom_bank(), 1);
+        assert!(!device.ram_enabled());
+
+        device.cpu.mmu.wb(0x2000, 0x03);
+        device.cpu.mmu.wb(0x0000, 0x0A);
+        assert_eq!(device.current_rom_bank(), 3);
+        assert_eq!(device.current_ram_bank(), 0);
+        assert!(device.ram_enabled());
+    }
+
+    #[test]
+    fn nop_cycles() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut device = Device::new_cgb_from_buffer(rom, true).unwrap();
+        assert_eq!(device.do_cycle(), 4);
+        assert_eq!(device.last_instruction_cycles(), 4);
+
+        device.cpu.mmu.wb(0xFF4D, 0x01);
+        assert!(device.cpu.mmu.switch_speed());
+        assert_eq!(device.do_cycle(), 2);
+        assert_eq!(device.last_instruction_cycles(), 4);
+    }
+
+    #[test]
+    fn vram_dirty_flag() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.set_strict_vram_access(false);
+        device.reset_vram_dirty();
+
+        device.cpu.mmu.wb(0x8010, 0x3C);
+        assert!(device.vram_dirty());
+        device.reset_vram_dirty();
+        assert!(!device.vram_dirty());
+
+        // Reads and writes elsewhere leave it alone
+        device.cpu.mmu.rb(0x8010);
+        device.cpu.mmu.wb(0xC000, 0x3C);
+        assert!(!device.vram_dirty());
+        device.cpu.mmu.wb(0x9800, 0x01);
+        assert!(device.vram_dirty());
+    }
+
+    #[test]
+    fn pull_audio_samples() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        assert!(device.tick_audio_to(16).is_err());
+
+        // Silence while the sound is off
+        device.enable_pull_audio(44100);
+        let samples = device.tick_audio_to(1024).unwrap();
+        assert_eq!(samples.len(), 2 * 1024);
+        assert!(samples.iter().all(|&v| v == 0.0));
+        let expected = 1024 * 4_194_304 / 44100;
+        assert!(device.total_cycles().abs_diff(expected) < 16);
+
+        // With the sound on, chunks mixed by the sound hardware are split up as needed
+        device.cpu.mmu.wb(0xFF26, 0x80);
+        device.set_speed_multiplier(0.0);
+        for _ in 0..3 {
+            assert_eq!(device.tick_audio_to(1024).unwrap().len(), 2 * 1024);
+        }
+
+        // STOP with the sound on, where nobody can press a button to wake the CPU
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0x80, 0xE0, 0x26, 0x10, 0x00]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.enable_pull_audio(44100);
+        let samples = device.tick_audio_to(1024).unwrap();
+        assert!(device.is_stopped());
+        assert_eq!(samples.len(), 2 * 1024);
+    }
+
+    #[cfg(feature = "file-io")]
+    #[test]
+    fn shutdown_writes_save() {
+        // MBC1 with RAM and a battery
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let dir = std::env::temp_dir().join(format!("rboy_shutdown_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rompath = dir.join("game.gb");
+        std::fs::write(&rompath, rom).unwrap();
+
+        let mut device = Device::new(rompath.to_str().unwrap(), true).unwrap();
+        device.cpu.mmu.wb(0x0000, 0x0A);
+        device.cpu.mmu.wb(0xA000, 0x42);
+        let dump = device.dumpram();
+        assert_eq!(dump[0], 0x42);
+
+        device.on_shutdown().unwrap();
+        assert_eq!(std::fs::read(dir.join("game.gbsave")).unwrap(), dump);
+        drop(device);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn debug_overlay_is_separate() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        for _ in 0..1000 {
+            device.do_cycle();
+        }
+        let plain = device.get_gpu_data().to_vec();
+        let overlay = device.get_gpu_data_with_overlay(59.7);
+
+        assert_eq!(overlay.len(), plain.len());
+        assert_ne!(overlay, plain);
+        assert_eq!(device.get_gpu_data(), &plain[..]);
+        assert_eq!(device.debug_overlay().pc, device.cpu.pc());
+        assert_eq!(device.debug_overlay().rom_bank, 1);
+    }
+
+    #[test]
+    fn ram_size_override() {
+        // MBC1 without RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x01;
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.override_ram_size(2).unwrap();
+
+        device.cpu.mmu.wb(0x0000, 0x0A);
+        device.cpu.mmu.wb(0x6000, 0x01);
+        device.cpu.mmu.wb(0xA000, 0x12);
+        device.cpu.mmu.wb(0x4000, 0x01);
+        device.cpu.mmu.wb(0xBFFF, 0x34);
+        assert_eq!(device.current_ram_bank(), 1);
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0x00);
+        assert_eq!(device.cpu.mmu.rb(0xBFFF), 0x34);
+
+        device.cpu.mmu.wb(0x4000, 0x00);
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0x12);
+        assert_eq!(device.cpu.mmu.rb(0xBFFF), 0x00);
+
+        // A cartridge without an MBC has nowhere to put RAM
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        assert!(device.override_ram_size(2).is_err());
+    }
+
+    #[test]
+    fn forks_run_independently() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10B].copy_from_slice(&[
+            0x3E, 0x20, // LD A,0x20
+            0xE0, 0x00, // LDH (0x00),A
+            0xF0, 0x00, // LDH A,(0x00)
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0x18, 0xF9, // JR -7
+        ]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.run_cycles(1000);
+        assert_eq!(device.cpu.mmu.rb(0xC000), 0xEF);
+
+        let mut right = device.fork();
+        let mut left = device.fork();
+        assert_eq!(
+            right.cartridge().rom().as_ptr(),
+            device.cartridge().rom().as_ptr()
+        );
+        right.keydown(KeypadKey::Right);
+        left.keydown(KeypadKey::Left);
+
+        let right = std::thread::spawn(move || {
+            right.run_cycles(1000);
+            right
+        });
+        left.run_cycles(1000);
+        let mut right = right.join().unwrap();
+
+        assert_eq!(right.cpu.mmu.rb(0xC000), 0xEE);
+        assert_eq!(left.cpu.mmu.rb(0xC000), 0xED);
+        assert_eq!(device.cpu.mmu.rb(0xC000), 0xEF);
+        assert_eq!(right.total_cycles(), left.total_cycles());
+    }
+
+    #[test]
+    fn forks_keep_sound() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.enable_audio(Box::new(RingBufferAudioPlayer::new(44100, 44100)));
+        device.cpu.mmu.wb(0xFF26, 0x80);
+        device.cpu.mmu.wb(0xFF12, 0xF0);
+        device.cpu.mmu.wb(0xFF11, 0x80);
+        device.cpu.mmu.wb(0xFF14, 0x80);
+        device.cpu.mmu.wb(0xFF30, 0x5A);
+        device.run_cycles(1000);
+
+        let mut fork = device.fork();
+        let registers = [0xFF26, 0xFF11, 0xFF12, 0xFF14, 0xFF25, 0xFF30];
+        for address in registers {
+            assert_eq!(fork.cpu.mmu.rb(address), device.cpu.mmu.rb(address));
+        }
+        assert_eq!(fork.cpu.mmu.rb(0xFF26), 0xF1);
+
+        fork.run_cycles(100_000);
+        device.run_cycles(100_000);
+        for address in registers {
+            assert_eq!(fork.cpu.mmu.rb(address), device.cpu.mmu.rb(address));
+        }
+    }
+
+    #[test]
+    fn illegal_opcode_policies() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x109].copy_from_slice(&[
+            0xD3, // illegal
+            0x3E, 0x42, // LD A,0x42
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0x18, 0xFE, // JR -2
+            0x00,
+        ]);
+        let device_with = |policy| {
+            let mut device = Device::new_from_buffer(rom.clone(), true).unwrap();
+            device.cpu.mmu.wb(0xC000, 0x00);
+            device.set_illegal_opcode_policy(policy);
+            device
+        };
+
+        let mut device = device_with(IllegalOpcodePolicy::Hang);
+        assert_eq!(device.run_cycles(1000), 1000);
+        assert_eq!(device.illegal_opcode_hit(), None);
+        assert_eq!(device.cpu.mmu.rb(0xC000), 0x00);
+
+        let mut device = device_with(IllegalOpcodePolicy::Nop);
+        device.run_cycles(1000);
+        assert_eq!(device.cpu.mmu.rb(0xC000), 0x42);
+
+        let mut device = device_with(IllegalOpcodePolicy::Break);
+        assert_eq!(device.run_cycles(1000), 4);
+        assert_eq!(device.illegal_opcode_hit(), Some(0x100));
+        // Execution stays on the opcode
+        device.run_cycles(1000);
+        assert_eq!(device.illegal_opcode_hit(), Some(0x100));
+        assert_eq!(device.cpu.mmu.rb(0xC000), 0x00);
+    }
+
+    #[test]
+    fn joypad_register_layout() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.keydown(KeypadKey::Up);
+        device.keydown(KeypadKey::Left);
+        device.keydown(KeypadKey::Start);
+
+        device.set_joypad_select(false, true);
+        assert_eq!(device.joypad_register(), 0xE9);
+        device.set_joypad_select(true, false);
+        assert_eq!(device.joypad_register(), 0xD7);
+        device.set_joypad_select(true, true);
+        assert_eq!(device.joypad_register(), 0xC1);
+        device.set_joypad_select(false, false);
+        assert_eq!(device.joypad_register(), 0xFF);
+        assert_eq!(device.cpu.mmu.rb(0xFF00), 0xFF);
+    }
+
+    #[test]
+    fn raw_ram_without_battery() {
+        // MBC3 with RAM but no battery
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x12;
+        rom[0x149] = 0x02;
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        assert!(!device.ram_is_battery_backed());
+
+        device.cpu.mmu.wb(0x0000, 0x0A);
+        device.cpu.mmu.wb(0xA010, 0x42);
+        let dump = device.dump_ram();
+        assert_eq!(dump.len(), 0x2000);
+        assert_eq!(dump[0x10], 0x42);
+
+        device.cpu.mmu.wb(0xA010, 0x99);
+        assert_eq!(device.cpu.mmu.rb(0xA010), 0x99);
+        device.load_ram(&dump).unwrap();
+        assert_eq!(device.cpu.mmu.rb(0xA010), 0x42);
+
+        assert!(device.load_ram(&dump[1..]).is_err());
+    }
+
+    // Sets a few registers, waits for `frames` frames and hands over to the cartridge
+    fn test_boot_rom(frames: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x100];
+        let code = [
+            0x31, 0xFE, 0xFF, // LD SP,0xFFFE
+            0x3E, 0x54, // LD A,0x54
+            0xE0, 0x47, // LDH (0x47),A
+            0xE0, 0x80, // LDH (0x80),A
+            0x06, frames, // LD B,frames
+            0xF0, 0x44, // LDH A,(0x44)
+            0xFE, 0x90, // CP 144
+            0x20, 0xFA, // JR NZ,-6
+            0xF0, 0x44, // LDH A,(0x44)
+            0xFE, 0x90, // CP 144
+            0x28, 0xFA, // JR Z,-6
+            0x05, // DEC B
+            0x20, 0xF1, // JR NZ,-15
+        ];
+        rom[..code.len()].copy_from_slice(&code);
+        // LD A,1; LDH (0x50),A
+        rom[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        rom
+    }
+
+    #[test]
+    fn skip_boot_animation_matches_full_boot() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+
+        let mut full = Device::new_from_buffer(rom.clone(), true).unwrap();
+        full.start_boot_rom(test_boot_rom(30)).unwrap();
+        while full.cpu.mmu.boot_rom_mapped() {
+            full.do_cycle();
+            full.check_and_reset_gpu_updated();
+        }
+
+        let mut fast = Device::new_from_buffer(rom, true).unwrap();
+        fast.start_boot_rom(test_boot_rom(30)).unwrap();
+        fast.skip_boot_animation().unwrap();
+
+        assert!(fast.frame_count() >= 30);
+        assert_eq!(fast.total_cycles(), full.total_cycles());
+        assert_eq!(fast.cpu.mmu.rb(0x0100), 0x18);
+        for &address in [0xFF47, 0xFF80, 0xFF44, 0xFF0F].iter() {
+            assert_eq!(fast.cpu.mmu.rb(address), full.cpu.mmu.rb(address));
+        }
+        assert_eq!(fast.cpu.mmu.rb(0xFF80), 0x54);
+        assert_eq!(fast.interrupt_state(), full.interrupt_state());
+
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        assert!(device.start_boot_rom(vec![0; 0x900]).is_err());
+    }
+
+    #[test]
+    fn autofire_toggles_per_frame() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.set_autofire(KeypadKey::A, 2, 1);
+        // Select the action buttons
+        device.cpu.mmu.wb(0xFF00, 0x10);
+
+        device.keydown(KeypadKey::A);
+        let mut pressed = Vec::new();
+        for _ in 0..6 {
+            pressed.push(device.cpu.mmu.rb(0xFF00) & 0x01 == 0);
+            device.step_frame();
+        }
+        assert_eq!(pressed, [true, true, false, true, true, false]);
+        assert_eq!(device.keypad_state(), KeypadKey::A.into());
+
+        device.set_autofire(KeypadKey::A, 2, 0);
+        device.step_frame();
+        device.step_frame();
+        assert_eq!(device.cpu.mmu.rb(0xFF00) & 0x01, 0);
+    }
+
+    #[test]
+    fn mode_from_header() {
+        let mut rom = vec![0; 0x8000];
+        let cart = Cartridge::from_bytes(rom.clone(), true).unwrap();
+        let device = Device::new_auto_from_cartridge(cart).unwrap();
+        assert!(device.cpu.mmu.gbmode == GbMode::Classic);
+
+        for &flag in [0x80, 0xC0].iter() {
+            rom[0x143] = flag;
+            let cart = Cartridge::from_bytes(rom.clone(), true).unwrap();
+            let device = Device::new_auto_from_cartridge(cart).unwrap();
+            assert!(device.cpu.mmu.gbmode == GbMode::Color);
+        }
+    }
+
+    #[test]
+    fn color_only_game_per_model() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0xC0;
+        for &model in Model::ALL.iter() {
+            let cart = Cartridge::from_bytes(rom.clone(), true).unwrap();
+            let device = Device::new_from_cartridge_with_model(cart, model);
+            assert_eq!(device.is_ok(), model.is_color(), "{:?}", model);
+            if let Ok(device) = device {
+                assert_eq!(device.model(), model);
+                assert!(device.cpu.mmu.gbmode == GbMode::Color);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_slices() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut device = Device::new_cgb_from_buffer(rom, true).unwrap();
+        // Turn the LCD off so VRAM and OAM are writable
+        device.cpu.mmu.wb(0xFF40, 0x00);
+
+        device.cpu.mmu.wb(0xFF4F, 1);
+        device.cpu.mmu.wb(0x8010, 0x11);
+        device.cpu.mmu.wb(0xFE05, 0x22);
+        device.cpu.mmu.wb(0xFF70, 3);
+        device.cpu.mmu.wb(0xD020, 0x33);
+        device.cpu.mmu.wb(0xC020, 0x44);
+        device.cpu.mmu.wb(0xFF81, 0x55);
+
+        assert_eq!(device.vram(1)[0x10], 0x11);
+        assert_eq!(device.vram(0).len(), 0x2000);
+        assert_eq!(device.oam()[0x05], 0x22);
+        assert_eq!(device.wram(3)[0x20], 0x33);
+        assert_eq!(device.wram(0)[0x20], 0x44);
+        assert_eq!(device.hram()[0x01], 0x55);
+    }
+
+    #[test]
+    fn boot_logo_frames() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x104..0x134].copy_from_slice(&[0x5A; 0x30]);
+        // LD A,0x80; LDH (LCDC),A; JR -2
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0x80, 0xE0, 0x40, 0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.show_boot_logo(30);
+
+        let blank = |device: &Device| device.get_gpu_data().iter().all(|&v| v == 0xFF);
+        for _ in 0..30 {
+            assert!(device.step_frame());
+            assert!(!blank(&device));
+        }
+        // The game turns the background off
+        for _ in 0..3 {
+            assert!(device.step_frame());
+            assert!(blank(&device));
+        }
+    }
+
+    #[test]
+    fn post_boot_io_registers() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        // DIV, SC, STAT and DMA
+        let expected = [
+            (Model::Dmg, [0xAB, 0x7E, 0x85, 0xFF]),
+            (Model::Mgb, [0xAB, 0x7E, 0x85, 0xFF]),
+            (Model::Sgb, [0xAB, 0x7E, 0x86, 0xFF]),
+            (Model::Cgb, [0x00, 0x7F, 0x86, 0x00]),
+            (Model::Agb, [0x00, 0x7F, 0x86, 0x00]),
+        ];
+        for &(model, registers) in expected.iter() {
+            let cart = Cartridge::from_bytes(rom.clone(), true).unwrap();
+            let mut device = Device::new_from_cartridge_with_model(cart, model).unwrap();
+            let mmu = &mut device.cpu.mmu;
+            let read = [0xFF04, 0xFF02, 0xFF41, 0xFF46].map(|a| mmu.rb(a));
+            assert_eq!(read, registers, "{:?}", model);
+            assert_eq!(mmu.rb(0xFF0F), 0xE1);
+        }
+
+        // The DMG starts the next line like any other
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.cpu.mmu.do_cycle(4);
+        assert_eq!(device.cpu.mmu.rb(0xFF41), 0x86);
+        assert_eq!(device.cpu.mmu.rb(0xFF44), 0);
+    }
+
+    #[test]
+    fn frame_callback_once_per_frame() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        let frames = Arc::new(Mutex::new(0));
+        let counter = frames.clone();
+        device.set_frame_callback(Box::new(move |data| {
+            assert_eq!(data.len(), crate::SCREEN_W * crate::SCREEN_H * 3);
+            *counter.lock().unwrap() += 1;
+        }));
+
+        device.run_cycles(10 * super::FRAME_TICKS);
+        assert_eq!(*frames.lock().unwrap(), 10);
+        assert_eq!(device.frame_count(), 10);
+    }
+
+    #[test]
+    fn run_one_frame_keeps_pace() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+
+        for _ in 0..60 {
+            let data = device.run_one_frame();
+            assert_eq!(data.len(), crate::SCREEN_W * crate::SCREEN_H * 3);
+        }
+        assert_eq!(device.frame_count(), 60);
+        // Whatever ran past the last frame boundary belongs to the next frame
+        let expected = 60 * super::FRAME_TICKS as u64;
+        assert!(device.total_cycles() >= expected);
+        assert!(device.total_cycles() < expected + 24);
+    }
+
+    #[test]
+    fn frame_matches_gpu_data() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.step_frame();
+
+        let frame = device.frame();
+        assert_eq!((frame.width(), frame.height()), (160, 144));
+        assert_eq!(&frame.pixel(0, 0)[..], &device.get_gpu_data()[0..3]);
+        assert_eq!(frame.as_rgb(), device.get_gpu_data());
+    }
+
+    #[test]
+    fn run_to_vblank_stops_at_line_144() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom.clone(), true).unwrap();
+        // Only read by current_scanline
+        device.add_watchpoint(0xFF44, true, false);
+
+        for frame in 1..=3 {
+            device.run_to_vblank();
+            assert_eq!(device.current_scanline(), 144);
+            assert_eq!(device.watchpoint_hit(), None);
+            assert_eq!(device.frame_count(), frame);
+            assert_eq!(device.interrupt_state().requested & 0x01, 0x01);
+            device.cpu.mmu.wb(0xFF0F, 0);
+        }
+
+        // LD (0xC000),A; JR -5
+        rom[0x100..0x105].copy_from_slice(&[0xEA, 0x00, 0xC0, 0x18, 0xFB]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.run_to_vblank();
+        device.add_watchpoint(0xC000, false, true);
+        device.run_to_vblank();
+        assert!(device.watchpoint_hit().is_some());
+        assert_eq!(device.frame_count(), 1);
+    }
+
+    #[test]
+    fn div_write_resets_timer() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.cpu.mmu.wb(0xFF06, 0x80);
+        device.cpu.mmu.wb(0xFF07, 0x05);
+        device.run_cycles(1000);
+
+        let state = device.timer_state();
+        assert_eq!(state.div_counter >> 8, device.cpu.mmu.rb(0xFF04) as u16);
+        assert_ne!(state.div_counter & 0xFF, 0);
+        assert_eq!((state.tma, state.tac), (0x80, 0xFD));
+        assert_eq!(state.tima, device.cpu.mmu.rb(0xFF05));
+
+        device.cpu.mmu.wb(0xFF04, 0x12);
+        let state = device.timer_state();
+        assert_eq!(state.div_counter, 0);
+        assert!(!state.overflow_pending);
+
+        // TIMA counts a full period from the reset
+        let tima = state.tima;
+        device.cpu.mmu.do_cycle(15);
+        assert_eq!(device.timer_state().tima, tima);
+        device.cpu.mmu.do_cycle(1);
+        assert_eq!(device.timer_state().tima, tima.wrapping_add(1));
+    }
+
+    #[test]
+    fn soft_reset_combo_is_released() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.keydown(KeypadKey::Right);
+        device.set_joypad_select(true, false);
+        device.inject_soft_reset_combo(3);
+        assert_eq!(device.joypad_register() & 0x0F, 0x00);
+
+        for _ in 0..2 {
+            device.run_to_vblank();
+            assert_eq!(device.joypad_register() & 0x0F, 0x00);
+        }
+        device.run_to_vblank();
+        assert_eq!(device.joypad_register() & 0x0F, 0x0F);
+        assert_eq!(device.keypad_state(), KeypadState::RIGHT);
+    }
+
+    #[test]
+    fn lcd_power_blanks_screen() {
+        // JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let log = changes.clone();
+        device.set_lcd_power_callback(Box::new(move |on| log.lock().unwrap().push(on)));
+
+        // Darken every shade so the drawn frame differs from a blank one
+        device.cpu.mmu.wb(0xFF47, 0xFF);
+        device.run_to_vblank();
+        assert!(device.get_gpu_data().iter().all(|&v| v == 0));
+        device.check_and_reset_gpu_updated();
+
+        device.cpu.mmu.wb(0xFF40, 0x11);
+        assert!(device.check_and_reset_gpu_updated());
+        assert!(device.get_gpu_data().iter().all(|&v| v == 0xFF));
+        // Writes that keep the LCD off do not count
+        device.cpu.mmu.wb(0xFF40, 0x01);
+        device.cpu.mmu.wb(0xFF40, 0x91);
+        assert_eq!(*changes.lock().unwrap(), [false, true]);
+    }
+
+    #[test]
+    fn trace_lines() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[
+            0x00, // NOP
+            0x3E, 0x42, // LD A,0x42
+            0xC3, 0x00, 0x01, // JP 0x0100
+        ]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = lines.clone();
+        device.set_trace_logger(Box::new(move |line| {
+            log.lock().unwrap().push(line.to_string());
+        }));
+        // Only read by the trace
+        device.add_watchpoint(0x0106, true, false);
+
+        for _ in 0..4 {
+            device.do_cycle();
+            assert_eq!(device.watchpoint_hit(), None);
+        }
+        device.unset_trace_logger();
+        device.do_cycle();
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,3E,42,C3",
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:3E,42,C3,00",
+                "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:C3,00,01,00",
+                "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,3E,42,C3",
+            ]
+        );
+    }
+
+    fn frame_hash(frame: &[u8]) -> u64 {
+        // FNV-1a
+        frame.iter().fold(0xcbf29ce484222325, |hash, &v| {
+            (hash ^ v as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    #[test]
+    fn play_sequence_holds_buttons() {
+        // Wait for A, then turn every background shade black
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x111].copy_from_slice(&[
+            0x3E, 0x10, // LD A,0x10
+            0xE0, 0x00, // LDH (0x00),A
+            0xF0, 0x00, // LDH A,(0x00)
+            0x2F, // CPL
+            0xE6, 0x01, // AND 0x01
+            0x28, 0xF9, // JR Z,-7
+            0x3E, 0xFF, // LD A,0xFF
+            0xE0, 0x47, // LDH (0x47),A
+            0x18, 0xFE, // JR -2
+        ]);
+        let mut device = Device::new_from_buffer(rom.clone(), true).unwrap();
+        let black = frame_hash(&vec![0; crate::SCREEN_W * crate::SCREEN_H * 3]);
+
+        let frame = device.play_sequence(&[(5, KeypadState::empty()), (2, KeypadState::START)]);
+        assert_eq!(device.frame_count(), 7);
+        assert_ne!(frame_hash(&frame), black);
+
+        let frame = device.play_sequence(&[(1, KeypadState::A), (3, KeypadState::empty())]);
+        assert_eq!(device.frame_count(), 11);
+        assert_eq!(frame_hash(&frame), black);
+        assert_eq!(device.keypad_state(), KeypadState::empty());
+
+        // The same script gives the same screen
+        let mut replay = Device::new_from_buffer(rom, true).unwrap();
+        let script = [
+            (5, KeypadState::empty()),
+            (2, KeypadState::START),
+            (1, KeypadState::A),
+            (3, KeypadState::empty()),
+        ];
+        assert_eq!(
+            frame_hash(&replay.play_sequence(&script)),
+            frame_hash(&frame)
+        );
+    }
+
+    #[test]
+    fn speed_multiplier_paces_frames() {
+        use super::FRAME_DURATION;
+        use std::time::Instant;
+
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        assert_eq!(device.frame_target(), Some(FRAME_DURATION));
+
+        device.set_speed_multiplier(4.0);
+        assert_eq!(device.frame_target(), Some(FRAME_DURATION / 4));
+        let start = Instant::now();
+        for _ in 0..4 {
+            device.run_one_frame_paced();
+        }
+        assert!(start.elapsed() >= FRAME_DURATION);
+
+        device.set_speed_multiplier(0.0);
+        assert_eq!(device.frame_target(), None);
+        device.set_speed_multiplier(-1.0);
+        assert_eq!(device.speed_multiplier(), 0.0);
+    }
+
+    #[test]
+    fn halt_until_interrupt() {
+        // DI; HALT; JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xF3, 0x76, 0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom.clone(), true).unwrap();
+        device.cpu.mmu.wb(0xFFFF, 0x04);
+        device.cpu.mmu.wb(0xFF0F, 0x00);
+        device.cpu.mmu.wb(0xFF05, 0x00);
+        device.cpu.mmu.wb(0xFF07, 0x05);
+
+        device.run_cycles(100);
+        assert!(device.is_halted());
+        while device.interrupt_state().requested & 0x04 == 0 {
+            assert!(device.is_halted());
+            device.do_cycle();
+        }
+        device.do_cycle();
+        assert!(!device.is_halted());
+
+        // STOP; JR -2
+        rom[0x100..0x104].copy_from_slice(&[0x10, 0x00, 0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.cpu.mmu.wb(0xFF00, 0x10);
+        device.run_cycles(100);
+        assert!(device.is_stopped());
+        // The divider was reset, and neither it nor the LCD runs while stopped
+        let line = device.current_scanline();
+        device.run_cycles(10000);
+        assert!(device.is_stopped());
+        assert_eq!(device.timer_state().div_counter, 0);
+        assert_eq!(device.current_scanline(), line);
+        device.keydown(KeypadKey::A);
+        device.do_cycle();
+        assert!(!device.is_stopped());
+        device.run_cycles(1000);
+        assert_ne!(device.timer_state().div_counter, 0);
+        assert_ne!(device.current_scanline(), line);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn save_state_round_trip() {
+        // INC A; LD (0xC000),A; LDH (0x43),A; JR -8
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x108].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0xE0, 0x43, 0x18, 0xF8]);
+        rom[0x134..0x138].copy_from_slice(b"SAVE");
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut device = Device::new_from_buffer(rom.clone(), true).unwrap();
+        device.cpu.mmu.wb(0x0000, 0x0A);
+        device.cpu.mmu.wb(0xA000, 0x42);
+        for _ in 0..5 {
+            device.run_one_frame();
+        }
+
+        let bytes = bincode::serialize(&device.capture_state()).unwrap();
+        for _ in 0..3 {
+            device.run_one_frame();
+        }
+
+        let state: super::EmulatorState = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(state.title(), "SAVE");
+        let mut restored = Device::new_from_buffer(rom.clone(), true).unwrap();
+        restored.restore_state(state).unwrap();
+        assert_eq!(restored.cpu.mmu.rb(0xA000), 0x42);
+        for _ in 0..3 {
+            restored.run_one_frame();
+        }
+        assert_eq!(restored.total_cycles(), device.total_cycles());
+        assert_eq!(restored.cpu.mmu.rb(0xC000), device.cpu.mmu.rb(0xC000));
+        assert_eq!(
+            frame_hash(restored.get_gpu_data()),
+            frame_hash(device.get_gpu_data())
+        );
+
+        // The ROM is not part of the state, so it must match
+        rom[0x134] = b'X';
+        let mut other = Device::new_from_buffer(rom, true).unwrap();
+        let state = bincode::deserialize(&bytes).unwrap();
+        assert!(other.restore_state(state).is_err());
+    }
+
+    #[test]
+    fn ppu_debug_mid_frame() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        // Show the window from the top left corner
+        device.cpu.mmu.wb(0xFF40, 0xB1);
+        device.cpu.mmu.wb(0xFF4A, 0);
+        device.cpu.mmu.wb(0xFF4B, 7);
+        device.run_to_vblank();
+        assert_eq!(device.ppu_debug().window_line, Some(143));
+
+        device.run_cycles(456 * 20 + 200);
+        let debug = device.ppu_debug();
+        assert!(debug.dot < 456);
+        assert_eq!(debug.line, 10);
+        assert_eq!(debug.mode, 3);
+        // Line 10 is only drawn at the end of mode 3
+        assert_eq!(debug.window_line, Some(9));
+        assert!(debug.fetcher_x.is_some_and(|x| x < 160));
+    }
+
+    #[test]
+    fn hide_background_layer() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.cpu.mmu.wb(0xFF40, 0x00);
+        // Tile 0 is colour 1 everywhere and fills the background, tile 1 is a colour 3 sprite
+        for i in 0..8 {
+            device.cpu.mmu.wb(0x8000 + i * 2, 0xFF);
+            device.cpu.mmu.wb(0x8010 + i * 2, 0xFF);
+            device.cpu.mmu.wb(0x8011 + i * 2, 0xFF);
+        }
+        for (i, &v) in [16, 8, 1, 0].iter().enumerate() {
+            device.cpu.mmu.wb(0xFE00 + i as u16, v);
+        }
+        device.cpu.mmu.wb(0xFF47, 0xE4);
+        device.cpu.mmu.wb(0xFF48, 0xE4);
+        device.cpu.mmu.wb(0xFF40, 0x93);
+        let shades = device.dmg_palette();
+
+        device.run_to_vblank();
+        device.run_to_vblank();
+        let frame = Frame::new(device.get_gpu_data());
+        assert_eq!(frame.pixel(0, 0), shades.obj0[3]);
+        assert_eq!(frame.pixel(100, 100), shades.bg[1]);
+
+        device.set_layer_enabled(Layer::Background, false);
+        device.run_to_vblank();
+        let frame = Frame::new(device.get_gpu_data());
+        assert_eq!(frame.pixel(0, 0), shades.obj0[3]);
+        assert_eq!(frame.pixel(100, 100), shades.bg[0]);
+    }
+
+    #[test]
+    fn scanline_callback() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let seen = lines.clone();
+        device.set_scanline_callback(Box::new(move |ly, pixels| {
+            assert_eq!(pixels.len(), 160 * 3);
+            seen.lock().unwrap().push(ly);
+        }));
+        device.run_to_vblank();
+        lines.lock().unwrap().clear();
+        device.run_to_vblank();
+        assert_eq!(*lines.lock().unwrap(), (0..144).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn oam_stat_interrupt_on_line_144() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        // Leave the vertical blank the boot ROM ends in
+        device.cpu.mmu.do_cycle(4);
+        device.cpu.mmu.wb(0xFF41, 0x20);
+        let gpu = &mut device.cpu.mmu.gpu;
+        gpu.interrupt = 0;
+        let mut lines = Vec::new();
+        for _ in 0..(154 * 456 / 4) {
+            gpu.do_cycle(4);
+            if gpu.interrupt & 0x02 != 0 {
+                lines.push(gpu.debug_state().line);
+                gpu.interrupt = 0;
+            }
+        }
+        lines.sort_unstable();
+        assert_eq!(lines, (0..=144).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn frame_ready_until_reset() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.check_and_reset_gpu_updated();
+        assert!(!device.is_frame_ready());
+        device.run_to_vblank();
+        assert!(device.is_frame_ready());
+        assert!(device.is_frame_ready());
+        assert!(device.check_and_reset_gpu_updated());
+        assert!(!device.is_frame_ready());
+    }
+
+    #[test]
+    fn ram_enable_override() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        device.cpu.mmu.wb(0xA000, 0x42);
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0xFF);
+
+        device.set_ram_enable_override(Some(true));
+        device.cpu.mmu.wb(0xA000, 0x42);
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0x42);
+        assert_eq!(device.dump_ram()[0], 0x42);
+
+        device.set_ram_enable_override(None);
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0xFF);
+        device.cpu.mmu.wb(0x0000, 0x0A);
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0x42);
+        device.set_ram_enable_override(Some(false));
+        assert_eq!(device.cpu.mmu.rb(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mmm01_boots_menu() {
+        // The menu in the last 32KiB fills the screen with colour 3
+        let mut rom = vec![0; 0x10000];
+        rom[0x8100..0x8104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x8150..0x8156].copy_from_slice(&[0x3E, 0xFF, 0xE0, 0x47, 0x18, 0xFE]);
+        rom[0x8104..0x8134].copy_from_slice(&crate::mbc::NINTENDO_LOGO);
+        rom[0x8147] = 0x0B;
+        rom[0x8148] = 0x01;
+        rom[0x814D] = crate::mbc::header_checksum(&rom[0x8000..]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        let shades = device.dmg_palette();
+
+        device.run_to_vblank();
+        device.run_to_vblank();
+        let frame = Frame::new(device.get_gpu_data());
+        assert_eq!(frame.pixel(80, 72), shades.bg[3]);
+        assert_ne!(shades.bg[3], shades.bg[0]);
+    }
+
+    #[test]
+    fn cycles_until_timer_interrupt() {
+        // DI; HALT; JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0xF3, 0x76, 0x18, 0xFE]);
+        let mut device = Device::new_from_buffer(rom, true).unwrap();
+        // Only the timer, which overflows every 256 * 16 cycles
+        device.cpu.mmu.wb(0xFF40, 0x00);
+        device.cpu.mmu.wb(0xFFFF, 0x04);
+        device.cpu.mmu.wb(0xFF0F, 0x00);
+        device.cpu.mmu.wb(0xFF05, 0x00);
+        device.cpu.mmu.wb(0xFF07, 0x05);
+        device.run_cycles(100);
+        assert!(device.is_halted());
+
+        let estimate = device.cycles_until_next_event();
+        assert!(estimate > 0 && estimate <= 256 * 16);
+        let mut elapsed = 0;
+        while device.interrupt_state().requested & 0x04 == 0 {
+            elapsed += device.do_cycle();
+        }
+        assert!(elapsed >= estimate && elapsed - estimate < 16);
+        assert_eq!(device.cycles_until_next_event(), 0);
+    }
+
+    #[test]
+    fn pcm_registers_are_current() {
+        let mut device = Device::new_cgb_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.enable_audio(Box::new(RingBufferAudioPlayer::new(44100, 44100)));
+        // Channel 2 at full volume, with a square wave of 512 Hz
+        device.cpu.mmu.wb(0xFF26, 0x80);
+        device.cpu.mmu.wb(0xFF16, 0x80);
+        device.cpu.mmu.wb(0xFF17, 0xF0);
+        device.cpu.mmu.wb(0xFF18, 0x00);
+        device.cpu.mmu.wb(0xFF19, 0x87);
+
+        // Well within the first chunk of output
+        let mut seen = Vec::new();
+        for _ in 0..256 {
+            device.cpu.mmu.do_cycle(64);
+            let value = device.cpu.mmu.rb(0xFF76) >> 4;
+            if !seen.contains(&value) {
+                seen.push(value);
+            }
+        }
+        seen.sort_unstable();
+        assert_eq!(seen, [0x0, 0xF]);
+    }
+
+    #[test]
+    fn sync_audio_drops_buffer() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        let player = RingBufferAudioPlayer::new(44100, 44100);
+        device.enable_audio(Box::new(player.clone()));
+        device.cpu.mmu.wb(0xFF26, 0x80);
+        for _ in 0..10 {
+            device.run_to_vblank();
+        }
+        assert!(!player.is_empty());
+
+        device.sync_audio();
+        assert!(player.is_empty());
+        for _ in 0..10 {
+            device.run_to_vblank();
+        }
+        assert!(!player.is_empty());
     }
 }
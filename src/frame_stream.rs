@@ -0,0 +1,136 @@
+//! The frames of a device running on its own thread, as a `futures_core::Stream` for hosts
+//! built on an async event loop.
+
+use crate::device::Device;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_core::Stream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+#[derive(Default)]
+struct State {
+    frame: Option<Vec<u8>>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when the frame was taken or the stream closed
+    taken: Condvar,
+}
+
+/// Runs the device one frame at a time and yields every frame, paced by the speed multiplier
+/// like `run_one_frame_paced`. The thread waits until the last frame was taken, so a slow
+/// consumer slows the emulator down instead of losing frames. The device is shared, so input
+/// can be given through the mutex while the stream runs.
+pub struct FrameStream {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FrameStream {
+    pub fn new(device: Arc<Mutex<Device>>) -> FrameStream {
+        let shared = Arc::new(Shared::default());
+        let producer = shared.clone();
+        let thread = thread::spawn(move || run(device, &producer));
+        FrameStream {
+            shared,
+            thread: Some(thread),
+        }
+    }
+}
+
+fn run(device: Arc<Mutex<Device>>, shared: &Shared) {
+    let mut deadline: Option<Instant> = None;
+    loop {
+        // The device is not locked while sleeping, so the host can use it meanwhile
+        let (frame, target) = {
+            let mut device = device.lock().unwrap();
+            let frame = device.run_one_frame().to_vec();
+            (frame, device.frame_target())
+        };
+        if let Some(target) = target {
+            let now = Instant::now();
+            let next = match deadline {
+                Some(deadline) if deadline + target >= now => deadline + target,
+                _ => now + target,
+            };
+            deadline = Some(next);
+            thread::sleep(next.saturating_duration_since(now));
+        }
+
+        let mut state = shared.state.lock().unwrap();
+        while state.frame.is_some() && !state.closed {
+            state = shared.taken.wait(state).unwrap();
+        }
+        if state.closed {
+            return;
+        }
+        state.frame = Some(frame);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.frame.take() {
+            Some(frame) => {
+                self.shared.taken.notify_one();
+                Poll::Ready(Some(frame))
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for FrameStream {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.taken.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FrameStream;
+    use crate::device::Device;
+    use crate::gpu::{SCREEN_H, SCREEN_W};
+    use futures_lite::future::block_on;
+    use futures_lite::StreamExt;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn pull_frames() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.set_speed_multiplier(0.0);
+        let device = Arc::new(Mutex::new(device));
+        let mut stream = FrameStream::new(device.clone());
+
+        let frames = block_on(async {
+            let first = stream.next().await.unwrap();
+            let second = stream.next().await.unwrap();
+            [first, second]
+        });
+        for frame in frames.iter() {
+            assert_eq!(frame.len(), SCREEN_W * SCREEN_H * 3);
+        }
+        drop(stream);
+        assert!(device.lock().unwrap().total_cycles() > 0);
+    }
+}
@@ -1,12 +1,143 @@
+use crate::cpu::CPU_FREQUENCY;
+use crate::palette::{self, Shades};
+use core::time::Duration;
+
 #[derive(PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum GbMode {
     Classic,
     Color,
     ColorAsClassic,
 }
 
-#[derive(PartialEq, Copy, Clone)]
+/// The hardware to emulate. The color models run color games in color mode and the others in
+/// classic mode, which mostly differ in the registers left by the boot ROM.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Model {
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+    /// Emulated like the CGB, apart from the registers after booting
+    Agb,
+}
+
+impl Model {
+    pub const ALL: [Model; 5] = [Model::Dmg, Model::Mgb, Model::Sgb, Model::Cgb, Model::Agb];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::Dmg => "dmg",
+            Model::Mgb => "mgb",
+            Model::Sgb => "sgb",
+            Model::Cgb => "cgb",
+            Model::Agb => "agb",
+        }
+    }
+
+    /// The model with the given name, ignoring case
+    pub fn from_name(name: &str) -> Option<Model> {
+        Model::ALL
+            .iter()
+            .copied()
+            .find(|model| model.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn is_color(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum GbSpeed {
     Single = 1,
     Double = 2,
 }
+
+/// The clock the CPU runs at in the given speed mode
+///
+/// The GPU, sound and the cycle counts returned by `do_cycle` always run at the single speed
+/// clock, so emulation has to be paced with `cpu_frequency(GbSpeed::Single)`.
+pub fn cpu_frequency(speed: GbSpeed) -> f64 {
+    CPU_FREQUENCY * speed as u32 as f64
+}
+
+/// How long the CPU takes to run the given number of cycles in the given speed mode
+pub fn cycles_to_duration(cycles: u64, speed: GbSpeed) -> Duration {
+    Duration::from_secs_f64(cycles as f64 / cpu_frequency(speed))
+}
+
+/// How many cycles the CPU runs in the given time, rounded to the nearest cycle
+pub fn duration_to_cycles(duration: Duration, speed: GbSpeed) -> u64 {
+    (duration.as_secs_f64() * cpu_frequency(speed) + 0.5) as u64
+}
+
+/// The background, first and second sprite palette a CGB gives a classic game with the given
+/// title, the bytes at 0x134-0x143. The boot ROM only does this for games published by
+/// Nintendo, which is left to the caller. A device applies it on its own, and
+/// `Device::set_dmg_palette` overrides it.
+pub fn compat_palette_for_title(title: &[u8]) -> [Shades; 3] {
+    let palette = palette::title_palette(title);
+    [palette.bg, palette.obj0, palette.obj1]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        compat_palette_for_title, cpu_frequency, cycles_to_duration, duration_to_cycles, GbSpeed,
+        Model,
+    };
+    use crate::cpu::CPU_FREQUENCY;
+    use crate::palette::CGB_DEFAULT;
+    use core::time::Duration;
+
+    #[test]
+    fn model_names() {
+        for &model in Model::ALL.iter() {
+            assert_eq!(Model::from_name(model.name()), Some(model));
+        }
+        assert_eq!(Model::from_name("CGB"), Some(Model::Cgb));
+        assert_eq!(Model::from_name("gba"), None);
+    }
+
+    #[test]
+    fn double_speed_frequency() {
+        assert_eq!(
+            cpu_frequency(GbSpeed::Double),
+            2.0 * cpu_frequency(GbSpeed::Single)
+        );
+    }
+
+    #[test]
+    fn convert_cycles() {
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            duration_to_cycles(second, GbSpeed::Single),
+            CPU_FREQUENCY as u64
+        );
+        assert_eq!(
+            duration_to_cycles(second, GbSpeed::Double),
+            2 * CPU_FREQUENCY as u64
+        );
+        assert_eq!(
+            cycles_to_duration(CPU_FREQUENCY as u64, GbSpeed::Single),
+            second
+        );
+        assert_eq!(
+            cycles_to_duration(CPU_FREQUENCY as u64, GbSpeed::Double),
+            second / 2
+        );
+    }
+
+    #[test]
+    fn compat_palette() {
+        let palette = compat_palette_for_title(b"POKEMON BLUE\0\0\0\0");
+        assert_eq!(compat_palette_for_title(b"POKEMON BLUE"), palette);
+        assert_eq!(palette[0][2], [0x00, 0x00, 0xFF]);
+        assert_eq!(palette[1][2], [0x94, 0x3A, 0x3A]);
+        let default = [CGB_DEFAULT.bg, CGB_DEFAULT.obj0, CGB_DEFAULT.obj1];
+        assert_eq!(compat_palette_for_title(b"UNKNOWN"), default);
+    }
+}
@@ -1,22 +1,105 @@
 use crate::gbmode::GbMode;
-use std::cmp::Ordering;
+use crate::palette::{self, DmgPalette, Shades};
+use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::convert::TryFrom;
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
+/// Receives the screen as packed RGB whenever a frame completes
+pub type FrameCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+/// Receives LY and the `SCREEN_W` pixels of packed RGB of every line as soon as it is drawn
+pub type ScanlineCallback = Box<dyn FnMut(u8, &[u8]) + Send>;
+
+/// Receives whether the LCD is on every time a game turns it on or off
+pub type LcdPowerCallback = Box<dyn FnMut(bool) + Send>;
+
+/// A view of the screen as `SCREEN_W * SCREEN_H` pixels of packed RGB, row by row
+#[derive(Clone, Copy)]
+pub struct Frame<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(data: &'a [u8]) -> Frame<'a> {
+        assert_eq!(data.len(), SCREEN_W * SCREEN_H * 3);
+        Frame { data }
+    }
+
+    pub fn width(&self) -> usize {
+        SCREEN_W
+    }
+
+    pub fn height(&self) -> usize {
+        SCREEN_H
+    }
+
+    pub fn as_rgb(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Panics when the position is outside of the screen
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        assert!(x < SCREEN_W && y < SCREEN_H, "Pixel outside of the screen");
+        let offset = (y * SCREEN_W + x) * 3;
+        [
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+        ]
+    }
+}
+
+/// A look at the internal position of the GPU, for debuggers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PpuDebug {
+    /// LY, 0 to 153
+    pub line: u8,
+    /// The dot within the current line, 0 to 455
+    pub dot: u16,
+    /// The STAT mode, 0 to 3
+    pub mode: u8,
+    /// How many dots mode 3 takes on the current line
+    pub mode3_length: u16,
+    /// The internal window line counter: the line of the window drawn last in this frame, or
+    /// `None` while the window was not drawn yet
+    pub window_line: Option<u8>,
+    /// The screen column the pixel fetcher has reached in mode 3. A whole line is drawn at once
+    /// when mode 3 ends, so this is estimated as if all penalties came before the first pixel.
+    pub fetcher_x: Option<u8>,
+}
+
+/// A layer of the picture, to hide while debugging
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
 #[derive(PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum PrioType {
     Color0,
     PrioFlag,
     Normal,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GPU {
     mode: u8,
     modeclock: u32,
-    line: u8,
+    // How long drawing the current line takes, which is longer with scrolling, the window and
+    // sprites
+    mode3_length: u32,
+    pub(crate) line: u8,
     lyc: u8,
     lcd_on: bool,
     win_tilemap: u16,
@@ -39,10 +122,14 @@ pub struct GPU {
     palbr: u8,
     pal0r: u8,
     pal1r: u8,
+    // The shade selected for each colour number
     palb: [u8; 4],
     pal0: [u8; 4],
     pal1: [u8; 4],
+    pub dmg_palette: DmgPalette,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_array"))]
     vram: [u8; VRAM_SIZE],
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_array"))]
     voam: [u8; VOAM_SIZE],
     cbgpal_inc: bool,
     cbgpal_ind: u8,
@@ -52,11 +139,26 @@ pub struct GPU {
     csprit: [[[u8; 3]; 4]; 8],
     vrambank: usize,
     pub data: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_array"))]
     bgprio: [PrioType; SCREEN_W],
     pub updated: bool,
+    // Set by every write to VRAM, for tools that only redraw tiles when they change
+    pub vram_dirty: bool,
+    pub frame_count: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    frame_callback: Option<FrameCallback>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    scanline_callback: Option<ScanlineCallback>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    lcd_power_callback: Option<LcdPowerCallback>,
     pub interrupt: u8,
     pub gbmode: GbMode,
+    // OPRI: sprites ordered by X like the DMG instead of by OAM index
+    dmg_sprite_priority: bool,
     hblanking: bool,
+    // Layers hidden by the host, indexed by `Layer`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    hidden_layers: [bool; 3],
 }
 
 impl GPU {
@@ -64,6 +166,7 @@ impl GPU {
         GPU {
             mode: 0,
             modeclock: 0,
+            mode3_length: 172,
             line: 0,
             lyc: 0,
             lcd_on: false,
@@ -90,13 +193,20 @@ impl GPU {
             palb: [0; 4],
             pal0: [0; 4],
             pal1: [0; 4],
+            dmg_palette: palette::GRAYSCALE,
             vram: [0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: vec![0; SCREEN_W * SCREEN_H * 3],
             bgprio: [PrioType::Normal; SCREEN_W],
             updated: false,
+            vram_dirty: false,
+            frame_count: 0,
+            frame_callback: None,
+            scanline_callback: None,
+            lcd_power_callback: None,
             interrupt: 0,
             gbmode: GbMode::Classic,
+            dmg_sprite_priority: false,
             cbgpal_inc: false,
             cbgpal_ind: 0,
             cbgpal: [[[0u8; 3]; 4]; 8],
@@ -105,6 +215,73 @@ impl GPU {
             csprit: [[[0u8; 3]; 4]; 8],
             vrambank: 0,
             hblanking: false,
+            hidden_layers: [false; 3],
+        }
+    }
+
+    /// A copy without the callbacks
+    pub fn fork(&self) -> GPU {
+        GPU {
+            data: self.data.clone(),
+            frame_callback: None,
+            scanline_callback: None,
+            lcd_power_callback: None,
+            ..*self
+        }
+    }
+
+    /// Rejects a GPU from a save state that would index out of bounds
+    pub fn check_state(&self) -> StrResult<()> {
+        let tilemaps = [0x9800, 0x9C00];
+        let mut shades = self.palb.iter().chain(&self.pal0).chain(&self.pal1);
+        if self.vrambank > 1
+            || self.cbgpal_ind > 0x3F
+            || self.csprit_ind > 0x3F
+            || self.mode > 3
+            || self.line > 153
+            || !matches!(self.sprite_size, 8 | 16)
+            || !matches!(self.tilebase, 0x8000 | 0x8800)
+            || !tilemaps.contains(&self.bg_tilemap)
+            || !tilemaps.contains(&self.win_tilemap)
+            || shades.any(|&v| v > 3)
+            || self.data.len() != SCREEN_W * SCREEN_H * 3
+        {
+            return Err("Invalid GPU state");
+        }
+        Ok(())
+    }
+
+    /// Takes over the state of a save state, but keeps the callbacks and the palette chosen by
+    /// the host
+    pub fn load_state(&mut self, state: GPU) -> StrResult<()> {
+        state.check_state()?;
+        *self = GPU {
+            frame_callback: self.frame_callback.take(),
+            scanline_callback: self.scanline_callback.take(),
+            lcd_power_callback: self.lcd_power_callback.take(),
+            dmg_palette: self.dmg_palette,
+            hidden_layers: self.hidden_layers,
+            vram_dirty: true,
+            ..state
+        };
+        Ok(())
+    }
+
+    pub fn debug_state(&self) -> PpuDebug {
+        let fetcher_x = match self.mode {
+            3 => {
+                let drawn = self.modeclock.saturating_sub(80 + self.mode3_length - 160);
+                Some(drawn.min(SCREEN_W as u32) as u8)
+            }
+            _ => None,
+        };
+        PpuDebug {
+            line: self.line,
+            dot: self.modeclock as u16,
+            mode: self.mode,
+            mode3_length: self.mode3_length as u16,
+            window_line: u8::try_from(self.wy_pos).ok(),
+            fetcher_x,
         }
     }
 
@@ -112,6 +289,19 @@ impl GPU {
         GPU::new()
     }
 
+    /// How many cycles until the mode or the line changes, or `None` while the LCD is off
+    pub fn cycles_until_mode_change(&self) -> Option<u32> {
+        if !self.lcd_on {
+            return None;
+        }
+        let end = match self.mode {
+            2 => 81,
+            3 => 81 + self.mode3_length,
+            _ => 456,
+        };
+        Some(end.saturating_sub(self.modeclock).max(1))
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
         if !self.lcd_on {
             return;
@@ -143,13 +333,12 @@ impl GPU {
                     if self.mode != 2 {
                         self.change_mode(2);
                     }
-                } else if self.modeclock <= (80 + 172) {
-                    // 252 cycles
+                } else if self.modeclock <= 80 + self.mode3_length {
                     if self.mode != 3 {
                         self.change_mode(3);
                     }
                 } else {
-                    // the remaining 204
+                    // The rest of the 456 cycles
                     if self.mode != 0 {
                         self.change_mode(0);
                     }
@@ -158,6 +347,128 @@ impl GPU {
         }
     }
 
+    /// Leaves the GPU where the DMG boot ROM hands over: at the end of the vertical blank, where
+    /// LY already reads 0. STAT reads 0x85 until the next line starts.
+    pub(crate) fn end_boot_frame(&mut self) {
+        self.line = 0;
+        self.mode = 1;
+    }
+
+    // The penalties as documented in the Pan Docs
+    fn calc_mode3_length(&self) -> u32 {
+        // Pixels scrolled out on the left are still fetched
+        let mut length = 172 + (self.scx & 0x07) as u32;
+        if self.win_on && self.wy_trigger && self.winx <= 166 {
+            length += 6;
+        }
+        if !self.sprite_on {
+            return length;
+        }
+
+        let line = self.line as i32;
+        let mut sprites = 0;
+        // Only the first sprite within a background tile waits for that tile to be fetched
+        let mut tiles_seen = [false; 32];
+        for index in 0..40 {
+            let spriteaddr = 0xFE00 + (index as u16) * 4;
+            let spritey = self.rb(spriteaddr) as i32 - 16;
+            if line < spritey || line >= spritey + self.sprite_size as i32 {
+                continue;
+            }
+            sprites += 1;
+            let x = self.rb(spriteaddr + 1);
+            if x < 168 {
+                let column = (x as usize + self.scx as usize + 248) & 0xFF;
+                let wait = if tiles_seen[column >> 3] {
+                    0
+                } else {
+                    5u32.saturating_sub((column & 0x07) as u32)
+                };
+                tiles_seen[column >> 3] = true;
+                length += 6 + wait;
+            }
+            if sprites >= 10 {
+                break;
+            }
+        }
+        length
+    }
+
+    /// The CPU cannot access VRAM while a line is being drawn
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_on || self.mode != 3
+    }
+
+    /// The CPU cannot access OAM during the OAM scan and while a line is being drawn
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_on || self.mode < 2
+    }
+
+    pub fn set_frame_callback(&mut self, cb: FrameCallback) {
+        self.frame_callback = Some(cb);
+    }
+
+    pub fn unset_frame_callback(&mut self) {
+        self.frame_callback = None;
+    }
+
+    pub fn set_scanline_callback(&mut self, cb: ScanlineCallback) {
+        self.scanline_callback = Some(cb);
+    }
+
+    pub fn unset_scanline_callback(&mut self) {
+        self.scanline_callback = None;
+    }
+
+    pub fn set_lcd_power_callback(&mut self, cb: LcdPowerCallback) {
+        self.lcd_power_callback = Some(cb);
+    }
+
+    pub fn unset_lcd_power_callback(&mut self) {
+        self.lcd_power_callback = None;
+    }
+
+    /// Hides or shows a layer regardless of LCDC
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.hidden_layers[layer as usize] = !enabled;
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vrambank
+    }
+
+    /// Draws the 48 byte logo from the cartridge header in the middle of the background, like
+    /// the boot ROM does
+    pub fn load_boot_logo(&mut self, logo: &[u8]) {
+        // Every bit becomes 2x2 pixels, so each nibble fills two lines of a tile
+        for (i, &byte) in logo.iter().enumerate() {
+            for (j, &nibble) in [byte >> 4, byte & 0x0F].iter().enumerate() {
+                let mut line = 0;
+                for bit in (0..4).rev() {
+                    line = (line << 2) | (((nibble >> bit) & 1) * 0b11);
+                }
+                let address = 0x0010 + i * 8 + j * 4;
+                self.vram[address..address + 4].copy_from_slice(&[line, 0, line, 0]);
+            }
+        }
+        // Tiles 1-12 and 13-24 on two lines of the tile map at 0x9800
+        for tile in 0..24 {
+            let address = 0x1904 + (tile / 12) * 0x20 + tile % 12;
+            self.vram[address] = tile as u8 + 1;
+        }
+        self.vram_dirty = true;
+    }
+
+    /// Panics for banks other than 0 and 1
+    pub fn vram(&self, bank: u8) -> &[u8] {
+        let start = bank as usize * 0x2000;
+        &self.vram[start..start + 0x2000]
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.voam
+    }
+
     fn check_interrupt_lyc(&mut self) {
         if self.lyc_inte && self.line == self.lyc {
             self.interrupt |= 0x02;
@@ -170,6 +481,10 @@ impl GPU {
         if match self.mode {
             0 => {
                 self.renderscan();
+                if let Some(ref mut cb) = self.scanline_callback {
+                    let start = self.line as usize * SCREEN_W * 3;
+                    cb(self.line, &self.data[start..start + SCREEN_W * 3]);
+                }
                 self.hblanking = true;
                 self.m0_inte
             }
@@ -178,7 +493,12 @@ impl GPU {
                 self.wy_trigger = false;
                 self.interrupt |= 0x01;
                 self.updated = true;
-                self.m1_inte
+                self.frame_count += 1;
+                if let Some(ref mut cb) = self.frame_callback {
+                    cb(&self.data);
+                }
+                // The OAM source fires on line 144 as well, even though mode 2 never starts
+                self.m1_inte || self.m2_inte
             }
             2 => self.m2_inte,
             3 => {
@@ -186,6 +506,7 @@ impl GPU {
                     self.wy_trigger = true;
                     self.wy_pos = -1;
                 }
+                self.mode3_length = self.calc_mode3_length();
                 false
             }
             _ => false,
@@ -228,7 +549,7 @@ impl GPU {
             0xFF4B => self.winx,
             0xFF4C => 0xFF,
             0xFF4E => 0xFF,
-            0xFF4F..=0xFF6B if self.gbmode != GbMode::Color => 0xFF,
+            0xFF4F..=0xFF6C if self.gbmode != GbMode::Color => 0xFF,
             0xFF4F => self.vrambank as u8 | 0xFE,
             0xFF68 => 0x40 | self.cbgpal_ind | (if self.cbgpal_inc { 0x80 } else { 0 }),
             0xFF69 => {
@@ -252,6 +573,7 @@ impl GPU {
                         | (self.csprit[palnum][colnum][2] << 2)
                 }
             }
+            0xFF6C => 0xFE | self.dmg_sprite_priority as u8,
             _ => 0xFF,
         }
     }
@@ -271,7 +593,10 @@ impl GPU {
 
     pub fn wb(&mut self, a: u16, v: u8) {
         match a {
-            0x8000..=0x9FFF => self.vram[(self.vrambank * 0x2000) | (a as usize & 0x1FFF)] = v,
+            0x8000..=0x9FFF => {
+                self.vram[(self.vrambank * 0x2000) | (a as usize & 0x1FFF)] = v;
+                self.vram_dirty = true;
+            }
             0xFE00..=0xFE9F => self.voam[a as usize - 0xFE00] = v,
             0xFF40 => {
                 let orig_lcd_on = self.lcd_on;
@@ -294,12 +619,26 @@ impl GPU {
                     self.change_mode(2);
                     self.modeclock = 4;
                 }
+                if orig_lcd_on != self.lcd_on {
+                    if let Some(ref mut cb) = self.lcd_power_callback {
+                        cb(self.lcd_on);
+                    }
+                }
             }
             0xFF41 => {
                 self.lyc_inte = v & 0x40 == 0x40;
                 self.m2_inte = v & 0x20 == 0x20;
                 self.m1_inte = v & 0x10 == 0x10;
                 self.m0_inte = v & 0x08 == 0x08;
+
+                // On the DMG, the write briefly enables every interrupt source, which fires the
+                // STAT interrupt outside of mode 2 and 3 or when LY matches LYC
+                if self.gbmode == GbMode::Classic
+                    && self.lcd_on
+                    && (self.mode < 2 || self.line == self.lyc)
+                {
+                    self.interrupt |= 0x02;
+                }
             }
             0xFF42 => self.scy = v,
             0xFF43 => self.scx = v,
@@ -325,7 +664,7 @@ impl GPU {
             0xFF4B => self.winx = v,
             0xFF4C => {}
             0xFF4E => {}
-            0xFF4F..=0xFF6B if self.gbmode != GbMode::Color => {}
+            0xFF4F..=0xFF6C if self.gbmode != GbMode::Color => {}
             0xFF4F => self.vrambank = (v & 0x01) as usize,
             0xFF68 => {
                 self.cbgpal_ind = v & 0x3F;
@@ -367,47 +706,52 @@ impl GPU {
                     self.csprit_ind = (self.csprit_ind + 1) & 0x3F;
                 };
             }
+            0xFF6C => self.dmg_sprite_priority = v & 0x01 == 0x01,
             _ => panic!("GPU does not handle write {:04X}", a),
         }
     }
 
     fn clear_screen(&mut self) {
-        for v in self.data.iter_mut() {
-            *v = 255;
+        let blank = self.blank_color();
+        for pixel in self.data.chunks_mut(3) {
+            pixel.copy_from_slice(&blank);
         }
         self.updated = true;
     }
 
-    fn update_pal(&mut self) {
-        for i in 0..4 {
-            self.palb[i] = GPU::get_monochrome_pal_val(self.palbr, i);
-            self.pal0[i] = GPU::get_monochrome_pal_val(self.pal0r, i);
-            self.pal1[i] = GPU::get_monochrome_pal_val(self.pal1r, i);
+    fn blank_color(&self) -> [u8; 3] {
+        match self.gbmode {
+            GbMode::Color => [255; 3],
+            _ => self.dmg_palette.bg[0],
         }
     }
 
-    fn get_monochrome_pal_val(value: u8, index: usize) -> u8 {
-        match (value >> 2 * index) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0,
+    fn update_pal(&mut self) {
+        for i in 0..4 {
+            self.palb[i] = (self.palbr >> (2 * i)) & 0x03;
+            self.pal0[i] = (self.pal0r >> (2 * i)) & 0x03;
+            self.pal1[i] = (self.pal1r >> (2 * i)) & 0x03;
         }
     }
 
     fn renderscan(&mut self) {
+        let blank = self.blank_color();
         for x in 0..SCREEN_W {
-            self.setcolor(x, 255);
+            self.setcolor(x, blank);
             self.bgprio[x] = PrioType::Normal;
         }
         self.draw_bg();
         self.draw_sprites();
     }
 
-    fn setcolor(&mut self, x: usize, color: u8) {
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 0] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 1] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 2] = color;
+    fn setcolor(&mut self, x: usize, color: [u8; 3]) {
+        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 0] = color[0];
+        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 1] = color[1];
+        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 2] = color[2];
+    }
+
+    fn setshade(&mut self, x: usize, shades: Shades, shade: u8) {
+        self.setcolor(x, shades[shade as usize]);
     }
 
     fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
@@ -449,24 +793,34 @@ impl GPU {
             let winx = -((self.winx as i32) - 7) + (x as i32);
             let bgx = self.scx as u32 + x as u32;
 
-            let (tilemapbase, tiley, tilex, pixely, pixelx) = if winy >= 0 && winx >= 0 {
-                (
+            let layer = if winy >= 0 && winx >= 0 {
+                Layer::Window
+            } else if drawbg {
+                Layer::Background
+            } else {
+                continue;
+            };
+            if self.hidden_layers[layer as usize] {
+                // Let all sprites through, as if this was colour 0
+                self.bgprio[x] = PrioType::Color0;
+                continue;
+            }
+
+            let (tilemapbase, tiley, tilex, pixely, pixelx) = match layer {
+                Layer::Window => (
                     self.win_tilemap,
                     wintiley,
                     (winx as u16 >> 3),
                     winy as u16 & 0x07,
                     winx as u8 & 0x07,
-                )
-            } else if drawbg {
-                (
+                ),
+                _ => (
                     self.bg_tilemap,
                     bgtiley,
                     (bgx as u16 >> 3) & 31,
                     bgy as u16 & 0x07,
                     bgx as u8 & 0x07,
-                )
-            } else {
-                continue;
+                ),
             };
 
             let tilenr: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
@@ -521,14 +875,14 @@ impl GPU {
                 let b = self.cbgpal[palnr][colnr][2];
                 self.setrgb(x as usize, r, g, b);
             } else {
-                let color = self.palb[colnr];
-                self.setcolor(x, color);
+                let shade = self.palb[colnr];
+                self.setshade(x, self.dmg_palette.bg, shade);
             }
         }
     }
 
     fn draw_sprites(&mut self) {
-        if !self.sprite_on {
+        if !self.sprite_on || self.hidden_layers[Layer::Sprites as usize] {
             return;
         }
 
@@ -550,7 +904,7 @@ impl GPU {
                 break;
             }
         }
-        if self.gbmode == GbMode::Color {
+        if self.gbmode == GbMode::Color && !self.dmg_sprite_priority {
             sprites_to_draw[..sidx].sort_unstable_by(cgb_sprite_order);
         } else {
             sprites_to_draw[..sidx].sort_unstable_by(dmg_sprite_order);
@@ -613,12 +967,12 @@ impl GPU {
                     if belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0 {
                         continue 'xloop;
                     }
-                    let color = if usepal1 {
-                        self.pal1[colnr]
+                    let (shades, shade) = if usepal1 {
+                        (self.dmg_palette.obj1, self.pal1[colnr])
                     } else {
-                        self.pal0[colnr]
+                        (self.dmg_palette.obj0, self.pal0[colnr])
                     };
-                    self.setcolor((spritex + x) as usize, color);
+                    self.setshade((spritex + x) as usize, shades, shade);
                 }
             }
         }
@@ -0,0 +1,102 @@
+use alloc::boxed::Box;
+
+pub type InfraredCallback = Box<dyn FnMut(bool) + Send>;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Infrared {
+    emitting: bool,
+    read_enable: u8,
+    receiving: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    callback: Option<InfraredCallback>,
+}
+
+impl Infrared {
+    pub fn new() -> Infrared {
+        Infrared {
+            emitting: false,
+            read_enable: 0,
+            receiving: false,
+            callback: None,
+        }
+    }
+
+    pub fn rb(&self) -> u8 {
+        // Bit 1 is cleared while light is received, but only when reading is enabled
+        let signal = if self.read_enable == 0xC0 && self.receiving {
+            0
+        } else {
+            0x02
+        };
+        self.read_enable | 0b00111100 | signal | (self.emitting as u8)
+    }
+
+    pub fn wb(&mut self, value: u8) {
+        self.read_enable = value & 0xC0;
+        let emitting = value & 0x01 == 0x01;
+        if emitting != self.emitting {
+            self.emitting = emitting;
+            if let Some(ref mut cb) = self.callback {
+                cb(emitting);
+            }
+        }
+    }
+
+    pub fn set_input(&mut self, receiving: bool) {
+        self.receiving = receiving;
+    }
+
+    /// A copy without the callback
+    pub fn fork(&self) -> Infrared {
+        Infrared {
+            callback: None,
+            ..*self
+        }
+    }
+
+    /// Takes over the state of a save state, but keeps the callback
+    pub fn load_state(&mut self, state: Infrared) {
+        *self = Infrared {
+            callback: self.callback.take(),
+            ..state
+        };
+    }
+
+    pub fn set_callback(&mut self, cb: InfraredCallback) {
+        self.callback = Some(cb);
+    }
+
+    pub fn unset_callback(&mut self) {
+        self.callback = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Infrared;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn loopback() {
+        let mut ir = Infrared::new();
+        let light = Arc::new(Mutex::new(false));
+        let emitted = light.clone();
+        ir.set_callback(Box::new(move |on| *emitted.lock().unwrap() = on));
+
+        ir.wb(0xC0);
+        assert_eq!(ir.rb() & 0x02, 0x02);
+
+        ir.wb(0xC1);
+        ir.set_input(*light.lock().unwrap());
+        assert_eq!(ir.rb(), 0xFD);
+
+        ir.wb(0xC0);
+        ir.set_input(*light.lock().unwrap());
+        assert_eq!(ir.rb(), 0xFE);
+
+        // Nothing is received while reading is disabled
+        ir.set_input(true);
+        ir.wb(0x00);
+        assert_eq!(ir.rb() & 0x02, 0x02);
+    }
+}
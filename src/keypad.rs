@@ -1,11 +1,28 @@
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Keypad {
     row0: u8,
     row1: u8,
     data: u8,
     pub interrupt: u8,
+    held: KeypadState,
+    // Indexed by the bit of the key in KeypadState
+    autofire: [Option<Autofire>; 8],
+    // Pressed on top of the held keys until the frames run out
+    injected: KeypadState,
+    injected_frames: u32,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Autofire {
+    frames_on: u8,
+    frames_off: u8,
+    // Frames since the start of the current on/off period
+    counter: u8,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum KeypadKey {
     Right,
     Left,
@@ -17,6 +34,105 @@ pub enum KeypadKey {
     Start,
 }
 
+impl KeypadKey {
+    /// Every key, in the order of their bits in `KeypadState`
+    pub const ALL: [KeypadKey; 8] = [
+        KeypadKey::Right,
+        KeypadKey::Left,
+        KeypadKey::Up,
+        KeypadKey::Down,
+        KeypadKey::A,
+        KeypadKey::B,
+        KeypadKey::Select,
+        KeypadKey::Start,
+    ];
+
+    /// The lowercase name used in key maps and by plugins
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeypadKey::Right => "right",
+            KeypadKey::Left => "left",
+            KeypadKey::Up => "up",
+            KeypadKey::Down => "down",
+            KeypadKey::A => "a",
+            KeypadKey::B => "b",
+            KeypadKey::Select => "select",
+            KeypadKey::Start => "start",
+        }
+    }
+
+    /// The key with the given name, ignoring case
+    pub fn from_name(name: &str) -> Option<KeypadKey> {
+        KeypadKey::ALL
+            .iter()
+            .copied()
+            .find(|key| key.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// The set of pressed buttons, one bit per button
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeypadState(u8);
+
+impl KeypadState {
+    pub const RIGHT: KeypadState = KeypadState(1 << 0);
+    pub const LEFT: KeypadState = KeypadState(1 << 1);
+    pub const UP: KeypadState = KeypadState(1 << 2);
+    pub const DOWN: KeypadState = KeypadState(1 << 3);
+    pub const A: KeypadState = KeypadState(1 << 4);
+    pub const B: KeypadState = KeypadState(1 << 5);
+    pub const SELECT: KeypadState = KeypadState(1 << 6);
+    pub const START: KeypadState = KeypadState(1 << 7);
+
+    pub const fn empty() -> KeypadState {
+        KeypadState(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> KeypadState {
+        KeypadState(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: KeypadState) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: KeypadState) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: KeypadState) {
+        self.0 &= !other.0;
+    }
+}
+
+impl core::ops::BitOr for KeypadState {
+    type Output = KeypadState;
+
+    fn bitor(self, rhs: KeypadState) -> KeypadState {
+        KeypadState(self.0 | rhs.0)
+    }
+}
+
+impl From<KeypadKey> for KeypadState {
+    fn from(key: KeypadKey) -> KeypadState {
+        match key {
+            KeypadKey::Right => KeypadState::RIGHT,
+            KeypadKey::Left => KeypadState::LEFT,
+            KeypadKey::Up => KeypadState::UP,
+            KeypadKey::Down => KeypadState::DOWN,
+            KeypadKey::A => KeypadState::A,
+            KeypadKey::B => KeypadState::B,
+            KeypadKey::Select => KeypadState::SELECT,
+            KeypadKey::Start => KeypadState::START,
+        }
+    }
+}
+
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad {
@@ -24,6 +140,10 @@ impl Keypad {
             row1: 0x0F,
             data: 0xFF,
             interrupt: 0,
+            held: KeypadState::empty(),
+            autofire: [None; 8],
+            injected: KeypadState::empty(),
+            injected_frames: 0,
         }
     }
 
@@ -47,45 +167,116 @@ impl Keypad {
             new_values &= self.row1;
         }
 
-        if old_values == 0xF && new_values != 0xF {
+        // Any selected line going from high to low raises the interrupt, releases never do
+        if old_values & !new_values != 0 {
             self.interrupt |= 0x10;
         }
 
         self.data = (self.data & 0xF0) | new_values;
     }
 
-    pub fn keydown(&mut self, key: KeypadKey) {
-        match key {
-            KeypadKey::Right => self.row0 &= !(1 << 0),
-            KeypadKey::Left => self.row0 &= !(1 << 1),
-            KeypadKey::Up => self.row0 &= !(1 << 2),
-            KeypadKey::Down => self.row0 &= !(1 << 3),
-            KeypadKey::A => self.row1 &= !(1 << 0),
-            KeypadKey::B => self.row1 &= !(1 << 1),
-            KeypadKey::Select => self.row1 &= !(1 << 2),
-            KeypadKey::Start => self.row1 &= !(1 << 3),
+    /// The buttons held by the player, also while autofire lets them go
+    pub fn state(&self) -> KeypadState {
+        self.held
+    }
+
+    pub fn set_state(&mut self, state: KeypadState) {
+        // Autofire starts over with the key pressed
+        let pressed = state.bits() & !self.held.bits();
+        for (i, autofire) in self.autofire.iter_mut().enumerate() {
+            if let Some(autofire) = autofire {
+                if pressed & (1 << i) != 0 {
+                    autofire.counter = 0;
+                }
+            }
         }
-        self.update();
+        self.held = state;
+        self.apply();
     }
 
-    pub fn keyup(&mut self, key: KeypadKey) {
-        match key {
-            KeypadKey::Right => self.row0 |= 1 << 0,
-            KeypadKey::Left => self.row0 |= 1 << 1,
-            KeypadKey::Up => self.row0 |= 1 << 2,
-            KeypadKey::Down => self.row0 |= 1 << 3,
-            KeypadKey::A => self.row1 |= 1 << 0,
-            KeypadKey::B => self.row1 |= 1 << 1,
-            KeypadKey::Select => self.row1 |= 1 << 2,
-            KeypadKey::Start => self.row1 |= 1 << 3,
+    /// While held, the key is pressed for `frames_on` frames and released for `frames_off`
+    /// frames. A `frames_off` of 0 disables autofire for the key.
+    pub fn set_autofire(&mut self, key: KeypadKey, frames_on: u8, frames_off: u8) {
+        let index = KeypadState::from(key).bits().trailing_zeros() as usize;
+        self.autofire[index] = match frames_off {
+            0 => None,
+            _ => Some(Autofire {
+                frames_on,
+                frames_off,
+                counter: 0,
+            }),
+        };
+        self.apply();
+    }
+
+    /// Presses the keys for the next `frames` frames, regardless of the held keys and autofire
+    pub fn inject(&mut self, state: KeypadState, frames: u32) {
+        self.injected = state;
+        self.injected_frames = frames;
+        self.apply();
+    }
+
+    /// Advances autofire and injected keys, called every time a frame completes
+    pub fn next_frame(&mut self) {
+        if self.injected_frames > 0 {
+            self.injected_frames -= 1;
+        }
+        for (i, autofire) in self.autofire.iter_mut().enumerate() {
+            if let Some(autofire) = autofire {
+                if self.held.bits() & (1 << i) != 0 {
+                    let period = autofire.frames_on as u16 + autofire.frames_off as u16;
+                    autofire.counter = ((autofire.counter as u16 + 1) % period) as u8;
+                }
+            }
+        }
+        self.apply();
+    }
+
+    fn apply(&mut self) {
+        let mut bits = self.held.bits();
+        for (i, autofire) in self.autofire.iter().enumerate() {
+            if let Some(autofire) = autofire {
+                if autofire.counter >= autofire.frames_on {
+                    bits &= !(1 << i);
+                }
+            }
+        }
+        if self.injected_frames > 0 {
+            bits |= self.injected.bits();
         }
+
+        // The rows are active low
+        self.row0 = !bits & 0x0F;
+        self.row1 = !bits >> 4;
         self.update();
     }
+
+    pub fn keydown(&mut self, key: KeypadKey) {
+        let mut state = self.state();
+        state.insert(key.into());
+        self.set_state(state);
+    }
+
+    pub fn keyup(&mut self, key: KeypadKey) {
+        let mut state = self.state();
+        state.remove(key.into());
+        self.set_state(state);
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::KeypadKey;
+    use super::{KeypadKey, KeypadState};
+
+    #[test]
+    fn key_names_round_trip() {
+        for (i, &key) in KeypadKey::ALL.iter().enumerate() {
+            assert_eq!(KeypadKey::from_name(key.name()), Some(key));
+            assert_eq!(KeypadState::from(key).bits(), 1 << i);
+        }
+        assert_eq!(KeypadKey::from_name("Start"), Some(KeypadKey::Start));
+        assert_eq!(KeypadKey::from_name("turbo"), None);
+    }
 
     #[test]
     fn keys_buttons() {
@@ -144,4 +335,51 @@ mod test {
             keypad.keyup(keys1[i]);
         }
     }
+
+    #[test]
+    fn bulk_state() {
+        let mut keypad = super::Keypad::new();
+        let state = KeypadState::A | KeypadState::START | KeypadState::LEFT;
+
+        keypad.set_state(state);
+        assert_eq!(keypad.state(), state);
+
+        keypad.wb(0x10);
+        assert_eq!(keypad.rb(), 0xDF & !(1 << 0) & !(1 << 3));
+        keypad.wb(0x20);
+        assert_eq!(keypad.rb(), 0xEF & !(1 << 1));
+        assert_eq!(keypad.interrupt, 0x10);
+
+        keypad.keyup(KeypadKey::Left);
+        assert_eq!(keypad.state(), KeypadState::A | KeypadState::START);
+
+        keypad.set_state(KeypadState::empty());
+        assert_eq!(keypad.rb(), 0xEF);
+    }
+
+    #[test]
+    fn interrupt_on_press() {
+        let mut keypad = super::Keypad::new();
+
+        // Select the action buttons
+        keypad.wb(0x10);
+        keypad.keydown(KeypadKey::A);
+        assert_eq!(keypad.interrupt, 0x10);
+
+        keypad.interrupt = 0;
+        keypad.keydown(KeypadKey::B);
+        assert_eq!(keypad.interrupt, 0x10);
+
+        keypad.interrupt = 0;
+        keypad.keyup(KeypadKey::A);
+        keypad.keyup(KeypadKey::B);
+        assert_eq!(keypad.interrupt, 0);
+
+        // Directions are not selected
+        keypad.keydown(KeypadKey::Up);
+        assert_eq!(keypad.interrupt, 0);
+
+        keypad.wb(0x20);
+        assert_eq!(keypad.interrupt, 0x10);
+    }
 }
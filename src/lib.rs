@@ -1,23 +1,65 @@
 #![crate_name = "rboy"]
 #![crate_type = "lib"]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-pub use crate::gpu::{SCREEN_H, SCREEN_W};
-pub use crate::keypad::KeypadKey;
-pub use crate::sound::AudioPlayer;
-pub use crate::cpu::CPU_FREQUENCY;
+extern crate alloc;
+
+pub use crate::cartridge::{Cartridge, Checksums, MapperKind};
+pub use crate::clock::Clock;
+#[cfg(feature = "std")]
+pub use crate::clock::SystemClock;
+pub use crate::cpu::{IllegalOpcodePolicy, InterruptState, TraceLine, CPU_FREQUENCY};
+#[cfg(feature = "async")]
+pub use crate::frame_stream::FrameStream;
+pub use crate::gbmode::{
+    compat_palette_for_title, cpu_frequency, cycles_to_duration, duration_to_cycles, GbSpeed, Model,
+};
+pub use crate::gpu::{Frame, Layer, PpuDebug, SCREEN_H, SCREEN_W};
+pub use crate::keypad::{KeypadKey, KeypadState};
+pub use crate::mmu::{IoAccess, WatchKind};
+pub use crate::overlay::DebugOverlay;
+#[cfg(feature = "std")]
+pub use crate::printer::{PrintedImage, PrinterStatus};
+#[cfg(feature = "std")]
+pub use crate::ringbuffer::RingBufferAudioPlayer;
+#[cfg(feature = "std")]
+pub use crate::sound::{
+    AudioPlayer, EnvelopeState, LengthState, NoiseState, SoundState, SquareState, WaveState,
+};
+pub use crate::statedump::StateDump;
+pub use crate::timer::TimerState;
 
 pub mod device;
+pub mod movie;
+pub mod palette;
+#[cfg(feature = "file-io")]
+pub mod sandbox;
+#[cfg(feature = "std")]
+pub mod speed;
 
+mod cartridge;
+mod clock;
 mod cpu;
+#[cfg(feature = "async")]
+mod frame_stream;
 mod gbmode;
 mod gpu;
+mod infrared;
 mod keypad;
 mod mbc;
 mod mmu;
+mod overlay;
+#[cfg(feature = "std")]
 mod printer;
 mod register;
+#[cfg(feature = "std")]
+mod ringbuffer;
+#[cfg(feature = "serde")]
+mod serde_array;
 mod serial;
+#[cfg(feature = "std")]
 mod sound;
+mod statedump;
 mod timer;
 
 pub type StrResult<T> = Result<T, &'static str>;
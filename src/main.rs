@@ -2,26 +2,41 @@
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{FromSample, Sample};
-use piccolo::{Callback, Closure, Executor, FromValue, Lua, StashedFunction};
+use piccolo::{Callback, Closure, Executor, FromValue, Lua, StashedExecutor, StashedFunction};
 use piccolo::{CallbackReturn, Value};
 use rboy::device::{Device, FRAME_DURATION};
-use rboy::CPU_FREQUENCY;
+use rboy::movie::InputMovie;
+use rboy::palette;
+use rboy::sandbox::FileSandbox;
+use rboy::speed::{FramePacer, SpeedMeter, SpeedReport};
+use rboy::{Cartridge, IoAccess, Model, WatchKind};
 use std::cell::RefCell;
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::mpsc::{
+    self, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError, TrySendError,
+};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
 use std::collections::HashMap;
 
 const EXITCODE_SUCCESS: i32 = 0;
 const EXITCODE_CPULOADFAILS: i32 = 2;
 
+const SPEED_WINDOW: Duration = Duration::from_secs(2);
+const SPEED_REPORT_INTERVAL: Duration = Duration::from_millis(500);
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+// How often changed battery backed RAM is written, so little is lost when the process is killed
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Default)]
 struct RenderOptions {
     pub linear_interpolation: bool,
+    pub debug_overlay: bool,
 }
 
 /// Holds permission requests from a plugin
@@ -31,6 +46,8 @@ struct RenderOptions {
 struct PluginPermissions {
     pub readbyte: bool,
     pub writebyte: bool,
+    pub filesystem: bool,
+    pub iotrap: bool,
 }
 
 impl<'gc> FromValue<'gc> for PluginPermissions {
@@ -48,19 +65,40 @@ impl<'gc> FromValue<'gc> for PluginPermissions {
         };
         for (key, val) in tab {
             let (Value::Integer(_), Value::String(s)) = (key, val) else {
-                eprintln!("Warn: skipping KV pair in plugin permissions: ([{key}] = {val})");
+                log::warn!("Skipping KV pair in plugin permissions: ([{key}] = {val})");
                 continue;
             };
             match s.as_bytes() {
                 b"readbyte" => ret.readbyte = true,
                 b"writebyte" => ret.writebyte = true,
-                _ => eprintln!("Warn: skipping unknown perm request: {s}"),
+                b"filesystem" => ret.filesystem = true,
+                b"iotrap" => ret.iotrap = true,
+                _ => log::warn!("Skipping unknown perm request: {s}"),
             }
         }
         Ok(ret)
     }
 }
 
+/// The handlers given to `trap_read` and `trap_write`, by address
+#[derive(Default)]
+struct PluginTraps {
+    reads: HashMap<u16, StashedFunction>,
+    writes: HashMap<u16, StashedFunction>,
+}
+
+/// What the IO trap callback of the device needs to call the plugin handlers. The callback
+/// must be `Send`, so it finds them through `TRAP_HOST` on the emulation thread.
+struct TrapHost {
+    lua: Rc<RefCell<Lua>>,
+    executor: StashedExecutor,
+    traps: Rc<RefCell<PluginTraps>>,
+}
+
+thread_local! {
+    static TRAP_HOST: RefCell<Option<TrapHost>> = const { RefCell::new(None) };
+}
+
 struct PluginTable {
     pub plugin_fn: StashedFunction,
     pub permissions: PluginPermissions,
@@ -106,8 +144,13 @@ enum GBEvent {
     SpeedDown,
     Pause,
     Resume,
-    LoadPlugin,
+    LoadPlugin(PathBuf),
     RunPlugin,
+    CyclePalette,
+    ToggleMute,
+    DisplayRefresh(Duration),
+    // Runs frames by the refreshes the render thread tells about instead of by a timer
+    DisplayPacing(Receiver<()>),
 }
 
 #[cfg(target_os = "windows")]
@@ -148,12 +191,36 @@ fn parse_scale_var(arg: &str) -> Result<u32, ArgParseError> {
     match arg.parse::<u32>() {
         Err(e) => Err(ArgParseError::new(format!("Could not parse scale: {}", e))),
         Ok(s) if s < 1 => Err(ArgParseError::new("Scale must be at least 1")),
-        Ok(s) if s > 8 => Err(ArgParseError::new("Scale may be at most 8")),
         Ok(s) => Ok(s),
     }
 }
 
+fn parse_model(arg: &str) -> Result<Model, ArgParseError> {
+    Model::from_name(arg)
+        .ok_or_else(|| ArgParseError::new("Model must be one of dmg, mgb, sgb, cgb or agb"))
+}
+
+// Messages from the library and the plugins go to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
 fn main() {
+    let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Info));
     let exit_status = real_main();
     if exit_status != EXITCODE_SUCCESS {
         std::process::exit(exit_status);
@@ -167,7 +234,7 @@ fn real_main() -> i32 {
         .about("A Gameboy Colour emulator written in Rust")
         .arg(
             clap::Arg::new("filename")
-                .help("Sets the ROM file to load")
+                .help("Sets the ROM file to load, - reads it from stdin")
                 .required(true),
         )
         .arg(
@@ -191,6 +258,20 @@ fn real_main() -> i32 {
                 .long("classic")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("color")
+                .help("Forces the emulator to run in color Gameboy mode")
+                .long("color")
+                .conflicts_with("classic")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("model")
+                .help("Emulates the given model: dmg, mgb, sgb, cgb or agb")
+                .long("model")
+                .conflicts_with_all(["classic", "color"])
+                .value_parser(parse_model),
+        )
         .arg(
             clap::Arg::new("scale")
                 .help("Sets the scale of the interface. Default: 2")
@@ -198,6 +279,13 @@ fn real_main() -> i32 {
                 .long("scale")
                 .value_parser(parse_scale_var),
         )
+        .arg(
+            clap::Arg::new("fullscreen")
+                .help("Starts in borderless fullscreen")
+                .short('f')
+                .long("fullscreen")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             clap::Arg::new("audio")
                 .help("Enables audio")
@@ -205,36 +293,163 @@ fn real_main() -> i32 {
                 .long("audio")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("muted")
+                .help("Starts with audio muted, M toggles it")
+                .long("muted")
+                .requires("audio")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("no-audio-cap")
+                .help("Never drops audio, even when more than a second is buffered")
+                .long("no-audio-cap")
+                .requires("audio")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("pacing")
+                .help("Paces frames by the Gameboy timing or by the refresh rate of the display")
+                .long("pacing")
+                .value_parser(["accurate", "display"])
+                .default_value("accurate"),
+        )
         .arg(
             clap::Arg::new("skip-checksum")
-                .help("Skips verification of the cartridge checksum")
+                .help("Skips verification of the header checksum and ROM size")
                 .long("skip-checksum")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("boot-rom")
+                .help("Runs the given boot ROM before the game")
+                .long("boot-rom"),
+        )
+        .arg(
+            clap::Arg::new("no-boot-animation")
+                .help("Runs the boot ROM without showing its animation")
+                .long("no-boot-animation")
+                .requires("boot-rom")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("logo-frames")
+                .help("Shows the boot logo for the given number of frames")
+                .long("logo-frames")
+                .value_parser(clap::value_parser!(u8)),
+        )
         .arg(
             clap::Arg::new("test-mode")
                 .help("Starts the emulator in a special test mode")
                 .long("test-mode")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            clap::Arg::new("plugin-dir")
+                .help("Directory plugins may access files in. Default: plugin_data")
+                .long("plugin-dir")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::Arg::new("input-log")
+                .help("Plays back the buttons recorded with --record-input")
+                .long("input-log")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::Arg::new("save")
+                .help("Loads and writes battery backed RAM at the given path")
+                .long("save")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::Arg::new("record-input")
+                .help("Records the buttons pressed to the given file on exit")
+                .long("record-input")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::Arg::new("trace")
+                .help("Logs every instruction to the given file, in the Gameboy Doctor format")
+                .long("trace")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            clap::Arg::new("dump-state-on-exit")
+                .help("Prints the registers, interrupts, banks and stack to stderr on exit")
+                .long("dump-state-on-exit")
+                .action(clap::ArgAction::SetTrue),
+        )
         .get_matches();
 
     let test_mode = matches.get_one::<bool>("test-mode").copied().unwrap();
     let opt_serial = matches.get_one::<bool>("serial").copied().unwrap();
     let opt_printer = matches.get_one::<bool>("printer").copied().unwrap();
     let opt_classic = matches.get_one::<bool>("classic").copied().unwrap();
+    let opt_color = matches.get_one::<bool>("color").copied().unwrap();
+    // --classic and --color pick the DMG and CGB, without any the cartridge header decides
+    let opt_model = match (opt_classic, opt_color) {
+        (true, _) => Some(Model::Dmg),
+        (_, true) => Some(Model::Cgb),
+        _ => matches.get_one::<Model>("model").copied(),
+    };
     let opt_audio = matches.get_one::<bool>("audio").copied().unwrap();
+    let opt_muted = matches.get_one::<bool>("muted").copied().unwrap();
+    let opt_no_audio_cap = matches.get_one::<bool>("no-audio-cap").copied().unwrap();
+    let opt_display_pacing = matches.get_one::<String>("pacing").unwrap() == "display";
     let opt_skip_checksum = matches.get_one::<bool>("skip-checksum").copied().unwrap();
+    let opt_boot_rom = matches.get_one::<String>("boot-rom");
+    let opt_logo_frames = matches.get_one::<u8>("logo-frames").copied();
+    let opt_no_boot_animation = matches
+        .get_one::<bool>("no-boot-animation")
+        .copied()
+        .unwrap();
     let filename = matches.get_one::<String>("filename").unwrap();
     let scale = matches.get_one::<u32>("scale").copied().unwrap_or(2);
+    let opt_fullscreen = matches.get_one::<bool>("fullscreen").copied().unwrap();
+    let plugin_dir = matches
+        .get_one::<PathBuf>("plugin-dir")
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from("plugin_data"));
+    let opt_trace = matches.get_one::<PathBuf>("trace");
+    let opt_input_log = matches.get_one::<PathBuf>("input-log");
+    let opt_record_input = matches.get_one::<PathBuf>("record-input").cloned();
+    let opt_save = matches.get_one::<PathBuf>("save");
+    let opt_dump_state = matches
+        .get_one::<bool>("dump-state-on-exit")
+        .copied()
+        .unwrap();
 
     if test_mode {
-        return run_test_mode(filename, opt_classic, opt_skip_checksum);
+        let model = opt_model.unwrap_or(Model::Cgb);
+        return run_test_mode(filename, model, opt_skip_checksum);
+    }
+
+    let playback = match opt_input_log {
+        Some(path) => match std::fs::read_to_string(path)
+            .map_err(|_| "Could not read input log")
+            .and_then(|text| InputMovie::parse(&text))
+        {
+            Ok(movie) => Some(movie),
+            Err(message) => {
+                warn(message);
+                return EXITCODE_CPULOADFAILS;
+            }
+        },
+        None => None,
+    };
+
+    if let Some(parent) = opt_save.and_then(|path| path.parent()) {
+        if std::fs::create_dir_all(parent).is_err() {
+            warn("Could not create the directory of the save file");
+            return EXITCODE_CPULOADFAILS;
+        }
     }
 
     let cpu = construct_cpu(
         filename,
-        opt_classic,
+        opt_save.map(PathBuf::as_path),
+        opt_model,
         opt_serial,
         opt_printer,
         opt_skip_checksum,
@@ -244,17 +459,46 @@ fn real_main() -> i32 {
         return EXITCODE_CPULOADFAILS;
     };
 
+    if let Some(path) = opt_boot_rom {
+        let booted = std::fs::read(path)
+            .map_err(|_| "Could not read boot ROM")
+            .and_then(|rom| cpu.start_boot_rom(rom))
+            .and_then(|()| match opt_no_boot_animation {
+                true => cpu.skip_boot_animation(),
+                false => Ok(()),
+            });
+        if let Err(message) = booted {
+            warn(message);
+            return EXITCODE_CPULOADFAILS;
+        }
+    }
+    if let Some(frames) = opt_logo_frames {
+        cpu.show_boot_logo(frames);
+    }
+    if let Some(path) = opt_trace {
+        let Ok(file) = File::create(path) else {
+            warn("Could not create trace file");
+            return EXITCODE_CPULOADFAILS;
+        };
+        let mut out = io::BufWriter::new(file);
+        cpu.set_trace_logger(Box::new(move |line| {
+            let _ = writeln!(out, "{}", line);
+        }));
+    }
+
     let mut cpal_audio_stream = None;
     if opt_audio {
         let player = CpalPlayer::get();
         match player {
-            Some((v, s)) => {
+            Some((mut v, s)) => {
+                v.set_capped(!opt_no_audio_cap);
                 cpu.enable_audio(Box::new(v) as Box<dyn rboy::AudioPlayer>);
+                cpu.set_audio_muted(opt_muted);
                 cpal_audio_stream = Some(s);
             }
             None => {
-                warn("Could not open audio device");
-                return EXITCODE_CPULOADFAILS;
+                warn("Could not open audio device, audio is disabled");
+                cpu.enable_audio(Box::new(NullAudioPlayer {}));
             }
         }
     }
@@ -262,13 +506,27 @@ fn real_main() -> i32 {
 
     let (sender1, receiver1) = mpsc::channel();
     let (sender2, receiver2) = mpsc::sync_channel(1);
+    let (speed_sender, speed_receiver) = mpsc::channel();
 
     let mut event_loop = winit::event_loop::EventLoop::new().unwrap();
     let window_builder = create_window_builder(&romname);
-    let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new()
-        .set_window_builder(window_builder)
-        .build(&event_loop);
+    let (window, display, vsync) = build_display(&event_loop, window_builder, opt_display_pacing);
+    let scale = match max_scale(&window) {
+        Some(max) if scale > max => {
+            warn(&format!(
+                "Scale {} does not fit on the screen, using {}",
+                scale, max
+            ));
+            max
+        }
+        _ => scale,
+    };
     set_window_size(&window, scale);
+    // The size to go back to when leaving fullscreen
+    let mut windowed_size = None;
+    if opt_fullscreen {
+        toggle_fullscreen(&window, &mut windowed_size);
+    }
 
     let mut texture = glium::texture::texture2d::Texture2d::empty_with_format(
         &display,
@@ -280,8 +538,46 @@ fn real_main() -> i32 {
     .unwrap();
 
     let mut renderoptions = <RenderOptions as Default>::default();
-
-    let cputhread = thread::spawn(move || run_cpu(cpu, sender2, receiver1));
+    let mut last_fps = 0.0;
+
+    // Show a black screen instead of whatever the new texture holds until the first frame
+    let mut shown = vec![0; rboy::SCREEN_W * rboy::SCREEN_H * 3];
+    recalculate_screen(&display, &mut texture, &shown, &renderoptions);
+
+    // With display pacing every buffer swap waits for a refresh, and the CPU thread runs a frame
+    // when told about enough of them. Follows the window to monitors with another refresh rate.
+    let mut refresh = None;
+    let (refresh_sender, refresh_receiver) = mpsc::sync_channel(1);
+    if opt_display_pacing {
+        refresh = display_refresh(&window);
+        match (refresh, vsync) {
+            (Some(interval), true) => {
+                let _ = sender1.send(GBEvent::DisplayPacing(refresh_receiver));
+                let _ = sender1.send(GBEvent::DisplayRefresh(interval));
+            }
+            (None, _) => {
+                warn("Could not get the refresh rate of the display, using accurate timing")
+            }
+            (Some(_), false) => {
+                warn("Could not wait for the refresh of the display, using accurate timing");
+                refresh = None;
+            }
+        }
+    }
+    let display_pacing = refresh.is_some();
+    let mut last_swap = Instant::now();
+
+    let cputhread = thread::spawn(move || {
+        run_cpu(
+            cpu,
+            sender2,
+            receiver1,
+            speed_sender,
+            plugin_dir,
+            playback,
+            opt_record_input,
+        )
+    });
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
     let mut paused = false;
@@ -295,11 +591,21 @@ fn real_main() -> i32 {
             match ev {
                 Event::WindowEvent { event, .. } => match event {
                     WindowEvent::CloseRequested => elwt.exit(),
+                    WindowEvent::Moved(_) if display_pacing => {
+                        let current = display_refresh(&window);
+                        if let (Some(interval), true) = (current, current != refresh) {
+                            let _ = sender1.send(GBEvent::DisplayRefresh(interval));
+                            refresh = current;
+                        }
+                    }
                     WindowEvent::KeyboardInput {
                         event: keyevent, ..
                     } => match (keyevent.state, keyevent.logical_key.as_ref()) {
                         (Pressed, Key::Named(NamedKey::Escape)) => elwt.exit(),
                         (Pressed, Key::Character("1")) => set_window_size(&window, 1),
+                        (Pressed, Key::Named(NamedKey::F11)) => {
+                            toggle_fullscreen(&window, &mut windowed_size)
+                        }
                         // (Pressed, Key::Character("r" | "R")) => set_window_size(&window, scale),
                         (Pressed, Key::Character("p" | "P")) => {
                             let _ = sender1.send(if paused {
@@ -310,7 +616,9 @@ fn real_main() -> i32 {
                             paused = !paused;
                         }
                         (Pressed, Key::Character("l" | "L")) => {
-                            let _ = sender1.send(GBEvent::LoadPlugin);
+                            if let Some(path) = choose_plugin_file() {
+                                let _ = sender1.send(GBEvent::LoadPlugin(path));
+                            }
                         }
                         (Pressed, Key::Character("r" | "R")) => {
                             let _ = sender1.send(GBEvent::RunPlugin);
@@ -321,10 +629,19 @@ fn real_main() -> i32 {
                         (Released, Key::Named(NamedKey::Shift)) => {
                             let _ = sender1.send(GBEvent::SpeedDown);
                         }
+                        (Pressed, Key::Character("c" | "C")) => {
+                            let _ = sender1.send(GBEvent::CyclePalette);
+                        }
+                        (Pressed, Key::Character("m" | "M")) => {
+                            let _ = sender1.send(GBEvent::ToggleMute);
+                        }
                         (Pressed, Key::Character("t" | "T")) => {
                             renderoptions.linear_interpolation =
                                 !renderoptions.linear_interpolation;
                         }
+                        (Pressed, Key::Character("o" | "O")) => {
+                            renderoptions.debug_overlay = !renderoptions.debug_overlay;
+                        }
                         (Pressed, winitkey) => {
                             if let Some(key) = winit_to_keypad(winitkey) {
                                 let _ = sender1.send(GBEvent::KeyDown(key));
@@ -346,30 +663,70 @@ fn real_main() -> i32 {
             break 'evloop;
         }
 
-        let new_frame = if paused {
+        if let Ok(report) = speed_receiver.try_recv() {
+            window.set_title(&speed_title(&romname, report));
+            last_fps = report.fps;
+        }
+
+        let new_frame = if display_pacing {
+            // Every refresh is drawn, repeating the last frame when there is no new one
+            match receiver2.try_recv() {
+                Ok(data) => Some(data),
+                Err(TryRecvError::Empty) => None,
+                Err(..) => break 'evloop, // Remote end has hung-up
+            }
+        } else if paused {
             match receiver2.try_recv() {
-                Ok(data) => data,
+                Ok(data) => Some(data),
                 Err(TryRecvError::Empty) => continue,
                 Err(..) => break 'evloop, // Remote end has hung-up
             }
         } else {
-            match receiver2.recv() {
-                Ok(data) => data,
+            // Keep handling window events while the CPU thread has nothing to show
+            match receiver2.recv_timeout(FRAME_TIMEOUT) {
+                Ok(data) => Some(data),
+                Err(RecvTimeoutError::Timeout) => continue,
                 Err(..) => break 'evloop, // Remote end has hung-up
             }
         };
-        recalculate_screen(&display, &mut texture, &*new_frame, &renderoptions);
+        if let Some((mut new_frame, overlay)) = new_frame {
+            if renderoptions.debug_overlay {
+                rboy::DebugOverlay {
+                    fps: last_fps,
+                    ..overlay
+                }
+                .draw(&mut new_frame);
+            }
+            shown = new_frame;
+        }
+        recalculate_screen(&display, &mut texture, &shown, &renderoptions);
+        if let (Some(interval), true) = (refresh, display_pacing) {
+            wait_for_refresh(&mut last_swap, interval);
+            let _ = refresh_sender.try_send(());
+        }
     }
 
     let _ = sender1.send(GBEvent::Resume);
 
     drop(cpal_audio_stream);
     drop(receiver2); // Stop CPU thread by disconnecting
-    let _ = cputhread.join();
+    drop(refresh_sender); // Which may be waiting for a refresh
+    if let (Ok(dump), true) = (cputhread.join(), opt_dump_state) {
+        eprint!("{}", dump);
+    }
 
     EXITCODE_SUCCESS
 }
 
+fn speed_title(romname: &str, report: SpeedReport) -> String {
+    format!(
+        "RBoy - {} ({:.0}% / {:.0} fps)",
+        romname,
+        report.speed * 100.0,
+        report.fps
+    )
+}
+
 fn winit_to_keypad(key: winit::keyboard::Key<&str>) -> Option<rboy::KeypadKey> {
     use winit::keyboard::{Key, NamedKey};
     match key {
@@ -418,36 +775,103 @@ fn recalculate_screen<
     );
 
     // We use a custom BlitTarget to transform OpenGL coordinates to row-column coordinates
-    let target = display.draw();
+    let mut target = display.draw();
     let (target_w, target_h) = target.get_dimensions();
+    let (left, bottom, width, height) = letterbox(target_w, target_h);
+    target.clear_color(0.0, 0.0, 0.0, 1.0);
     texture.as_surface().blit_whole_color_to(
         &target,
         &glium::BlitTarget {
-            left: 0,
-            bottom: target_h,
-            width: target_w as i32,
-            height: -(target_h as i32),
+            left,
+            bottom: bottom + height,
+            width: width as i32,
+            height: -(height as i32),
         },
         interpolation_type,
     );
     target.finish().unwrap();
 }
 
+/// Like glium's `SimpleWindowBuilder`, but with `vsync` the buffer swaps wait for the display to
+/// refresh. Also returns whether they do.
+fn build_display(
+    event_loop: &winit::event_loop::EventLoop<()>,
+    window_builder: winit::window::WindowBuilder,
+    vsync: bool,
+) -> (
+    winit::window::Window,
+    glium::Display<glium::glutin::surface::WindowSurface>,
+    bool,
+) {
+    use glium::glutin::config::ConfigTemplateBuilder;
+    use glium::glutin::context::ContextAttributesBuilder;
+    use glium::glutin::display::GetGlDisplay;
+    use glium::glutin::prelude::*;
+    use glium::glutin::surface::{SurfaceAttributesBuilder, SwapInterval, WindowSurface};
+    use raw_window_handle::HasRawWindowHandle;
+    use std::num::NonZeroU32;
+
+    let (window, config) = glutin_winit::DisplayBuilder::new()
+        .with_window_builder(Some(window_builder))
+        .build(event_loop, ConfigTemplateBuilder::new(), |mut configs| {
+            configs.next().unwrap()
+        })
+        .unwrap();
+    let window = window.unwrap();
+
+    let (width, height): (u32, u32) = window.inner_size().into();
+    let attrs = SurfaceAttributesBuilder::<WindowSurface>::new().build(
+        window.raw_window_handle(),
+        NonZeroU32::new(width).unwrap(),
+        NonZeroU32::new(height).unwrap(),
+    );
+    let surface = unsafe {
+        config
+            .display()
+            .create_window_surface(&config, &attrs)
+            .unwrap()
+    };
+    let context_attrs = ContextAttributesBuilder::new().build(Some(window.raw_window_handle()));
+    let context = unsafe { config.display().create_context(&config, &context_attrs) }
+        .expect("failed to create context")
+        .make_current(&surface)
+        .unwrap();
+    let interval = match vsync {
+        true => SwapInterval::Wait(NonZeroU32::MIN),
+        false => SwapInterval::DontWait,
+    };
+    let vsync = surface.set_swap_interval(&context, interval).is_ok() && vsync;
+    let display = glium::Display::from_context_surface(context, surface).unwrap();
+    (window, display, vsync)
+}
+
+/// Sleeps for the rest of the refresh interval when the last buffer swap did not wait for the
+/// display, as some platforms do while the window is hidden, so the game does not speed up
+fn wait_for_refresh(last_swap: &mut Instant, interval: Duration) {
+    let elapsed = last_swap.elapsed();
+    if elapsed < interval / 2 {
+        thread::sleep(interval - elapsed);
+    }
+    *last_swap = Instant::now();
+}
+
 fn warn(message: &str) {
     eprintln!("{}", message);
 }
 
 fn construct_cpu(
     filename: &str,
-    classic_mode: bool,
+    save: Option<&Path>,
+    model: Option<Model>,
     output_serial: bool,
     output_printer: bool,
     skip_checksum: bool,
 ) -> Option<Device> {
-    let opt_c = match classic_mode {
-        true => Device::new(filename, skip_checksum),
-        false => Device::new_cgb(filename, skip_checksum),
-    };
+    let cart = load_cartridge(filename, save, skip_checksum);
+    let opt_c = cart.and_then(|cart| match model {
+        Some(model) => Device::new_from_cartridge_with_model(cart, model),
+        None => Device::new_auto_from_cartridge(cart),
+    });
     let mut c = match opt_c {
         Ok(cpu) => cpu,
         Err(message) => {
@@ -465,6 +889,28 @@ fn construct_cpu(
     Some(c)
 }
 
+/// A filename of `-` reads the ROM from stdin, which has no save file unless `save` is given
+fn load_cartridge(
+    filename: &str,
+    save: Option<&Path>,
+    skip_checksum: bool,
+) -> rboy::StrResult<Cartridge> {
+    if filename != "-" {
+        return match save {
+            Some(path) => Cartridge::from_file_with_save(filename, path, skip_checksum),
+            None => Cartridge::from_file(filename, skip_checksum),
+        };
+    }
+    let mut data = Vec::new();
+    io::stdin()
+        .read_to_end(&mut data)
+        .map_err(|_| "Could not read ROM from stdin")?;
+    match save {
+        Some(path) => Cartridge::from_bytes_with_save(data, path, skip_checksum),
+        None => Cartridge::from_bytes(data, skip_checksum),
+    }
+}
+
 fn ask_user_for_permission(permission_name: &str) -> bool {
     print!("Autorize {} ? (y/n, défaut: n): ", permission_name);
     io::stdout().flush().unwrap();
@@ -475,7 +921,13 @@ fn ask_user_for_permission(permission_name: &str) -> bool {
     matches!(input.trim().to_lowercase().as_str(), "y")
 }
 
-fn load_permissions(lua: &mut Lua, perms: &PluginPermissions, cpu: &Rc<RefCell<Device>>) {
+fn load_permissions(
+    lua: &mut Lua,
+    perms: &PluginPermissions,
+    cpu: &Rc<RefCell<Device>>,
+    traps: &Rc<RefCell<PluginTraps>>,
+    plugin_dir: &Path,
+) {
 
     let perms_str = format!("{:?}", perms);
     let trimmed_str = perms_str.trim_start_matches("PluginPermissions { ").trim_end_matches(" }");
@@ -517,13 +969,17 @@ fn load_permissions(lua: &mut Lua, perms: &PluginPermissions, cpu: &Rc<RefCell<D
             lua.enter(|ctx| {
                 let _ = ctx.set_global(
                     "readbyte",
-                    Callback::from_fn(&ctx, move |_, _, mut stack| {
+                    Callback::from_fn(&ctx, move |ctx, _, mut stack| {
                         let Value::Integer(address) = stack.pop_front() else {
                             stack.push_front(Value::Nil);
                             return Ok(CallbackReturn::Return);
                         };
 
-                        let byte = rb_clone.borrow().cpu.mmu.rb(address as u16);
+                        // Trap handlers run while the device is emulating
+                        let Ok(mut cpu) = rb_clone.try_borrow_mut() else {
+                            return Err(busy_device_error(ctx));
+                        };
+                        let byte = cpu.cpu.mmu.rb(address as u16);
                         stack.push_front(Value::Integer(byte as i64));
                         Ok(piccolo::CallbackReturn::Return)
                     }),
@@ -539,7 +995,7 @@ fn load_permissions(lua: &mut Lua, perms: &PluginPermissions, cpu: &Rc<RefCell<D
             lua.enter(|ctx| {
                 let _ = ctx.set_global(
                     "writebyte",
-                    Callback::from_fn(&ctx, move |_, _, mut stack| {
+                    Callback::from_fn(&ctx, move |ctx, _, mut stack| {
                         let Value::Integer(address) = stack.pop_front() else {
                             stack.push_front(Value::Nil);
                             return Ok(CallbackReturn::Return);
@@ -550,17 +1006,201 @@ fn load_permissions(lua: &mut Lua, perms: &PluginPermissions, cpu: &Rc<RefCell<D
                             return Ok(CallbackReturn::Return);
                         };
 
-                        wb_clone.borrow_mut().cpu.mmu.wb(address as u16, byte as u8);
+                        let Ok(mut cpu) = wb_clone.try_borrow_mut() else {
+                            return Err(busy_device_error(ctx));
+                        };
+                        cpu.cpu.mmu.wb(address as u16, byte as u8);
                         Ok(piccolo::CallbackReturn::Return)
                     }),
                 );
             });
         }
     }
+
+    if let Some(iotrap_permission) = permissions_map.get("iotrap") {
+        if *iotrap_permission {
+            println!("Giving iotrap permission");
+            load_trap_functions(lua, cpu, traps);
+        }
+    }
+
+    if let Some(filesystem_permission) = permissions_map.get("filesystem") {
+        if *filesystem_permission {
+            match FileSandbox::new(plugin_dir) {
+                Ok(sandbox) => {
+                    println!("Giving filesystem permission in {}", sandbox.root().display());
+                    load_file_functions(lua, Rc::new(sandbox));
+                }
+                Err(e) => log::warn!("Could not give filesystem permission: {e}"),
+            }
+        }
+    }
     print!("\n");
 
 }
 
+/// `trap_read(address, handler)` and `trap_write(address, handler)` take the address away from
+/// the emulated hardware. The handlers run during the access, see `set_trap_host`, with the
+/// address and the value read or written. Whatever a read handler returns is what that read
+/// gives the game, 0xFF if it returns nothing.
+fn load_trap_functions(lua: &mut Lua, cpu: &Rc<RefCell<Device>>, traps: &Rc<RefCell<PluginTraps>>) {
+    lua.enter(|ctx| {
+        let globals = [
+            ("trap_read", WatchKind::Read),
+            ("trap_write", WatchKind::Write),
+        ];
+        for (name, kind) in globals {
+            let cpu = cpu.clone();
+            let traps = traps.clone();
+            let _ = ctx.set_global(
+                name,
+                Callback::from_fn(&ctx, move |ctx, _, mut stack| {
+                    let (Value::Integer(address), Value::Function(handler)) =
+                        (stack.pop_front(), stack.pop_front())
+                    else {
+                        stack.push_front(Value::Nil);
+                        return Ok(CallbackReturn::Return);
+                    };
+
+                    let address = address as u16;
+                    let Ok(mut cpu) = cpu.try_borrow_mut() else {
+                        return Err(busy_device_error(ctx));
+                    };
+                    let mut traps = traps.borrow_mut();
+                    match kind {
+                        WatchKind::Read => traps.reads.insert(address, ctx.stash(handler)),
+                        WatchKind::Write => traps.writes.insert(address, ctx.stash(handler)),
+                    };
+                    let on_read = traps.reads.contains_key(&address);
+                    let on_write = traps.writes.contains_key(&address);
+                    cpu.add_io_trap(address, on_read, on_write);
+                    Ok(CallbackReturn::Return)
+                }),
+            );
+        }
+    });
+}
+
+/// Removes the traps of the previous plugin
+fn clear_traps(traps: &Rc<RefCell<PluginTraps>>, cpu: &Rc<RefCell<Device>>) {
+    let mut traps = traps.borrow_mut();
+    for address in traps.reads.keys().chain(traps.writes.keys()) {
+        cpu.borrow_mut().remove_io_trap(*address);
+    }
+    *traps = PluginTraps::default();
+}
+
+// Returned by the functions that need the device when a trap handler calls them, because the
+// access that ran the handler is still going on
+fn busy_device_error(ctx: piccolo::Context<'_>) -> piccolo::Error<'_> {
+    Value::String(ctx.intern_static(b"the device can not be used in a trap handler")).into()
+}
+
+/// Makes the device call the plugin handlers during every trapped access
+fn set_trap_host(cpu: &mut Device, host: TrapHost) {
+    TRAP_HOST.with(|h| *h.borrow_mut() = Some(host));
+    cpu.set_io_trap_callback(Box::new(|access| {
+        TRAP_HOST.with(|host| host.borrow().as_ref()?.run_handler(access))
+    }));
+}
+
+impl TrapHost {
+    fn run_handler(&self, access: IoAccess) -> Option<u8> {
+        // The plugin itself accessed a trapped address with `readbyte` or `writebyte`
+        let Ok(mut lua) = self.lua.try_borrow_mut() else {
+            log::error!(
+                "Plugin trap handler for {:04X} not run from plugin code",
+                access.address
+            );
+            return None;
+        };
+        let found = lua.enter(|ctx| {
+            let traps = self.traps.borrow();
+            let handler = match access.kind {
+                WatchKind::Read => traps.reads.get(&access.address),
+                WatchKind::Write => traps.writes.get(&access.address),
+            };
+            let Some(handler) = handler else {
+                return false;
+            };
+            let args = (access.address as i64, access.value as i64);
+            ctx.fetch(&self.executor)
+                .restart(ctx, ctx.fetch(handler), args);
+            true
+        });
+        if !found {
+            return None;
+        }
+        match lua.execute::<Option<i64>>(&self.executor) {
+            Ok(Some(value)) if access.kind == WatchKind::Read => Some(value as u8),
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Error in plugin trap handler: {e}");
+                None
+            }
+        }
+    }
+}
+
+fn load_file_functions(lua: &mut Lua, sandbox: Rc<FileSandbox>) {
+    let read_sandbox = sandbox.clone();
+    let write_sandbox = sandbox;
+    lua.enter(|ctx| {
+        let _ = ctx.set_global(
+            "readfile",
+            Callback::from_fn(&ctx, move |ctx, _, mut stack| {
+                let Value::String(path) = stack.pop_front() else {
+                    stack.push_front(Value::Nil);
+                    return Ok(CallbackReturn::Return);
+                };
+
+                match read_sandbox.read(&path.to_str_lossy()) {
+                    Ok(data) => stack.push_front(Value::String(ctx.intern(&data))),
+                    Err(e) => {
+                        log::warn!("Plugin readfile denied: {e}");
+                        stack.push_front(Value::Nil);
+                    }
+                }
+                Ok(CallbackReturn::Return)
+            }),
+        );
+        let _ = ctx.set_global(
+            "writefile",
+            Callback::from_fn(&ctx, move |_, _, mut stack| {
+                let (Value::String(path), Value::String(data)) =
+                    (stack.pop_front(), stack.pop_front())
+                else {
+                    stack.push_front(Value::Nil);
+                    return Ok(CallbackReturn::Return);
+                };
+
+                match write_sandbox.write(&path.to_str_lossy(), data.as_bytes()) {
+                    Ok(()) => stack.push_front(Value::Boolean(true)),
+                    Err(e) => {
+                        log::warn!("Plugin writefile denied: {e}");
+                        stack.push_front(Value::Nil);
+                    }
+                }
+                Ok(CallbackReturn::Return)
+            }),
+        );
+    });
+}
+
+/// Returns `None` when the user cancels the dialog
+#[cfg(feature = "file-dialog")]
+fn choose_plugin_file() -> Option<PathBuf> {
+    rfd::FileDialog::new()
+        .set_title("Load plugin")
+        .add_filter("Lua plugin", &["lua"])
+        .pick_file()
+}
+
+#[cfg(not(feature = "file-dialog"))]
+fn choose_plugin_file() -> Option<PathBuf> {
+    Some(PathBuf::from("plugin.lua"))
+}
+
 fn pause_cpu(receiver: &Receiver<GBEvent>) {
     'a: loop {
         let res = receiver.recv();
@@ -572,54 +1212,135 @@ fn pause_cpu(receiver: &Receiver<GBEvent>) {
     }
 }
 
-fn run_cpu(cpu: Device, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>) {
-    let periodic = timer_periodic(FRAME_DURATION);
+fn run_cpu(
+    cpu: Device,
+    sender: SyncSender<(Vec<u8>, rboy::DebugOverlay)>,
+    receiver: Receiver<GBEvent>,
+    speed_sender: Sender<SpeedReport>,
+    plugin_dir: PathBuf,
+    playback: Option<InputMovie>,
+    record_path: Option<PathBuf>,
+) -> rboy::StateDump {
+    let mut periodic = timer_periodic(FRAME_DURATION);
+    let mut pacer: Option<FramePacer> = None;
     let mut limit_speed = true;
     let cpu = Rc::new(RefCell::new(cpu));
 
-    let mut lua = Lua::full();
+    let lua = Rc::new(RefCell::new(Lua::full()));
+    // Restarting one executor for every call keeps running a plugin from allocating
+    let executor = lua.borrow_mut().enter(|ctx| ctx.stash(Executor::new(ctx)));
 
     let mut plugin_table: Option<PluginTable> = None;
+    let traps = Rc::new(RefCell::new(PluginTraps::default()));
+    let host = TrapHost {
+        lua: lua.clone(),
+        executor: executor.clone(),
+        traps: traps.clone(),
+    };
+    set_trap_host(&mut cpu.borrow_mut(), host);
+    let mut palette_index = 0;
+
+    let mut speed_meter = SpeedMeter::new(SPEED_WINDOW);
+    let mut last_report = Instant::now();
+    speed_meter.reset(last_report);
+    let mut last_save = last_report;
 
-    let waitticks = ((CPU_FREQUENCY / 1000.0) * FRAME_DURATION.as_millis() as f64).round() as u32;
-    let mut ticks = 0;
+    // The buttons pressed on the keyboard are ignored until the input log runs out
+    let mut playback = playback.as_ref().map(|movie| movie.frames());
+    let mut recording = record_path.as_ref().map(|_| InputMovie::new());
 
     'outer: loop {
-        while ticks < waitticks {
-            ticks += cpu.borrow_mut().do_cycle();
-            if cpu.borrow_mut().check_and_reset_gpu_updated() {
-                let data = cpu.borrow().get_gpu_data().to_vec();
-                if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
-                    break 'outer;
+        if let Some(frames) = playback.as_mut() {
+            match frames.next() {
+                Some(state) => cpu.borrow_mut().set_keypad_state(state),
+                None => {
+                    playback = None;
+                    println!("Input log finished");
                 }
             }
         }
+        if let Some(movie) = recording.as_mut() {
+            movie.record(cpu.borrow().keypad_state());
+        }
 
-        ticks -= waitticks;
+        let start_cycles = cpu.borrow().total_cycles();
+        let data = cpu.borrow_mut().run_one_frame().to_vec();
+        let cycles = (cpu.borrow().total_cycles() - start_cycles) as u32;
+        // Nothing new is drawn while the LCD is off
+        let updated = cpu.borrow_mut().check_and_reset_gpu_updated();
+        if updated {
+            let overlay = cpu.borrow().debug_overlay();
+            if let Err(TrySendError::Disconnected(..)) = sender.try_send((data, overlay)) {
+                break 'outer;
+            }
+        }
+
+        let now = Instant::now();
+        speed_meter.add(now, cycles, updated as u32);
+        if now.duration_since(last_report) >= SPEED_REPORT_INTERVAL {
+            last_report = now;
+            if let Some(report) = speed_meter.report() {
+                let _ = speed_sender.send(report);
+            }
+        }
+        if now.duration_since(last_save) >= SAVE_INTERVAL {
+            last_save = now;
+            if cpu.borrow_mut().check_and_reset_ram_updated() {
+                flush_save(&mut cpu.borrow_mut());
+            }
+        }
 
         'recv: loop {
             match receiver.try_recv() {
                 Ok(event) => match event {
+                    GBEvent::KeyUp(_) | GBEvent::KeyDown(_) if playback.is_some() => (),
                     GBEvent::KeyUp(key) => cpu.borrow_mut().keyup(key),
                     GBEvent::KeyDown(key) => cpu.borrow_mut().keydown(key),
-                    GBEvent::SpeedUp => limit_speed = false,
+                    GBEvent::SpeedUp => {
+                        limit_speed = false;
+                        cpu.borrow_mut().set_speed_multiplier(0.0);
+                    }
                     GBEvent::SpeedDown => {
                         limit_speed = true;
+                        cpu.borrow_mut().set_speed_multiplier(1.0);
                         cpu.borrow_mut().sync_audio();
                     }
                     GBEvent::Pause => {
+                        cpu.borrow_mut().clear_audio();
                         pause_cpu(&receiver);
+                        cpu.borrow_mut().sync_audio();
+                        speed_meter.reset(Instant::now());
                     }
                     GBEvent::Resume => (),
-                    GBEvent::LoadPlugin => {
-                        let readfile =
-                            piccolo::io::buffered_read(File::open("plugin.lua").unwrap()).unwrap();
-                        let executor = lua.enter(|ctx| {
-                            let closure = Closure::load(ctx, Some("plugin.lua"), readfile).unwrap();
-                            ctx.stash(Executor::start(ctx, closure.into(), ()))
+                    GBEvent::ToggleMute => {
+                        let muted = !cpu.borrow().audio_muted();
+                        cpu.borrow_mut().set_audio_muted(muted);
+                    }
+                    GBEvent::DisplayRefresh(interval) => {
+                        pacer = Some(FramePacer::new(FRAME_DURATION, interval));
+                    }
+                    GBEvent::DisplayPacing(refreshes) => periodic = refreshes,
+                    GBEvent::CyclePalette => {
+                        palette_index = (palette_index + 1) % palette::PRESETS.len();
+                        let (name, palette) = palette::PRESETS[palette_index];
+                        cpu.borrow_mut().set_dmg_palette(palette);
+                        println!("Palette: {}", name);
+                    }
+                    GBEvent::LoadPlugin(path) => {
+                        let Ok(file) = File::open(&path) else {
+                            log::error!("Could not open plugin {}", path.display());
+                            continue;
+                        };
+                        let readfile = piccolo::io::buffered_read(file).unwrap();
+                        let name = path.display().to_string();
+                        let mut lua = lua.borrow_mut();
+                        lua.enter(|ctx| {
+                            let closure = Closure::load(ctx, Some(&*name), readfile).unwrap();
+                            ctx.fetch(&executor).restart(ctx, closure.into(), ());
                         });
                         let tab = lua.execute::<PluginTable>(&executor).unwrap();
-                        load_permissions(&mut lua, &tab.permissions, &cpu);
+                        clear_traps(&traps, &cpu);
+                        load_permissions(&mut lua, &tab.permissions, &cpu, &traps, &plugin_dir);
                         plugin_table = Some(tab);
                         println!("Loaded plugin");
                     }
@@ -629,13 +1350,14 @@ fn run_cpu(cpu: Device, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>
                             continue;
                         };
 
-                        let executor = lua.enter(|ctx| {
+                        let mut lua = lua.borrow_mut();
+                        lua.enter(|ctx| {
                             let f = ctx.fetch(&ptab.plugin_fn);
-                            ctx.stash(Executor::start(ctx, f, ()))
+                            ctx.fetch(&executor).restart(ctx, f, ());
                         });
                         match lua.execute::<()>(&executor) {
                             Ok(()) => {}
-                            Err(e) => eprintln!("Error during plugin execution: {e}"),
+                            Err(e) => log::error!("Error during plugin execution: {e}"),
                         };
                     }
                 },
@@ -645,9 +1367,29 @@ fn run_cpu(cpu: Device, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>
         }
 
         if limit_speed {
-            let _ = periodic.recv();
+            let refreshes = pacer.as_mut().map_or(1, |p| p.refreshes_until_next_frame());
+            for _ in 0..refreshes {
+                let _ = periodic.recv();
+            }
+        }
+    }
+
+    flush_save(&mut cpu.borrow_mut());
+
+    if let (Some(path), Some(movie)) = (record_path, recording) {
+        if std::fs::write(&path, movie.to_string()).is_err() {
+            log::error!("Could not write input log to {}", path.display());
         }
     }
+
+    let dump = cpu.borrow().state_dump();
+    dump
+}
+
+fn flush_save(cpu: &mut Device) {
+    if let Err(message) = cpu.on_shutdown() {
+        log::error!("{}", message);
+    }
 }
 
 fn timer_periodic(d: std::time::Duration) -> Receiver<()> {
@@ -661,6 +1403,51 @@ fn timer_periodic(d: std::time::Duration) -> Receiver<()> {
     rx
 }
 
+/// The largest part of the target with the aspect ratio of the screen, centered, as
+/// (left, bottom, width, height)
+fn letterbox(target_w: u32, target_h: u32) -> (u32, u32, u32, u32) {
+    let (screen_w, screen_h) = (rboy::SCREEN_W as u32, rboy::SCREEN_H as u32);
+    let (width, height) = if target_w * screen_h > target_h * screen_w {
+        (target_h * screen_w / screen_h, target_h)
+    } else {
+        (target_w, target_w * screen_h / screen_w)
+    };
+    let (left, bottom) = ((target_w - width) / 2, (target_h - height) / 2);
+    (left, bottom, width, height)
+}
+
+/// The refresh interval of the monitor showing the window, when the platform knows it
+fn display_refresh(window: &winit::window::Window) -> Option<Duration> {
+    let millihertz = window.current_monitor()?.refresh_rate_millihertz()?;
+    Some(Duration::from_secs_f64(1000.0 / millihertz as f64))
+}
+
+/// The largest scale at which the window still fits on the primary monitor
+fn max_scale(window: &winit::window::Window) -> Option<u32> {
+    let monitor = window.primary_monitor()?;
+    let size: winit::dpi::LogicalSize<u32> = monitor.size().to_logical(monitor.scale_factor());
+    let scale = (size.width / rboy::SCREEN_W as u32).min(size.height / rboy::SCREEN_H as u32);
+    Some(scale.max(1))
+}
+
+fn toggle_fullscreen(
+    window: &winit::window::Window,
+    windowed_size: &mut Option<winit::dpi::PhysicalSize<u32>>,
+) {
+    match window.fullscreen() {
+        Some(_) => {
+            window.set_fullscreen(None);
+            if let Some(size) = windowed_size.take() {
+                let _ = window.request_inner_size(size);
+            }
+        }
+        None => {
+            *windowed_size = Some(window.inner_size());
+            window.set_fullscreen(Some(winit::window::Fullscreen::Borderless(None)));
+        }
+    }
+}
+
 fn set_window_size(window: &winit::window::Window, scale: u32) {
     let _ = window.request_inner_size(winit::dpi::LogicalSize::<u32>::from((
         rboy::SCREEN_W as u32 * scale,
@@ -671,9 +1458,17 @@ fn set_window_size(window: &winit::window::Window, scale: u32) {
 struct CpalPlayer {
     buffer: Arc<Mutex<Vec<(f32, f32)>>>,
     sample_rate: u32,
+    buffer_target: usize,
+    capped: bool,
 }
 
 impl CpalPlayer {
+    /// Without the cap every sample is kept, at the cost of a growing delay when the emulator
+    /// runs too fast
+    fn set_capped(&mut self, capped: bool) {
+        self.capped = capped;
+    }
+
     fn get() -> Option<(CpalPlayer, cpal::Stream)> {
         let device = match cpal::default_host().default_output_device() {
             Some(e) => e,
@@ -712,7 +1507,7 @@ impl CpalPlayer {
         let sample_format = selected_config.sample_format();
         let config: cpal::StreamConfig = selected_config.into();
 
-        let err_fn = |err| eprintln!("An error occurred on the output audio stream: {}", err);
+        let err_fn = |err| log::error!("An error occurred on the output audio stream: {}", err);
 
         let shared_buffer = Arc::new(Mutex::new(Vec::new()));
         let stream_buffer = shared_buffer.clone();
@@ -720,6 +1515,8 @@ impl CpalPlayer {
         let player = CpalPlayer {
             buffer: shared_buffer,
             sample_rate: config.sample_rate.0,
+            buffer_target: config.sample_rate.0 as usize,
+            capped: true,
         };
 
         let stream = match sample_format {
@@ -823,6 +1620,10 @@ fn cpal_thread<T: Sample + FromSample<f32>>(
         outbuffer[i * 2] = T::from_sample(in_l);
         outbuffer[i * 2 + 1] = T::from_sample(in_r);
     }
+    // Play silence on an underflow instead of what was left in the output buffer
+    for v in outbuffer[outlen * 2..].iter_mut() {
+        *v = T::EQUILIBRIUM;
+    }
 }
 
 impl rboy::AudioPlayer for CpalPlayer {
@@ -832,8 +1633,8 @@ impl rboy::AudioPlayer for CpalPlayer {
         let mut buffer = self.buffer.lock().unwrap();
 
         for (l, r) in buf_left.iter().zip(buf_right) {
-            if buffer.len() > self.sample_rate as usize {
-                // Do not fill the buffer with more than 1 second of data
+            if self.capped && buffer.len() >= self.buffer_target {
+                // Do not fill the buffer with more than the target, by default 1 second of data
                 // This speeds up the resync after the turning on and off the speed limiter
                 return;
             }
@@ -848,6 +1649,14 @@ impl rboy::AudioPlayer for CpalPlayer {
     fn underflowed(&self) -> bool {
         (*self.buffer.lock().unwrap()).len() == 0
     }
+
+    fn set_buffer_target(&mut self, frames: usize) {
+        self.buffer_target = frames;
+    }
+
+    fn clear(&mut self) {
+        self.buffer.lock().unwrap().clear();
+    }
 }
 
 struct NullAudioPlayer {}
@@ -866,11 +1675,9 @@ impl rboy::AudioPlayer for NullAudioPlayer {
     }
 }
 
-fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32 {
-    let opt_cpu = match classic_mode {
-        true => Device::new(filename, skip_checksum),
-        false => Device::new_cgb(filename, skip_checksum),
-    };
+fn run_test_mode(filename: &str, model: Model, skip_checksum: bool) -> i32 {
+    let opt_cpu = load_cartridge(filename, None, skip_checksum)
+        .and_then(|cart| Device::new_from_cartridge_with_model(cart, model));
     let mut cpu = match opt_cpu {
         Err(errmsg) => {
             warn(errmsg);
@@ -883,7 +1690,11 @@ fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32
     cpu.enable_audio(Box::new(NullAudioPlayer {}));
 
     // from masonforest, https://stackoverflow.com/a/55201400 (CC BY-SA 4.0)
-    let stdin_channel = spawn_stdin_channel();
+    // A ROM read from stdin leaves no commands to read, so it runs until it is killed
+    let (_no_commands, mut stdin_channel) = mpsc::channel();
+    if filename != "-" {
+        stdin_channel = spawn_stdin_channel();
+    }
     loop {
         match stdin_channel.try_recv() {
             Ok(stdin_byte) => match stdin_byte {
@@ -926,3 +1737,129 @@ fn print_screenshot(data: Vec<u8>) {
     }
     eprintln!();
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        cpal_thread, load_trap_functions, set_trap_host, wait_for_refresh, CpalPlayer, PluginTraps,
+        TrapHost,
+    };
+    use piccolo::{Closure, Executor, Lua, Value};
+    use rboy::device::Device;
+    use rboy::AudioPlayer;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn uncapped_player_keeps_all_samples() {
+        let mut player = CpalPlayer {
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            sample_rate: 44100,
+            buffer_target: 44100,
+            capped: true,
+        };
+        let samples = vec![0.5; 44100 * 2];
+        player.play(&samples, &samples);
+        assert_eq!(player.buffer.lock().unwrap().len(), 44100);
+
+        player.buffer.lock().unwrap().clear();
+        player.set_capped(false);
+        player.play(&samples, &samples);
+        assert_eq!(player.buffer.lock().unwrap().len(), 44100 * 2);
+    }
+
+    #[test]
+    fn buffer_target_caps_player() {
+        let mut player = CpalPlayer {
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            sample_rate: 44100,
+            buffer_target: 44100,
+            capped: true,
+        };
+        player.set_buffer_target(441);
+        player.play(&[0.5; 1000], &[0.5; 1000]);
+        assert_eq!(player.buffer.lock().unwrap().len(), 441);
+        player.play(&[0.5; 10], &[0.5; 10]);
+        assert_eq!(player.buffer.lock().unwrap().len(), 441);
+    }
+
+    #[test]
+    fn paused_player_plays_silence() {
+        let mut player = CpalPlayer {
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            sample_rate: 44100,
+            buffer_target: 44100,
+            capped: true,
+        };
+        player.play(&[0.5; 2], &[0.5; 2]);
+        let mut out = [0.7f32; 8];
+        cpal_thread(&mut out, &player.buffer);
+        assert_eq!(out, [0.5, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0, 0.0]);
+
+        player.play(&[0.5; 4], &[0.5; 4]);
+        player.clear();
+        assert!(player.underflowed());
+        cpal_thread(&mut out, &player.buffer);
+        assert_eq!(out, [0.0; 8]);
+    }
+
+    #[test]
+    fn early_swap_waits_for_refresh() {
+        let interval = Duration::from_millis(20);
+        let start = Instant::now();
+        let mut last_swap = start;
+        wait_for_refresh(&mut last_swap, interval);
+        assert!(start.elapsed() >= interval);
+        assert!(last_swap >= start + interval);
+
+        // A swap that waited for the display goes on right away
+        let mut last_swap = Instant::now() - interval;
+        let start = Instant::now();
+        wait_for_refresh(&mut last_swap, interval);
+        assert!(start.elapsed() < interval / 2);
+    }
+
+    #[test]
+    fn plugin_traps_write() {
+        let device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        let cpu = Rc::new(RefCell::new(device));
+        let traps = Rc::new(RefCell::new(PluginTraps::default()));
+        let lua = Rc::new(RefCell::new(Lua::full()));
+        let executor = lua.borrow_mut().enter(|ctx| ctx.stash(Executor::new(ctx)));
+        let host = TrapHost {
+            lua: lua.clone(),
+            executor: executor.clone(),
+            traps: traps.clone(),
+        };
+        set_trap_host(&mut cpu.borrow_mut(), host);
+        load_trap_functions(&mut lua.borrow_mut(), &cpu, &traps);
+
+        let plugin = "trap_write(0xFF7F, function(address, value) seen = value end)
+            trap_read(0xFF7F, function(address, value) return seen + 1 end)";
+        let run = |code: &str| {
+            let mut lua = lua.borrow_mut();
+            lua.enter(|ctx| {
+                let closure = Closure::load(ctx, None, code.as_bytes()).unwrap();
+                ctx.fetch(&executor).restart(ctx, closure.into(), ());
+            });
+            lua.execute::<()>(&executor)
+        };
+        run(plugin).unwrap();
+
+        // The handlers run before the access is over
+        cpu.borrow_mut().cpu.mmu.wb(0xFF7F, 0x42);
+        let seen = lua.borrow_mut().enter(|ctx| match ctx.get_global("seen") {
+            Value::Integer(v) => Some(v),
+            _ => None,
+        });
+        assert_eq!(seen, Some(0x42));
+        assert_eq!(cpu.borrow_mut().cpu.mmu.rb(0xFF7F), 0x43);
+
+        // A handler can not use the device it was called from
+        run("trap_write(0xFF7E, function() trap_read(0xFF7D, function() end) end)").unwrap();
+        cpu.borrow_mut().cpu.mmu.wb(0xFF7E, 0);
+        assert!(!traps.borrow().reads.contains_key(&0xFF7D));
+    }
+}
@@ -1,22 +1,33 @@
 use crate::mbc::MBC;
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
+#[derive(Clone)]
 pub struct MBC0 {
-    rom: Vec<u8>,
+    rom: Arc<[u8]>,
 }
 
 impl MBC0 {
     pub fn new(data: Vec<u8>) -> StrResult<MBC0> {
-        Ok(MBC0 { rom: data })
+        Ok(MBC0 { rom: data.into() })
     }
 }
 
 impl MBC for MBC0 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn fork(&self) -> Box<dyn MBC> {
+        Box::new(self.clone())
+    }
     fn readrom(&self, a: u16) -> u8 {
         self.rom[a as usize]
     }
     fn readram(&self, _a: u16) -> u8 {
-        0
+        // Nothing drives the bus without RAM
+        0xFF
     }
     fn writerom(&mut self, _a: u16, _v: u8) {
         ()
@@ -37,4 +48,13 @@ impl MBC for MBC0 {
     fn check_and_reset_ram_updated(&mut self) -> bool {
         false
     }
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+    fn ram_bank(&self) -> u8 {
+        0
+    }
+    fn ram_enabled(&self) -> bool {
+        false
+    }
 }
@@ -1,10 +1,16 @@
-use crate::mbc::{ram_banks, rom_banks, MBC};
+use crate::mbc::{ram_banks, rom_banks, MbcState, MBC};
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
+#[derive(Clone)]
 pub struct MBC1 {
-    rom: Vec<u8>,
+    rom: Arc<[u8]>,
     ram: Vec<u8>,
     ram_on: bool,
+    ram_override: Option<bool>,
     ram_updated: bool,
     banking_mode: u8,
     rombank: usize,
@@ -25,9 +31,10 @@ impl MBC1 {
         let ramsize = rambanks * 0x2000;
 
         let res = MBC1 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
+            rom: data.into(),
+            ram: core::iter::repeat(0u8).take(ramsize).collect(),
             ram_on: false,
+            ram_override: None,
             banking_mode: 0,
             rombank: 1,
             rambank: 0,
@@ -42,6 +49,18 @@ impl MBC1 {
 }
 
 impl MBC for MBC1 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn fork(&self) -> Box<dyn MBC> {
+        Box::new(self.clone())
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
     fn readrom(&self, a: u16) -> u8 {
         let bank = if a < 0x4000 {
             if self.banking_mode == 0 {
@@ -56,7 +75,7 @@ impl MBC for MBC1 {
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
-        if !self.ram_on {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return 0xFF;
         }
         let rambank = if self.banking_mode == 1 {
@@ -64,7 +83,8 @@ impl MBC for MBC1 {
         } else {
             0
         };
-        self.ram[(rambank * 0x2000) | ((a & 0x1FFF) as usize)]
+        let address = (rambank * 0x2000) | ((a & 0x1FFF) as usize);
+        *self.ram.get(address).unwrap_or(&0xFF)
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
@@ -85,7 +105,7 @@ impl MBC for MBC1 {
                     self.rombank = self.rombank & 0x1F | (upper_bits << 5)
                 }
                 if self.rambanks > 1 {
-                    self.rambank = (v as usize) & 0x03;
+                    self.rambank = ((v as usize) & 0x03) % self.rambanks;
                 }
             }
             0x6000..=0x7FFF => {
@@ -96,7 +116,7 @@ impl MBC for MBC1 {
     }
 
     fn writeram(&mut self, a: u16, v: u8) {
-        if !self.ram_on {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return;
         }
         let rambank = if self.banking_mode == 1 {
@@ -111,6 +131,27 @@ impl MBC for MBC1 {
         }
     }
 
+    fn state(&self) -> MbcState {
+        MbcState {
+            ram: self.ram.clone(),
+            ram_on: self.ram_on,
+            rombank: self.rombank,
+            rambank: self.rambank,
+            banking_mode: self.banking_mode,
+            ..MbcState::default()
+        }
+    }
+    fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        state.check(&self.ram, self.rombanks)?;
+        self.ram.copy_from_slice(&state.ram);
+        self.ram_on = state.ram_on;
+        self.rombank = state.rombank;
+        self.rambank = state.rambank;
+        self.banking_mode = state.banking_mode & 0x01;
+        self.ram_updated = true;
+        Ok(())
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
@@ -134,4 +175,31 @@ impl MBC for MBC1 {
         self.ram_updated = false;
         result
     }
+
+    fn rom_bank(&self) -> u16 {
+        self.rombank as u16
+    }
+
+    fn ram_bank(&self) -> u8 {
+        if self.banking_mode == 1 {
+            self.rambank as u8
+        } else {
+            0
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_on
+    }
+
+    fn set_ram_override(&mut self, enabled: Option<bool>) {
+        self.ram_override = enabled;
+    }
+
+    fn set_ram_banks(&mut self, banks: usize) -> StrResult<()> {
+        self.ram = vec![0; banks * 0x2000];
+        self.rambanks = banks;
+        self.rambank = 0;
+        Ok(())
+    }
 }
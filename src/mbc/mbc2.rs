@@ -1,10 +1,16 @@
-use crate::mbc::{rom_banks, MBC};
+use crate::mbc::{rom_banks, MbcState, MBC};
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
+#[derive(Clone)]
 pub struct MBC2 {
-    rom: Vec<u8>,
+    rom: Arc<[u8]>,
     ram: Vec<u8>,
     ram_on: bool,
+    ram_override: Option<bool>,
     ram_updated: bool,
     rombank: usize,
     has_battery: bool,
@@ -20,9 +26,10 @@ impl MBC2 {
         let rombanks = rom_banks(data[0x148]);
 
         let res = MBC2 {
-            rom: data,
+            rom: data.into(),
             ram: vec![0; 512],
             ram_on: false,
+            ram_override: None,
             ram_updated: false,
             rombank: 1,
             has_battery: has_battery,
@@ -34,13 +41,25 @@ impl MBC2 {
 }
 
 impl MBC for MBC2 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn fork(&self) -> Box<dyn MBC> {
+        Box::new(self.clone())
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
     fn readrom(&self, a: u16) -> u8 {
         let bank = if a < 0x4000 { 0 } else { self.rombank };
         let idx = bank * 0x4000 | ((a as usize) & 0x3FFF);
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
-        if !self.ram_on {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return 0xFF;
         }
         self.ram[(a as usize) & 0x1FF] | 0xF0
@@ -63,13 +82,30 @@ impl MBC for MBC2 {
     }
 
     fn writeram(&mut self, a: u16, v: u8) {
-        if !self.ram_on {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return;
         }
         self.ram[(a as usize) & 0x1FF] = v | 0xF0;
         self.ram_updated = true;
     }
 
+    fn state(&self) -> MbcState {
+        MbcState {
+            ram: self.ram.clone(),
+            ram_on: self.ram_on,
+            rombank: self.rombank,
+            ..MbcState::default()
+        }
+    }
+    fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        state.check(&self.ram, self.rombanks)?;
+        self.ram.copy_from_slice(&state.ram);
+        self.ram_on = state.ram_on;
+        self.rombank = state.rombank;
+        self.ram_updated = true;
+        Ok(())
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
@@ -93,4 +129,20 @@ impl MBC for MBC2 {
         self.ram_updated = false;
         result
     }
+
+    fn rom_bank(&self) -> u16 {
+        self.rombank as u16
+    }
+
+    fn ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_on
+    }
+
+    fn set_ram_override(&mut self, enabled: Option<bool>) {
+        self.ram_override = enabled;
+    }
 }
@@ -1,23 +1,32 @@
-use crate::mbc::{ram_banks, MBC};
+use crate::clock::{self, Clock};
+use crate::mbc::{ram_banks, rom_banks, MbcState, MBC};
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
-use std::convert::TryInto;
-use std::io::prelude::*;
-use std::time;
+use core::convert::TryInto;
 
 pub struct MBC3 {
-    rom: Vec<u8>,
+    rom: Arc<[u8]>,
     ram: Vec<u8>,
     rombank: usize,
     rambank: usize,
+    rombanks: usize,
     rambanks: usize,
     selectrtc: bool,
     ram_on: bool,
+    ram_override: Option<bool>,
     ram_updated: bool,
     has_battery: bool,
     rtc_ram: [u8; 5],
     rtc_ram_latch: [u8; 5],
     rtc_zero: Option<u64>,
+    // When the loaded save was written
+    saved_at: Option<u64>,
+    rtc_catch_up: bool,
+    clock: Box<dyn Clock>,
 }
 
 impl MBC3 {
@@ -32,24 +41,30 @@ impl MBC3 {
             _ => 0,
         };
         let ramsize = rambanks * 0x2000;
+        let rombanks = rom_banks(data[0x148]);
         let rtc = match subtype {
             0x0F | 0x10 => Some(0),
             _ => None,
         };
 
         let res = MBC3 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
+            rom: data.into(),
+            ram: core::iter::repeat(0u8).take(ramsize).collect(),
             rombank: 1,
             rambank: 0,
+            rombanks,
             rambanks: rambanks,
             selectrtc: false,
             ram_on: false,
+            ram_override: None,
             ram_updated: false,
             has_battery: has_battery,
             rtc_ram: [0u8; 5],
             rtc_ram_latch: [0u8; 5],
             rtc_zero: rtc,
+            saved_at: None,
+            rtc_catch_up: true,
+            clock: clock::default_clock(),
         };
 
         Ok(res)
@@ -67,7 +82,7 @@ impl MBC3 {
         }
 
         let tzero = match self.rtc_zero {
-            Some(t) => time::UNIX_EPOCH + time::Duration::from_secs(t),
+            Some(t) => t,
             None => return,
         };
 
@@ -76,10 +91,7 @@ impl MBC3 {
             return;
         }
 
-        let difftime = match time::SystemTime::now().duration_since(tzero) {
-            Ok(n) => n.as_secs(),
-            _ => 0,
-        };
+        let difftime = self.clock.now().as_secs().saturating_sub(tzero);
         self.rtc_ram[0] = (difftime % 60) as u8;
         self.rtc_ram[1] = ((difftime / 60) % 60) as u8;
         self.rtc_ram[2] = ((difftime / 3600) % 24) as u8;
@@ -96,10 +108,7 @@ impl MBC3 {
         if self.rtc_zero.is_none() {
             return None;
         }
-        let mut difftime = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(t) => t.as_secs(),
-            Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
-        };
+        let mut difftime = self.clock.now().as_secs();
         difftime -= self.rtc_ram[0] as u64;
         difftime -= (self.rtc_ram[1] as u64) * 60;
         difftime -= (self.rtc_ram[2] as u64) * 3600;
@@ -108,12 +117,41 @@ impl MBC3 {
         Some(difftime)
     }
 
+    // Moves the start of the RTC by the time since the save was written, either to skip that
+    // time or to count it again
+    fn shift_rtc_zero(&mut self, skip: bool) {
+        if let (Some(zero), Some(saved_at)) = (self.rtc_zero, self.saved_at) {
+            let delta = self.clock.now().as_secs().saturating_sub(saved_at);
+            self.rtc_zero = Some(match skip {
+                true => zero + delta,
+                false => zero.saturating_sub(delta),
+            });
+        }
+    }
+
     fn calc_rtc_zero(&mut self) {
         self.rtc_zero = self.compute_difftime();
     }
 }
 
 impl MBC for MBC3 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn fork(&self) -> Box<dyn MBC> {
+        Box::new(MBC3 {
+            rom: self.rom.clone(),
+            ram: self.ram.clone(),
+            clock: clock::default_clock(),
+            ..*self
+        })
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
     fn readrom(&self, a: u16) -> u8 {
         let idx = if a < 0x4000 {
             a as usize
@@ -123,7 +161,7 @@ impl MBC for MBC3 {
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
-        if !self.ram_on {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return 0xFF;
         }
         if !self.selectrtc && self.rambank < self.rambanks {
@@ -141,7 +179,7 @@ impl MBC for MBC3 {
                 self.rombank = match v & 0x7F {
                     0 => 1,
                     n => n as usize,
-                }
+                } % self.rombanks
             }
             0x4000..=0x5FFF => {
                 self.selectrtc = v & 0x8 == 0x8;
@@ -152,7 +190,7 @@ impl MBC for MBC3 {
         }
     }
     fn writeram(&mut self, a: u16, v: u8) {
-        if !self.ram_on {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return;
         }
         if !self.selectrtc && self.rambank < self.rambanks {
@@ -172,21 +210,58 @@ impl MBC for MBC3 {
         }
     }
 
+    fn state(&self) -> MbcState {
+        MbcState {
+            ram: self.ram.clone(),
+            ram_on: self.ram_on,
+            rombank: self.rombank,
+            rambank: self.rambank,
+            selectrtc: self.selectrtc,
+            rtc_ram: self.rtc_ram,
+            rtc_ram_latch: self.rtc_ram_latch,
+            rtc_zero: self.rtc_zero,
+            ..MbcState::default()
+        }
+    }
+    fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        state.check(&self.ram, self.rombanks)?;
+        self.ram.copy_from_slice(&state.ram);
+        self.ram_on = state.ram_on;
+        self.rombank = state.rombank;
+        self.rambank = state.rambank & 0x07;
+        self.selectrtc = state.selectrtc;
+        self.rtc_ram = state.rtc_ram;
+        self.rtc_ram_latch = state.rtc_ram_latch;
+        self.rtc_zero = state.rtc_zero;
+        self.ram_updated = true;
+        Ok(())
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
 
     fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
-        if ramdata.len() != 8 + self.ram.len() {
-            return Err("Loaded ram is too small");
-        }
+        // Older saves do not end with the time they were written
+        let saved_at = match ramdata.len() {
+            n if n == 8 + self.ram.len() => None,
+            n if n == 16 + self.ram.len() => {
+                let bytes = &ramdata[ramdata.len() - 8..];
+                Some(u64::from_be_bytes(bytes.try_into().unwrap()))
+            }
+            _ => return Err("Loaded ram is too small"),
+        };
 
         let (int_bytes, rest) = ramdata.split_at(8);
         let rtc = u64::from_be_bytes(int_bytes.try_into().unwrap());
         if self.rtc_zero.is_some() {
             self.rtc_zero = Some(rtc);
         }
-        self.ram = rest.to_vec();
+        self.ram = rest[..self.ram.len()].to_vec();
+        self.saved_at = saved_at;
+        if !self.rtc_catch_up {
+            self.shift_rtc_zero(true);
+        }
         Ok(())
     }
 
@@ -196,17 +271,10 @@ impl MBC for MBC3 {
             None => 0,
         };
 
-        let mut file = vec![];
-
-        let mut ok = true;
-        if ok {
-            let rtc_bytes = rtc.to_be_bytes();
-            ok = file.write_all(&rtc_bytes).is_ok();
-        };
-        if ok {
-            let _ = file.write_all(&*self.ram);
-        };
-
+        let mut file = Vec::with_capacity(16 + self.ram.len());
+        file.extend_from_slice(&rtc.to_be_bytes());
+        file.extend_from_slice(&self.ram);
+        file.extend_from_slice(&self.clock.now().as_secs().to_be_bytes());
         file
     }
 
@@ -215,4 +283,123 @@ impl MBC for MBC3 {
         self.ram_updated = false;
         result
     }
+
+    fn rom_bank(&self) -> u16 {
+        self.rombank as u16
+    }
+
+    fn ram_bank(&self) -> u8 {
+        // Values 0x08-0x0C select an RTC register instead of a RAM bank
+        match self.selectrtc {
+            true => self.rambank as u8 | 0x08,
+            false => self.rambank as u8,
+        }
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_on
+    }
+
+    fn set_ram_override(&mut self, enabled: Option<bool>) {
+        self.ram_override = enabled;
+    }
+
+    fn set_ram_banks(&mut self, banks: usize) -> StrResult<()> {
+        self.ram = vec![0; banks * 0x2000];
+        self.rambanks = banks;
+        self.rambank = 0;
+        Ok(())
+    }
+
+    fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    fn set_rtc_catch_up(&mut self, enabled: bool) {
+        if enabled != self.rtc_catch_up {
+            self.rtc_catch_up = enabled;
+            self.shift_rtc_zero(!enabled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MBC3;
+    use crate::clock::Clock;
+    use crate::mbc::MBC;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            Duration::from_secs(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    fn read_rtc(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.writerom(0x4000, 0x08 + register);
+        mbc.readram(0xA000)
+    }
+
+    #[test]
+    fn rtc_minute_rollover() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x0F;
+        let mut mbc = MBC3::new(rom).unwrap();
+        let seconds = Arc::new(AtomicU64::new(1_000_000));
+        mbc.set_clock(Box::new(FakeClock(seconds.clone())));
+
+        mbc.writerom(0x0000, 0x0A);
+        for register in 0..5 {
+            mbc.writerom(0x4000, 0x08 + register);
+            mbc.writeram(0xA000, 0);
+        }
+
+        seconds.fetch_add(61, Ordering::SeqCst);
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0), 1);
+        assert_eq!(read_rtc(&mut mbc, 1), 1);
+        assert_eq!(read_rtc(&mut mbc, 2), 0);
+    }
+
+    #[test]
+    fn rtc_counts_time_between_sessions() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x10;
+        let seconds = Arc::new(AtomicU64::new(1_000_000));
+        let new_mbc = |catch_up: bool| {
+            let mut mbc = MBC3::new(rom.clone()).unwrap();
+            mbc.set_clock(Box::new(FakeClock(seconds.clone())));
+            mbc.set_rtc_catch_up(catch_up);
+            mbc.writerom(0x0000, 0x0A);
+            mbc
+        };
+
+        let mut mbc = new_mbc(true);
+        for register in 0..5 {
+            mbc.writerom(0x4000, 0x08 + register);
+            mbc.writeram(0xA000, 0);
+        }
+        let save = mbc.dumpram();
+
+        // Two hours pass while the emulator is closed
+        seconds.fetch_add(2 * 3600, Ordering::SeqCst);
+        let mut mbc = new_mbc(true);
+        mbc.loadram(&save).unwrap();
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 2), 2);
+
+        let mut mbc = new_mbc(false);
+        mbc.loadram(&save).unwrap();
+        mbc.writerom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 2), 0);
+
+        // Saves without a timestamp still load
+        let mut mbc = new_mbc(true);
+        mbc.loadram(&save[..save.len() - 8]).unwrap();
+    }
 }
@@ -1,12 +1,18 @@
-use crate::mbc::{ram_banks, rom_banks, MBC};
+use crate::mbc::{ram_banks, rom_banks, MbcState, MBC};
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
+#[derive(Clone)]
 pub struct MBC5 {
-    rom: Vec<u8>,
+    rom: Arc<[u8]>,
     ram: Vec<u8>,
     rombank: usize,
     rambank: usize,
     ram_on: bool,
+    ram_override: Option<bool>,
     ram_updated: bool,
     has_battery: bool,
     rombanks: usize,
@@ -28,12 +34,13 @@ impl MBC5 {
         let rombanks = rom_banks(data[0x148]);
 
         let res = MBC5 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
+            rom: data.into(),
+            ram: core::iter::repeat(0u8).take(ramsize).collect(),
             rombank: 1,
             rambank: 0,
             ram_updated: false,
             ram_on: false,
+            ram_override: None,
             has_battery: has_battery,
             rombanks: rombanks,
             rambanks: rambanks,
@@ -44,6 +51,18 @@ impl MBC5 {
 }
 
 impl MBC for MBC5 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn fork(&self) -> Box<dyn MBC> {
+        Box::new(self.clone())
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
     fn readrom(&self, a: u16) -> u8 {
         let idx = if a < 0x4000 {
             a as usize
@@ -53,10 +72,11 @@ impl MBC for MBC5 {
         *self.rom.get(idx).unwrap_or(&0)
     }
     fn readram(&self, a: u16) -> u8 {
-        if !self.ram_on {
-            return 0;
+        if !self.ram_override.unwrap_or(self.ram_on) {
+            return 0xFF;
         }
-        self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)]
+        let address = self.rambank * 0x2000 | ((a as usize) & 0x1FFF);
+        *self.ram.get(address).unwrap_or(&0xFF)
     }
     fn writerom(&mut self, a: u16, v: u8) {
         match a {
@@ -68,17 +88,43 @@ impl MBC for MBC5 {
                 self.rombank =
                     ((self.rombank & 0x0FF) | (((v & 0x1) as usize) << 8)) % self.rombanks
             }
-            0x4000..=0x5FFF => self.rambank = ((v & 0x0F) as usize) % self.rambanks,
+            0x4000..=0x5FFF => {
+                if self.rambanks > 0 {
+                    self.rambank = ((v & 0x0F) as usize) % self.rambanks
+                }
+            }
             0x6000..=0x7FFF => { /* ? */ }
             _ => panic!("Could not write to {:04X} (MBC5)", a),
         }
     }
     fn writeram(&mut self, a: u16, v: u8) {
-        if self.ram_on == false {
+        if !self.ram_override.unwrap_or(self.ram_on) {
             return;
         }
-        self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)] = v;
+        let address = self.rambank * 0x2000 | ((a as usize) & 0x1FFF);
+        if let Some(byte) = self.ram.get_mut(address) {
+            *byte = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn state(&self) -> MbcState {
+        MbcState {
+            ram: self.ram.clone(),
+            ram_on: self.ram_on,
+            rombank: self.rombank,
+            rambank: self.rambank,
+            ..MbcState::default()
+        }
+    }
+    fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        state.check(&self.ram, self.rombanks)?;
+        self.ram.copy_from_slice(&state.ram);
+        self.ram_on = state.ram_on;
+        self.rombank = state.rombank;
+        self.rambank = state.rambank;
         self.ram_updated = true;
+        Ok(())
     }
 
     fn is_battery_backed(&self) -> bool {
@@ -104,4 +150,27 @@ impl MBC for MBC5 {
         self.ram_updated = false;
         result
     }
+
+    fn rom_bank(&self) -> u16 {
+        self.rombank as u16
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.rambank as u8
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_on
+    }
+
+    fn set_ram_override(&mut self, enabled: Option<bool>) {
+        self.ram_override = enabled;
+    }
+
+    fn set_ram_banks(&mut self, banks: usize) -> StrResult<()> {
+        self.ram = vec![0; banks * 0x2000];
+        self.rambanks = banks;
+        self.rambank = 0;
+        Ok(())
+    }
 }
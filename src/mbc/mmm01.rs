@@ -0,0 +1,226 @@
+use crate::mbc::{ram_banks, rom_banks, MbcState, MBC};
+use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The mapper of multi game compilations. It boots into a menu in the last 32KiB of the ROM,
+/// which writes the ROM and RAM window of the chosen game and then maps it. From then on the
+/// game sees an MBC1 limited to its window. The MBC1 mode and multiplex bits are ignored.
+#[derive(Clone)]
+pub struct MMM01 {
+    rom: Arc<[u8]>,
+    ram: Vec<u8>,
+    ram_on: bool,
+    ram_override: Option<bool>,
+    ram_updated: bool,
+    // Where the menu header is
+    header: usize,
+    mapped: bool,
+    rombank: usize,
+    rambank: usize,
+    // Bits 1-4 of the ROM bank and 0-1 of the RAM bank that the mapped game can not change
+    rom_mask: u8,
+    ram_mask: u8,
+    has_battery: bool,
+    rombanks: usize,
+    rambanks: usize,
+}
+
+impl MMM01 {
+    pub fn new(data: Vec<u8>, header: usize) -> StrResult<MMM01> {
+        let (has_battery, rambanks) = match data[header + 0x147] {
+            0x0C => (false, ram_banks(data[header + 0x149])),
+            0x0D => (true, ram_banks(data[header + 0x149])),
+            _ => (false, 0),
+        };
+        let rombanks = rom_banks(data[header + 0x148]);
+
+        Ok(MMM01 {
+            rom: data.into(),
+            ram: vec![0; rambanks * 0x2000],
+            ram_on: false,
+            ram_override: None,
+            ram_updated: false,
+            header,
+            mapped: false,
+            rombank: 0,
+            rambank: 0,
+            rom_mask: 0,
+            ram_mask: 0,
+            has_battery,
+            rombanks,
+            rambanks,
+        })
+    }
+
+    // The ROM bank bits written at 0x2000-0x3FFF
+    fn rom_bits(&self) -> usize {
+        match self.mapped {
+            true => 0x1F & !((self.rom_mask as usize) << 1),
+            false => 0x7F,
+        }
+    }
+
+    fn ram_bits(&self) -> usize {
+        match self.mapped {
+            true => 0x03 & !(self.ram_mask as usize),
+            false => 0x0F,
+        }
+    }
+
+    fn ram_address(&self, a: u16) -> usize {
+        ((self.rambank % self.rambanks.max(1)) * 0x2000) | ((a & 0x1FFF) as usize)
+    }
+}
+
+impl MBC for MMM01 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    fn fork(&self) -> Box<dyn MBC> {
+        Box::new(self.clone())
+    }
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    fn readrom(&self, a: u16) -> u8 {
+        let bank = match (self.mapped, a < 0x4000) {
+            // The menu
+            (false, true) => self.rombanks - 2,
+            (false, false) => self.rombanks - 1,
+            // The first bank of the game
+            (true, true) => self.rombank & !self.rom_bits(),
+            (true, false) => self.rombank,
+        };
+        let idx = (bank * 0x4000) | ((a as usize) & 0x3FFF);
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+    fn readram(&self, a: u16) -> u8 {
+        if !self.ram_override.unwrap_or(self.ram_on) {
+            return 0xFF;
+        }
+        *self.ram.get(self.ram_address(a)).unwrap_or(&0xFF)
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1FFF => {
+                self.ram_on = v & 0x0F == 0x0A;
+                if !self.mapped {
+                    self.ram_mask = (v >> 4) & 0x03;
+                    self.mapped = v & 0x40 == 0x40;
+                }
+            }
+            0x2000..=0x3FFF => {
+                let bits = self.rom_bits();
+                let low = match v as usize & bits {
+                    0 if self.mapped => 1,
+                    n => n,
+                };
+                self.rombank = ((self.rombank & !bits) | low) % self.rombanks;
+            }
+            0x4000..=0x5FFF => {
+                let bits = self.ram_bits();
+                self.rambank = (self.rambank & !bits) | (v as usize & bits);
+                if !self.mapped {
+                    let high = (v as usize & 0x30) << 3;
+                    self.rombank = ((self.rombank & 0x7F) | high) % self.rombanks;
+                }
+            }
+            0x6000..=0x7FFF => {
+                if !self.mapped {
+                    self.rom_mask = (v >> 2) & 0x0F;
+                }
+            }
+            _ => panic!("Could not write to {:04X} (MMM01)", a),
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if !self.ram_override.unwrap_or(self.ram_on) {
+            return;
+        }
+        let address = self.ram_address(a);
+        if address < self.ram.len() {
+            self.ram[address] = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn state(&self) -> MbcState {
+        MbcState {
+            ram: self.ram.clone(),
+            ram_on: self.ram_on,
+            rombank: self.rombank,
+            rambank: self.rambank,
+            mapped: self.mapped,
+            rom_mask: self.rom_mask,
+            ram_mask: self.ram_mask,
+            ..MbcState::default()
+        }
+    }
+    fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        state.check(&self.ram, self.rombanks)?;
+        self.ram.copy_from_slice(&state.ram);
+        self.ram_on = state.ram_on;
+        self.rombank = state.rombank;
+        self.rambank = state.rambank & 0x0F;
+        self.mapped = state.mapped;
+        self.rom_mask = state.rom_mask & 0x0F;
+        self.ram_mask = state.ram_mask & 0x03;
+        self.ram_updated = true;
+        Ok(())
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        self.has_battery
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+        if ramdata.len() != self.ram.len() {
+            return Err("Loaded RAM has incorrect length");
+        }
+
+        self.ram = ramdata.to_vec();
+
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+
+    fn rom_bank(&self) -> u16 {
+        match self.mapped {
+            true => self.rombank as u16,
+            false => (self.rombanks - 1) as u16,
+        }
+    }
+
+    fn ram_bank(&self) -> u8 {
+        (self.rambank % self.rambanks.max(1)) as u8
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_on
+    }
+
+    fn header_start(&self) -> usize {
+        self.header
+    }
+
+    fn set_ram_override(&mut self, enabled: Option<bool>) {
+        self.ram_override = enabled;
+    }
+}
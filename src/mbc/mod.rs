@@ -1,7 +1,15 @@
+use crate::clock::Clock;
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "file-io")]
 use std::fs::{self, File};
+#[cfg(feature = "file-io")]
 use std::io;
+#[cfg(feature = "file-io")]
 use std::io::prelude::*;
+#[cfg(feature = "file-io")]
 use std::path;
 
 mod mbc0;
@@ -9,17 +17,98 @@ mod mbc1;
 mod mbc2;
 mod mbc3;
 mod mbc5;
+mod mmm01;
+
+/// The RAM and the registers of a memory bank controller, for save states. Mappers without a
+/// register ignore it.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct MbcState {
+    pub ram: Vec<u8>,
+    pub ram_on: bool,
+    pub rombank: usize,
+    pub rambank: usize,
+    pub banking_mode: u8,
+    pub selectrtc: bool,
+    pub rtc_ram: [u8; 5],
+    pub rtc_ram_latch: [u8; 5],
+    pub rtc_zero: Option<u64>,
+    /// MMM01: whether the menu mapped a game, and the bank bits it locked
+    pub mapped: bool,
+    pub rom_mask: u8,
+    pub ram_mask: u8,
+}
+
+impl MbcState {
+    fn check(&self, ram: &[u8], rombanks: usize) -> StrResult<()> {
+        if self.ram.len() != ram.len() {
+            return Err("Save state RAM has incorrect length");
+        }
+        if self.rombank >= rombanks {
+            return Err("Invalid ROM bank in save state");
+        }
+        Ok(())
+    }
+}
 
 pub trait MBC: Send {
+    fn rom(&self) -> &[u8];
+    /// An independent copy that shares the ROM. A file backed cartridge is not saved by the copy.
+    fn fork(&self) -> Box<dyn MBC>;
     fn readrom(&self, a: u16) -> u8;
     fn readram(&self, a: u16) -> u8;
     fn writerom(&mut self, a: u16, v: u8);
     fn writeram(&mut self, a: u16, v: u8);
     fn check_and_reset_ram_updated(&mut self) -> bool;
 
+    /// The bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> u16;
+    /// The bank mapped at 0xA000-0xBFFF
+    fn ram_bank(&self) -> u8;
+    fn ram_enabled(&self) -> bool;
+
+    /// Where the cartridge header is in the ROM, see `header_start`
+    fn header_start(&self) -> usize {
+        0
+    }
+
+    /// Forces the RAM enabled or disabled regardless of the enable register, until `None`
+    fn set_ram_override(&mut self, _enabled: Option<bool>) {}
+
+    /// Only used by cartridges with a real time clock
+    fn set_clock(&mut self, _clock: Box<dyn Clock>) {}
+
+    /// Whether the real time clock counts the time that passed since the save was written
+    fn set_rtc_catch_up(&mut self, _enabled: bool) {}
+
+    /// Replaces the RAM size given in the header. The RAM is cleared.
+    fn set_ram_banks(&mut self, _banks: usize) -> StrResult<()> {
+        Err("Cartridge RAM size can not be changed")
+    }
+
+    /// The cartridge RAM as the game sees it, without the clock data that saves may contain
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn state(&self) -> MbcState {
+        MbcState::default()
+    }
+    fn load_state(&mut self, _state: &MbcState) -> StrResult<()> {
+        Ok(())
+    }
+
     fn is_battery_backed(&self) -> bool;
     fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()>;
     fn dumpram(&self) -> Vec<u8>;
+    /// Writes battery backed RAM to wherever it is kept outside of the emulator
+    fn flush(&mut self) -> StrResult<()> {
+        Ok(())
+    }
 
     fn romname(&self) -> String {
         const TITLE_START: u16 = 0x134;
@@ -43,46 +132,82 @@ pub trait MBC: Send {
     }
 }
 
-pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> StrResult<Box<dyn MBC + 'static>> {
+// The largest ROM any cartridge header can declare, 512 banks
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
+/// With `skip_checksum` a ROM that is shorter than its header claims is padded instead of
+/// rejected, like an unconnected data bus reading 0xFF
+pub fn get_mbc(mut data: Vec<u8>, skip_checksum: bool) -> StrResult<Box<dyn MBC + 'static>> {
     if data.len() < 0x150 {
         return Err("Rom size to small");
     }
+    if data.len() > MAX_ROM_SIZE {
+        return Err("Rom size too large");
+    }
+    let header = header_start(&data);
+    let romsize = match rom_banks(data[header + 0x148]) {
+        0 => return Err("Unsupported ROM size"),
+        n => n * 0x4000,
+    };
+    if data.len() < romsize {
+        if !skip_checksum {
+            return Err("Rom is smaller than the size given in its header");
+        }
+        data.resize(romsize, 0xFF);
+    }
+    // Anything past the declared size can not be reached with the bank registers
+    data.truncate(romsize);
     if !skip_checksum {
-        check_checksum(&data)?;
+        check_checksum(&data[header..])?;
     }
-    match data[0x147] {
+    if header != 0 && header + 0x8000 != data.len() {
+        return Err("MMM01 menu is not at the end of the ROM");
+    }
+    match data[header + 0x147] {
         0x00 => mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x01..=0x03 => mbc1::MBC1::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x05..=0x06 => mbc2::MBC2::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        0x0B..=0x0D => mmm01::MMM01::new(data, header).map(|v| Box::new(v) as Box<dyn MBC>),
         0x0F..=0x13 => mbc3::MBC3::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         0x19..=0x1E => mbc5::MBC5::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
         _ => Err("Unsupported MBC type"),
     }
 }
 
+#[cfg(feature = "file-io")]
 pub struct FileBackedMBC {
     rampath: path::PathBuf,
     mbc: Box<dyn MBC>,
 }
 
+#[cfg(feature = "file-io")]
 impl FileBackedMBC {
     pub fn new(rompath: path::PathBuf, skip_checksum: bool) -> StrResult<FileBackedMBC> {
-        let mut data = vec![];
-        File::open(&rompath)
-            .and_then(|mut f| f.read_to_end(&mut data))
-            .map_err(|_| "Could not read ROM")?;
-        let mut mbc = get_mbc(data, skip_checksum)?;
-
         let rampath = rompath.with_extension("gbsave");
+        FileBackedMBC::with_save(read_rom(&rompath)?, rampath, skip_checksum)
+    }
 
-        if mbc.is_battery_backed() {
-            match fs::File::open(&rampath) {
+    /// Loads and writes battery backed RAM at `rampath`, whatever the ROM is called
+    pub fn with_save(
+        data: Vec<u8>,
+        rampath: path::PathBuf,
+        skip_checksum: bool,
+    ) -> StrResult<FileBackedMBC> {
+        let mbc = get_mbc(data, skip_checksum)?;
+        let mut result = FileBackedMBC { rampath, mbc };
+        result.load_save()?;
+        Ok(result)
+    }
+
+    fn load_save(&mut self) -> StrResult<()> {
+        if self.mbc.is_battery_backed() {
+            match fs::File::open(&self.rampath) {
                 Ok(mut file) => {
                     let mut ramdata: Vec<u8> = vec![];
                     match file.read_to_end(&mut ramdata) {
                         Err(..) => return Err("Error while reading existing save file"),
                         Ok(..) => {
-                            mbc.loadram(&ramdata)?;
+                            self.mbc.loadram(&ramdata)?;
                         }
                     }
                 }
@@ -90,13 +215,37 @@ impl FileBackedMBC {
                 Err(_) => return Err("Error loading existing save file"),
             }
         }
-
-        Ok(FileBackedMBC { rampath, mbc })
+        Ok(())
     }
 }
 
 // Implement MBC for FileBackedMBC such that the MMU can use this transparently
+#[cfg(feature = "file-io")]
 impl MBC for FileBackedMBC {
+    fn rom(&self) -> &[u8] {
+        self.mbc.rom()
+    }
+
+    fn fork(&self) -> Box<dyn MBC> {
+        self.mbc.fork()
+    }
+
+    fn ram(&self) -> &[u8] {
+        self.mbc.ram()
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        self.mbc.ram_mut()
+    }
+
+    fn state(&self) -> MbcState {
+        self.mbc.state()
+    }
+
+    fn load_state(&mut self, state: &MbcState) -> StrResult<()> {
+        self.mbc.load_state(state)
+    }
+
     fn readrom(&self, a: u16) -> u8 {
         self.mbc.readrom(a)
     }
@@ -125,21 +274,97 @@ impl MBC for FileBackedMBC {
         self.mbc.dumpram()
     }
 
+    fn flush(&mut self) -> StrResult<()> {
+        if !self.mbc.is_battery_backed() {
+            return Ok(());
+        }
+        fs::write(&self.rampath, self.mbc.dumpram()).map_err(|_| "Could not write save file")
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         self.mbc.check_and_reset_ram_updated()
     }
+
+    fn rom_bank(&self) -> u16 {
+        self.mbc.rom_bank()
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.mbc.ram_bank()
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.mbc.ram_enabled()
+    }
+
+    fn header_start(&self) -> usize {
+        self.mbc.header_start()
+    }
+
+    fn set_ram_override(&mut self, enabled: Option<bool>) {
+        self.mbc.set_ram_override(enabled)
+    }
+
+    fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.mbc.set_clock(clock)
+    }
+
+    fn set_rtc_catch_up(&mut self, enabled: bool) {
+        self.mbc.set_rtc_catch_up(enabled)
+    }
+
+    fn set_ram_banks(&mut self, banks: usize) -> StrResult<()> {
+        self.mbc.set_ram_banks(banks)?;
+        // The existing save only fits once the size is right
+        self.load_save()
+    }
 }
 
+#[cfg(feature = "file-io")]
+pub fn read_rom(rompath: &path::Path) -> StrResult<Vec<u8>> {
+    let mut data = vec![];
+    // Reading one byte too many is enough to reject files that are too large
+    File::open(rompath)
+        .and_then(|f| f.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut data))
+        .map_err(|_| "Could not read ROM")?;
+    Ok(data)
+}
+
+#[cfg(feature = "file-io")]
 impl Drop for FileBackedMBC {
     fn drop(&mut self) {
-        if self.mbc.is_battery_backed() {
-            // TODO: error handling
-            let mut file = match fs::File::create(&self.rampath) {
-                Ok(f) => f,
-                Err(..) => return,
-            };
-            let _ = file.write_all(&self.mbc.dumpram());
+        // TODO: error handling
+        let _ = self.flush();
+    }
+}
+
+pub(crate) const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// Whether `data` starts with a header the boot ROM would accept
+fn valid_header(data: &[u8]) -> bool {
+    data[0x104..0x134] == NINTENDO_LOGO && data[0x14D] == header_checksum(data)
+}
+
+/// Where the cartridge header is. MMM01 compilations boot a menu from the last 32KiB of the
+/// ROM, and the header there describes the whole cartridge. It is only used when the header
+/// of the first bank is not valid, so other games can have any byte at that spot.
+fn header_start(data: &[u8]) -> usize {
+    if valid_header(data) {
+        return 0;
+    }
+    match data.len().checked_sub(0x8000) {
+        Some(menu)
+            if menu > 0
+                && (0x0B..=0x0D).contains(&data[menu + 0x147])
+                && valid_header(&data[menu..]) =>
+        {
+            menu
         }
+        _ => 0,
     }
 }
 
@@ -168,12 +393,17 @@ fn rom_banks(v: u8) -> usize {
     }
 }
 
-fn check_checksum(data: &[u8]) -> StrResult<()> {
+/// The header checksum at 0x14D over 0x134-0x14C, which the boot ROM checks
+pub(crate) fn header_checksum(data: &[u8]) -> u8 {
     let mut value: u8 = 0;
     for i in 0x134..0x14D {
         value = value.wrapping_sub(data[i]).wrapping_sub(1);
     }
-    match data[0x14D] == value {
+    value
+}
+
+fn check_checksum(data: &[u8]) -> StrResult<()> {
+    match data[0x14D] == header_checksum(data) {
         true => Ok(()),
         false => Err("Cartridge checksum is invalid"),
     }
@@ -181,6 +411,8 @@ fn check_checksum(data: &[u8]) -> StrResult<()> {
 
 #[cfg(test)]
 mod test {
+    use crate::cartridge::{Cartridge, MapperKind};
+
     #[test]
     fn checksum_zero() {
         let mut data = vec![0; 0x150];
@@ -194,4 +426,109 @@ mod test {
         data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
         super::check_checksum(&data).unwrap();
     }
+
+    #[test]
+    fn missing_ram() {
+        // ROM only, MBC1 and MBC5 without RAM
+        for &kind in &[0x00, 0x01, 0x19] {
+            let mut data = vec![0; 0x8000];
+            data[0x147] = kind;
+            let mut mbc = super::get_mbc(data, true).unwrap();
+            mbc.writerom(0x0000, 0x0A);
+            mbc.writerom(0x4000, 0x01);
+            mbc.writeram(0xA000, 0x12);
+            assert_eq!(mbc.readram(0xA000), 0xFF);
+            assert!(mbc.dumpram().is_empty());
+        }
+    }
+
+    // An MBC1 ROM with a header claiming four banks, where every byte holds its bank number
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..banks * 0x4000).map(|i| (i / 0x4000) as u8).collect();
+        data[0x147] = 0x01;
+        data[0x148] = 0x01;
+        data
+    }
+
+    #[test]
+    fn truncated_rom() {
+        let data = banked_rom(3);
+        assert!(super::get_mbc(data.clone(), false).is_err());
+
+        let mut mbc = super::get_mbc(data, true).unwrap();
+        mbc.writerom(0x2000, 2);
+        assert_eq!(mbc.readrom(0x4000), 2);
+        mbc.writerom(0x2000, 3);
+        assert_eq!(mbc.readrom(0x4000), 0xFF);
+    }
+
+    #[test]
+    fn overlong_rom() {
+        let mut mbc = super::get_mbc(banked_rom(8), true).unwrap();
+        // Bank numbers wrap around at the size given in the header
+        mbc.writerom(0x2000, 6);
+        assert_eq!(mbc.rom_bank(), 2);
+        assert_eq!(mbc.readrom(0x4000), 2);
+
+        let mut data = banked_rom(1);
+        data.resize(super::MAX_ROM_SIZE + 1, 0);
+        assert!(super::get_mbc(data, true).is_err());
+    }
+
+    // Writes a header the boot ROM accepts at `start`
+    fn write_header(data: &mut [u8], start: usize, kind: u8, size: u8) {
+        let header = &mut data[start..];
+        header[0x104..0x134].copy_from_slice(&super::NINTENDO_LOGO);
+        header[0x147] = kind;
+        header[0x148] = size;
+        header[0x14D] = super::header_checksum(header);
+    }
+
+    #[test]
+    fn mmm01_maps_game() {
+        // The header of the first game is not valid, the menu header is in the last 32KiB
+        let mut data = banked_rom(8);
+        write_header(&mut data, 0x18000, 0x0B, 0x02);
+        let mut mbc = super::get_mbc(data, true).unwrap();
+        assert_eq!(mbc.readrom(0x0000), 6);
+        assert_eq!(mbc.readrom(0x4000), 7);
+
+        // A game in banks 2-3, with bits 1-4 of the ROM bank locked
+        mbc.writerom(0x6000, 0x3C);
+        mbc.writerom(0x2000, 0x02);
+        assert_eq!(mbc.readrom(0x4000), 7);
+        mbc.writerom(0x0000, 0x40);
+        assert_eq!(mbc.readrom(0x0000), 2);
+        mbc.writerom(0x2000, 0x00);
+        assert_eq!(mbc.readrom(0x4000), 3);
+        mbc.writerom(0x2000, 0x1E);
+        assert_eq!(mbc.readrom(0x4000), 3);
+
+        // Mapping can not be undone
+        mbc.writerom(0x0000, 0x00);
+        mbc.writerom(0x6000, 0x00);
+        mbc.writerom(0x2000, 0x04);
+        assert_eq!(mbc.readrom(0x0000), 2);
+    }
+
+    #[test]
+    fn mmm01_needs_invalid_first_header() {
+        // An MBC5 game with the MMM01 type where a menu header would be
+        let mut data = vec![0; 0x20000];
+        write_header(&mut data, 0, 0x19, 0x02);
+        data[0x18147] = 0x0C;
+        let cartridge = Cartridge::from_bytes(data.clone(), false).unwrap();
+        assert_eq!(cartridge.kind(), MapperKind::Mbc5);
+        assert_eq!(cartridge.mbc.header_start(), 0);
+
+        // Even with a valid menu header
+        write_header(&mut data, 0x18000, 0x0C, 0x02);
+        let cartridge = Cartridge::from_bytes(data, false).unwrap();
+        assert_eq!(cartridge.kind(), MapperKind::Mbc5);
+
+        // A menu that is not at the end of the size it declares
+        let mut data = vec![0; 0x20000];
+        write_header(&mut data, 0x18000, 0x0B, 0x01);
+        assert!(super::get_mbc(data, true).is_err());
+    }
 }
@@ -1,16 +1,60 @@
-use crate::gbmode::{GbMode, GbSpeed};
+use crate::cartridge::Cartridge;
+use crate::gbmode::{GbMode, GbSpeed, Model};
 use crate::gpu::GPU;
+use crate::infrared::Infrared;
 use crate::keypad::Keypad;
-use crate::mbc;
-use crate::serial::{Serial, SerialCallback};
-use crate::sound::Sound;
+use crate::mbc::MbcState;
+use crate::palette;
+use crate::serial::{Serial, SerialCallback, SerialState};
+#[cfg(feature = "std")]
+use crate::sound::{Sound, SoundState};
 use crate::timer::Timer;
 use crate::StrResult;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::Cell;
 
 const WRAM_SIZE: usize = 0x8000;
 const ZRAM_SIZE: usize = 0x7F;
+// Every 16 bytes of HDMA take 32 GPU cycles, about 8 microseconds, in either speed mode
+const HDMA_ROW_TICKS: u32 = 32;
+// Trapped accesses kept for `take_io_accesses`, later ones are dropped until it is called
+const MAX_IO_ACCESSES: usize = 1024;
 
-#[derive(PartialEq)]
+/// Called during every trapped access. For a read, `Some` is returned instead of the trap value.
+pub type IoTrapCallback = Box<dyn FnMut(IoAccess) -> Option<u8> + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+}
+
+/// An access to an address trapped with `add_io_trap`. For a read, `value` is what it returned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoAccess {
+    pub address: u16,
+    pub kind: WatchKind,
+    pub value: u8,
+}
+
+#[derive(Clone)]
+struct IoTrap {
+    address: u16,
+    on_read: bool,
+    on_write: bool,
+    value: u8,
+}
+
+#[derive(Clone)]
+struct Watchpoint {
+    address: u16,
+    on_read: bool,
+    on_write: bool,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum DMAType {
     NoDMA,
     GDMA,
@@ -26,18 +70,64 @@ pub struct MMU<'a> {
     pub serial: Serial<'a>,
     pub timer: Timer,
     pub keypad: Keypad,
+    pub infrared: Infrared,
     pub gpu: GPU,
+    #[cfg(feature = "std")]
     pub sound: Option<Sound>,
     hdma_status: DMAType,
     hdma_src: u16,
     hdma_dst: u16,
     hdma_len: u8,
     wrambank: usize,
-    pub mbc: Box<dyn mbc::MBC + 'static>,
+    pub cartridge: Cartridge,
+    pub model: Model,
     pub gbmode: GbMode,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
-    undocumented_cgb_regs: [u8; 3], // 0xFF72, 0xFF73, 0xFF75
+    undocumented_cgb_regs: [u8; 4], // 0xFF72-0xFF75
+    oamdma_source: u8,
+    // CPU cycles until the OAM DMA is done and the CPU can access OAM again
+    oamdma_remaining: u32,
+    pub strict_vram_access: bool,
+    watchpoints: Vec<Watchpoint>,
+    // Reads only borrow the MMU, so record hits through a Cell
+    watchpoint_hit: Cell<Option<(u16, WatchKind)>>,
+    io_traps: Vec<IoTrap>,
+    io_accesses: Vec<IoAccess>,
+    io_trap_callback: Option<IoTrapCallback>,
+    boot_rom: Option<Vec<u8>>,
+}
+
+/// The memory and all hardware behind the MMU except for the cartridge ROM, for save states.
+/// Watchpoints and callbacks belong to the host and are not part of it.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MmuState {
+    wram: Vec<u8>,
+    zram: Vec<u8>,
+    hdma: [u8; 4],
+    inte: u8,
+    intf: u8,
+    hdma_status: DMAType,
+    hdma_src: u16,
+    hdma_dst: u16,
+    hdma_len: u8,
+    wrambank: usize,
+    gbmode: GbMode,
+    gbspeed: GbSpeed,
+    speed_switch_req: bool,
+    undocumented_cgb_regs: [u8; 4],
+    oamdma_source: u8,
+    oamdma_remaining: u32,
+    boot_rom: Option<Vec<u8>>,
+    serial: SerialState,
+    timer: Timer,
+    keypad: Keypad,
+    infrared: Infrared,
+    gpu: GPU,
+    cartridge: MbcState,
+    /// Only present when audio was enabled
+    #[cfg(feature = "std")]
+    sound: Option<SoundState>,
 }
 
 fn fill_random(slice: &mut [u8], start: u32) {
@@ -54,47 +144,21 @@ fn fill_random(slice: &mut [u8], start: u32) {
 }
 
 impl<'a> MMU<'a> {
-    pub fn new(
-        cart: Box<dyn mbc::MBC + 'static>,
+    pub fn new(cart: Cartridge, serial_callback: Option<SerialCallback<'a>>) -> StrResult<MMU<'a>> {
+        MMU::new_with_model(cart, serial_callback, Model::Dmg)
+    }
+
+    pub fn new_cgb(
+        cart: Cartridge,
         serial_callback: Option<SerialCallback<'a>>,
     ) -> StrResult<MMU<'a>> {
-        let serial = match serial_callback {
-            Some(cb) => Serial::new_with_callback(cb),
-            None => Serial::new(),
-        };
-        let mut res = MMU {
-            wram: [0; WRAM_SIZE],
-            zram: [0; ZRAM_SIZE],
-            hdma: [0; 4],
-            wrambank: 1,
-            inte: 0,
-            intf: 0,
-            serial: serial,
-            timer: Timer::new(),
-            keypad: Keypad::new(),
-            gpu: GPU::new(),
-            sound: None,
-            mbc: cart,
-            gbmode: GbMode::Classic,
-            gbspeed: GbSpeed::Single,
-            speed_switch_req: false,
-            hdma_src: 0,
-            hdma_dst: 0,
-            hdma_status: DMAType::NoDMA,
-            hdma_len: 0xFF,
-            undocumented_cgb_regs: [0; 3],
-        };
-        fill_random(&mut res.wram, 42);
-        if res.rb(0x0143) == 0xC0 {
-            return Err("This game does not work in Classic mode");
-        }
-        res.set_initial();
-        Ok(res)
+        MMU::new_with_model(cart, serial_callback, Model::Cgb)
     }
 
-    pub fn new_cgb(
-        cart: Box<dyn mbc::MBC + 'static>,
+    pub fn new_with_model(
+        cart: Cartridge,
         serial_callback: Option<SerialCallback<'a>>,
+        model: Model,
     ) -> StrResult<MMU<'a>> {
         let serial = match serial_callback {
             Some(cb) => Serial::new_with_callback(cb),
@@ -103,27 +167,49 @@ impl<'a> MMU<'a> {
         let mut res = MMU {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
-            wrambank: 1,
             hdma: [0; 4],
+            wrambank: 1,
             inte: 0,
             intf: 0,
             serial: serial,
             timer: Timer::new(),
             keypad: Keypad::new(),
-            gpu: GPU::new_cgb(),
+            infrared: Infrared::new(),
+            gpu: match model.is_color() {
+                true => GPU::new_cgb(),
+                false => GPU::new(),
+            },
+            #[cfg(feature = "std")]
             sound: None,
-            mbc: cart,
-            gbmode: GbMode::Color,
+            cartridge: cart,
+            model,
+            gbmode: match model.is_color() {
+                true => GbMode::Color,
+                false => GbMode::Classic,
+            },
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
             hdma_src: 0,
             hdma_dst: 0,
             hdma_status: DMAType::NoDMA,
             hdma_len: 0xFF,
-            undocumented_cgb_regs: [0; 3],
+            undocumented_cgb_regs: [0; 4],
+            oamdma_source: 0,
+            oamdma_remaining: 0,
+            strict_vram_access: true,
+            watchpoints: Vec::new(),
+            watchpoint_hit: Cell::new(None),
+            io_traps: Vec::new(),
+            io_accesses: Vec::new(),
+            io_trap_callback: None,
+            boot_rom: None,
         };
         fill_random(&mut res.wram, 42);
-        res.determine_mode();
+        if model.is_color() {
+            res.determine_mode();
+        } else if res.rb(0x0143) == 0xC0 {
+            return Err("This game only works on the color models");
+        }
         res.set_initial();
         Ok(res)
     }
@@ -160,6 +246,23 @@ impl<'a> MMU<'a> {
         self.wb(0xFF49, 0xFF);
         self.wb(0xFF4A, 0);
         self.wb(0xFF4B, 0);
+
+        // The boot ROM leaves the VBlank interrupt requested
+        self.intf = 0x01;
+        match self.model {
+            Model::Dmg | Model::Mgb | Model::Sgb => {
+                self.timer.set_divider(0xAB);
+                self.oamdma_source = 0xFF;
+                // The SGB boot ROM takes longer, and where it leaves the LCD is not known
+                if self.model != Model::Sgb {
+                    self.gpu.end_boot_frame();
+                }
+            }
+            Model::Cgb | Model::Agb => {
+                // SC reads back with the internal clock selected
+                self.serial.wb(0xFF02, 0x01);
+            }
+        }
     }
 
     fn determine_mode(&mut self) {
@@ -169,6 +272,12 @@ impl<'a> MMU<'a> {
         };
         self.gbmode = mode;
         self.gpu.gbmode = mode;
+
+        if mode == GbMode::ColorAsClassic {
+            // Like the CGB boot ROM, colour classic games based on their header
+            let header: Vec<u8> = (0..0x150).map(|a| self.cartridge.mbc.readrom(a)).collect();
+            self.gpu.dmg_palette = palette::cgb_compat_palette(&header);
+        }
     }
 
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
@@ -177,10 +286,15 @@ impl<'a> MMU<'a> {
         let gputicks = ticks / cpudivider + vramticks;
         let cputicks = ticks + vramticks * cpudivider;
 
+        // The DMA runs at the CPU clock, so it finishes sooner in double speed mode
+        self.oamdma_remaining = self.oamdma_remaining.saturating_sub(cputicks);
+
         self.timer.do_cycle(cputicks);
         self.intf |= self.timer.interrupt;
         self.timer.interrupt = 0;
 
+        self.serial.do_cycle(cputicks);
+
         self.intf |= self.keypad.interrupt;
         self.keypad.interrupt = 0;
 
@@ -188,6 +302,7 @@ impl<'a> MMU<'a> {
         self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
+        #[cfg(feature = "std")]
         let _ = self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
 
         self.intf |= self.serial.interrupt;
@@ -196,11 +311,273 @@ impl<'a> MMU<'a> {
         return gputicks;
     }
 
-    pub fn rb(&self, address: u16) -> u8 {
+    /// The system clock does not run while STOP waits for a button, so the timer, the LCD and
+    /// the sound stand still. Returns the time that passed like `do_cycle`.
+    pub fn stopped_cycle(&mut self, ticks: u32) -> u32 {
+        self.intf |= self.keypad.interrupt;
+        self.keypad.interrupt = 0;
+        ticks / self.gbspeed as u32
+    }
+
+    pub fn speed(&self) -> GbSpeed {
+        self.gbspeed
+    }
+
+    /// The WRAM bank mapped at 0xD000-0xDFFF, always 1 outside of color mode
+    pub fn wram_bank(&self) -> usize {
+        self.wrambank
+    }
+
+    /// The 4 KiB bank `bank` of WRAM, regardless of the banks currently mapped. Panics for banks
+    /// above 7.
+    pub fn wram(&self, bank: u8) -> &[u8] {
+        let start = bank as usize * 0x1000;
+        &self.wram[start..start + 0x1000]
+    }
+
+    /// 0xFF80-0xFFFE
+    pub fn hram(&self) -> &[u8] {
+        &self.zram
+    }
+
+    /// A copy of the whole memory map that shares the cartridge ROM. Callbacks are not copied,
+    /// and the sound hardware of the copy plays to nowhere.
+    pub fn fork(&self) -> MMU<'a> {
+        MMU {
+            serial: self.serial.fork(),
+            timer: self.timer.clone(),
+            keypad: self.keypad.clone(),
+            infrared: self.infrared.fork(),
+            gpu: self.gpu.fork(),
+            #[cfg(feature = "std")]
+            sound: self.sound.as_ref().map(|sound| sound.fork()),
+            cartridge: self.cartridge.fork(),
+            watchpoints: self.watchpoints.clone(),
+            watchpoint_hit: self.watchpoint_hit.clone(),
+            io_traps: self.io_traps.clone(),
+            io_accesses: self.io_accesses.clone(),
+            io_trap_callback: None,
+            boot_rom: self.boot_rom.clone(),
+            ..*self
+        }
+    }
+
+    pub fn state(&self) -> MmuState {
+        MmuState {
+            wram: self.wram.to_vec(),
+            zram: self.zram.to_vec(),
+            hdma: self.hdma,
+            inte: self.inte,
+            intf: self.intf,
+            hdma_status: self.hdma_status,
+            hdma_src: self.hdma_src,
+            hdma_dst: self.hdma_dst,
+            hdma_len: self.hdma_len,
+            wrambank: self.wrambank,
+            gbmode: self.gbmode,
+            gbspeed: self.gbspeed,
+            speed_switch_req: self.speed_switch_req,
+            undocumented_cgb_regs: self.undocumented_cgb_regs,
+            oamdma_source: self.oamdma_source,
+            oamdma_remaining: self.oamdma_remaining,
+            boot_rom: self.boot_rom.clone(),
+            serial: self.serial.state(),
+            timer: self.timer.clone(),
+            keypad: self.keypad.clone(),
+            infrared: self.infrared.fork(),
+            gpu: self.gpu.fork(),
+            cartridge: self.cartridge.state(),
+            #[cfg(feature = "std")]
+            sound: self.sound.as_ref().map(|sound| sound.save_state()),
+        }
+    }
+
+    /// The sound state is only loaded while audio is enabled. Nothing is changed when the state
+    /// does not fit this machine.
+    pub fn load_state(&mut self, state: MmuState) -> StrResult<()> {
+        if state.gbmode != self.gbmode {
+            return Err("Save state is for a different Gameboy mode");
+        }
+        if state.wram.len() != WRAM_SIZE
+            || state.zram.len() != ZRAM_SIZE
+            || !(1..=7).contains(&state.wrambank)
+        {
+            return Err("Invalid memory in save state");
+        }
+        // Everything that can fail is checked before anything is changed
+        state.gpu.check_state()?;
+        state.timer.check_state()?;
+        self.cartridge.fork().load_state(&state.cartridge)?;
+        #[cfg(feature = "std")]
+        if let (Some(sound), Some(sound_state)) = (self.sound.as_mut(), state.sound.as_ref()) {
+            sound.load_state(sound_state)?;
+        }
+        self.cartridge.load_state(&state.cartridge)?;
+        self.gpu.load_state(state.gpu)?;
+
+        self.wram.copy_from_slice(&state.wram);
+        self.zram.copy_from_slice(&state.zram);
+        self.hdma = state.hdma;
+        self.inte = state.inte;
+        self.intf = state.intf;
+        self.hdma_status = state.hdma_status;
+        self.hdma_src = state.hdma_src;
+        self.hdma_dst = state.hdma_dst;
+        self.hdma_len = state.hdma_len;
+        self.wrambank = state.wrambank;
+        self.gbspeed = state.gbspeed;
+        self.speed_switch_req = state.speed_switch_req;
+        self.undocumented_cgb_regs = state.undocumented_cgb_regs;
+        self.oamdma_source = state.oamdma_source;
+        self.oamdma_remaining = state.oamdma_remaining;
+        self.boot_rom = state.boot_rom;
+        self.serial.load_state(state.serial);
+        self.timer = state.timer;
+        self.keypad = state.keypad;
+        self.infrared.load_state(state.infrared);
+        Ok(())
+    }
+
+    /// Maps a boot ROM over the start of the cartridge, until the boot ROM writes to 0xFF50. The
+    /// color boot ROM leaves 0x0100-0x01FF free for the cartridge header.
+    pub fn map_boot_rom(&mut self, rom: Vec<u8>) {
+        self.boot_rom = Some(rom);
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16, on_read: bool, on_write: bool) {
+        self.remove_watchpoint(address);
+        if on_read || on_write {
+            self.watchpoints.push(Watchpoint {
+                address,
+                on_read,
+                on_write,
+            });
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) {
+        self.watchpoints.retain(|w| w.address != address);
+    }
+
+    pub fn watchpoint_hit(&self) -> Option<(u16, WatchKind)> {
+        self.watchpoint_hit.get()
+    }
+
+    pub fn clear_watchpoint_hit(&mut self) {
+        self.watchpoint_hit.set(None);
+    }
+
+    fn check_watchpoint(&self, address: u16, kind: WatchKind) {
+        let hit = self.watchpoints.iter().any(|w| {
+            w.address == address
+                && match kind {
+                    WatchKind::Read => w.on_read,
+                    WatchKind::Write => w.on_write,
+                }
+        });
+        if hit && self.watchpoint_hit.get().is_none() {
+            self.watchpoint_hit.set(Some((address, kind)));
+        }
+    }
+
+    /// Detaches the address from the hardware behind it: reads return the value given to
+    /// `set_io_trap_value` or the callback, writes go nowhere, and both are passed to the
+    /// callback and recorded for `take_io_accesses`
+    pub fn add_io_trap(&mut self, address: u16, on_read: bool, on_write: bool) {
+        self.remove_io_trap(address);
+        if on_read || on_write {
+            self.io_traps.push(IoTrap {
+                address,
+                on_read,
+                on_write,
+                value: 0xFF,
+            });
+        }
+    }
+
+    pub fn remove_io_trap(&mut self, address: u16) {
+        self.io_traps.retain(|t| t.address != address);
+    }
+
+    pub fn set_io_trap_value(&mut self, address: u16, value: u8) {
+        for trap in self.io_traps.iter_mut().filter(|t| t.address == address) {
+            trap.value = value;
+        }
+    }
+
+    pub fn take_io_accesses(&mut self) -> Vec<IoAccess> {
+        core::mem::take(&mut self.io_accesses)
+    }
+
+    pub fn set_io_trap_callback(&mut self, cb: IoTrapCallback) {
+        self.io_trap_callback = Some(cb);
+    }
+
+    pub fn unset_io_trap_callback(&mut self) {
+        self.io_trap_callback = None;
+    }
+
+    fn check_io_trap(&mut self, address: u16, kind: WatchKind, value: u8) -> Option<u8> {
+        let trap = self.io_traps.iter().find(|t| {
+            t.address == address
+                && match kind {
+                    WatchKind::Read => t.on_read,
+                    WatchKind::Write => t.on_write,
+                }
+        })?;
+        let mut access = IoAccess {
+            address,
+            kind,
+            value: match kind {
+                WatchKind::Read => trap.value,
+                WatchKind::Write => value,
+            },
+        };
+        if let Some(cb) = self.io_trap_callback.as_mut() {
+            if let (WatchKind::Read, Some(value)) = (kind, cb(access)) {
+                access.value = value;
+            }
+        }
+        if self.io_accesses.len() < MAX_IO_ACCESSES {
+            self.io_accesses.push(access);
+        }
+        Some(access.value)
+    }
+
+    pub fn rb(&mut self, address: u16) -> u8 {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoint(address, WatchKind::Read);
+        }
+        if !self.io_traps.is_empty() {
+            if let Some(value) = self.check_io_trap(address, WatchKind::Read, 0) {
+                return value;
+            }
+        }
+        // PCM12 and PCM34 show the channels as they are now
+        #[cfg(feature = "std")]
+        if let (0xFF76..=0xFF77, Some(sound)) = (address, self.sound.as_mut()) {
+            sound.catch_up();
+        }
+        self.peek(address)
+    }
+
+    /// Reads a byte like the CPU would, without triggering watchpoints or IO traps
+    pub fn peek(&self, address: u16) -> u8 {
+        if let Some(ref rom) = self.boot_rom {
+            if (address as usize) < rom.len() && !(0x0100..0x0200).contains(&address) {
+                return rom[address as usize];
+            }
+        }
         match address {
-            0x0000..=0x7FFF => self.mbc.readrom(address),
+            0x0000..=0x7FFF => self.cartridge.mbc.readrom(address),
+            0x8000..=0x9FFF if self.strict_vram_access && !self.gpu.vram_accessible() => 0xFF,
+            0xFE00..=0xFE9F if self.strict_vram_access && !self.oam_accessible() => 0xFF,
             0x8000..=0x9FFF => self.gpu.rb(address),
-            0xA000..=0xBFFF => self.mbc.readram(address),
+            0xA000..=0xBFFF => self.cartridge.mbc.readram(address),
             0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram[address as usize & 0x0FFF],
             0xD000..=0xDFFF | 0xF000..=0xFDFF => {
                 self.wram[(self.wrambank * 0x1000) | address as usize & 0x0FFF]
@@ -210,8 +587,11 @@ impl<'a> MMU<'a> {
             0xFF01..=0xFF02 => self.serial.rb(address),
             0xFF04..=0xFF07 => self.timer.rb(address),
             0xFF0F => self.intf | 0b11100000,
+            #[cfg(feature = "std")]
             0xFF10..=0xFF3F => self.sound.as_ref().map_or(0xFF, |s| s.rb(address)),
-            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 if self.gbmode != GbMode::Color => {
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF56 | 0xFF6C | 0xFF70 | 0xFF74
+                if self.gbmode != GbMode::Color =>
+            {
                 0xFF
             }
             0xFF72..=0xFF73 | 0xFF75..=0xFF77 if self.gbmode == GbMode::Classic => 0xFF,
@@ -224,13 +604,18 @@ impl<'a> MMU<'a> {
                     })
                     | (if self.speed_switch_req { 1 } else { 0 })
             }
+            0xFF46 => self.oamdma_source,
             0xFF40..=0xFF4F => self.gpu.rb(address),
             0xFF51..=0xFF55 => self.hdma_read(address),
-            0xFF68..=0xFF6B => self.gpu.rb(address),
+            0xFF56 => self.infrared.rb(),
+            0xFF68..=0xFF6C => self.gpu.rb(address),
             0xFF70 => self.wrambank as u8,
-            0xFF72..=0xFF73 => self.undocumented_cgb_regs[address as usize - 0xFF72],
-            0xFF75 => self.undocumented_cgb_regs[2] | 0b10001111,
-            0xFF76..=0xFF77 => 0x00, // CGB PCM registers. Not yet implemented.
+            0xFF72..=0xFF74 => self.undocumented_cgb_regs[address as usize - 0xFF72],
+            0xFF75 => self.undocumented_cgb_regs[3] | 0b10001111,
+            #[cfg(feature = "std")]
+            0xFF76..=0xFF77 => self.sound.as_ref().map_or(0x00, |s| s.rb(address)),
+            #[cfg(not(feature = "std"))]
+            0xFF76..=0xFF77 => 0x00,
             0xFF80..=0xFFFE => self.zram[address as usize & 0x007F],
             0xFFFF => self.inte,
             _ => 0xFF,
@@ -242,28 +627,46 @@ impl<'a> MMU<'a> {
     }
 
     pub fn wb(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoint(address, WatchKind::Write);
+        }
+        if !self.io_traps.is_empty()
+            && self
+                .check_io_trap(address, WatchKind::Write, value)
+                .is_some()
+        {
+            return;
+        }
         match address {
-            0x0000 ..= 0x7FFF => self.mbc.writerom(address, value),
+            0x0000 ..= 0x7FFF => self.cartridge.mbc.writerom(address, value),
+            0x8000 ..= 0x9FFF if self.strict_vram_access && !self.gpu.vram_accessible() => {},
+            0xFE00 ..= 0xFE9F if self.strict_vram_access && !self.oam_accessible() => {},
             0x8000 ..= 0x9FFF => self.gpu.wb(address, value),
-            0xA000 ..= 0xBFFF => self.mbc.writeram(address, value),
+            0xA000 ..= 0xBFFF => self.cartridge.mbc.writeram(address, value),
             0xC000 ..= 0xCFFF | 0xE000 ..= 0xEFFF => self.wram[address as usize & 0x0FFF] = value,
             0xD000 ..= 0xDFFF | 0xF000 ..= 0xFDFF => self.wram[(self.wrambank * 0x1000) | (address as usize & 0x0FFF)] = value,
             0xFE00 ..= 0xFE9F => self.gpu.wb(address, value),
             0xFF00 => self.keypad.wb(value),
+            // The fast serial clock only exists in color mode
+            0xFF02 if self.gbmode != GbMode::Color => self.serial.wb(address, value & 0x81),
             0xFF01 ..= 0xFF02 => self.serial.wb(address, value),
             0xFF04 ..= 0xFF07 => self.timer.wb(address, value),
+            #[cfg(feature = "std")]
             0xFF10 ..= 0xFF3F => self.sound.as_mut().map_or((), |s| s.wb(address, value)),
             0xFF46 => self.oamdma(value),
-            0xFF4D | 0xFF4F | 0xFF51 ..= 0xFF55 | 0xFF6C | 0xFF70 | 0xFF76 ..= 0xFF77 if self.gbmode != GbMode::Color => {},
+            0xFF4D | 0xFF4F | 0xFF51 ..= 0xFF56 | 0xFF6C | 0xFF70 | 0xFF74 | 0xFF76 ..= 0xFF77 if self.gbmode != GbMode::Color => {},
             0xFF72 ..= 0xFF73 | 0xFF75 ..= 0xFF77 if self.gbmode == GbMode::Classic => {},
-            0xFF4D => if value & 0x1 == 0x1 { self.speed_switch_req = true; },
+            0xFF4D => self.speed_switch_req = value & 0x1 == 0x1,
+            0xFF50 if value & 0x1 == 0x1 => self.boot_rom = None,
             0xFF40 ..= 0xFF4F => self.gpu.wb(address, value),
             0xFF51 ..= 0xFF55 => self.hdma_write(address, value),
-            0xFF68 ..= 0xFF6B => self.gpu.wb(address, value),
+            0xFF56 => self.infrared.wb(value),
+            0xFF68 ..= 0xFF6C => self.gpu.wb(address, value),
             0xFF0F => self.intf = value,
             0xFF70 => { self.wrambank = match value & 0x7 { 0 => 1, n => n as usize }; },
-            0xFF72 ..= 0xFF73 => self.undocumented_cgb_regs[address as usize - 0xFF72] = value,
-            0xFF75 => self.undocumented_cgb_regs[2] = value,
+            0xFF72 ..= 0xFF74 => self.undocumented_cgb_regs[address as usize - 0xFF72] = value,
+            // Only bits 4-6 exist
+            0xFF75 => self.undocumented_cgb_regs[3] = value & 0b01110000,
             0xFF80 ..= 0xFFFE => self.zram[address as usize & 0x007F] = value,
             0xFFFF => self.inte = value,
             _ => {},
@@ -275,8 +678,10 @@ impl<'a> MMU<'a> {
         self.wb(address + 1, (value >> 8) as u8);
     }
 
-    pub fn switch_speed(&mut self) {
-        if self.speed_switch_req {
+    /// Returns whether a speed switch was requested through KEY1
+    pub fn switch_speed(&mut self) -> bool {
+        let requested = self.speed_switch_req;
+        if requested {
             if self.gbspeed == GbSpeed::Double {
                 self.gbspeed = GbSpeed::Single;
             } else {
@@ -284,13 +689,28 @@ impl<'a> MMU<'a> {
             }
         }
         self.speed_switch_req = false;
+        requested
+    }
+
+    fn oam_accessible(&self) -> bool {
+        self.oamdma_remaining == 0 && self.gpu.oam_accessible()
     }
 
+    /// The copy happens at once, but the CPU can not access OAM for the 160 machine cycles the
+    /// transfer takes
     fn oamdma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
+        self.oamdma_source = value;
+        self.oamdma_remaining = 160 * 4;
+        // Above 0xDF the DMA does not reach OAM and I/O, but reads the echo of WRAM
+        let source = match value {
+            0xE0..=0xFF => value - 0x20,
+            _ => value,
+        };
+        let base = (source as u16) << 8;
         for i in 0..0xA0 {
             let b = self.rb(base + i);
-            self.wb(0xFE00 + i, b);
+            // DMA writes to OAM regardless of the GPU mode
+            self.gpu.wb(0xFE00 + i, b);
         }
     }
 
@@ -360,7 +780,7 @@ impl<'a> MMU<'a> {
             self.hdma_status = DMAType::NoDMA;
         }
 
-        return 8;
+        return HDMA_ROW_TICKS;
     }
 
     fn perform_gdma(&mut self) -> u32 {
@@ -370,7 +790,7 @@ impl<'a> MMU<'a> {
         }
 
         self.hdma_status = DMAType::NoDMA;
-        return len * 8;
+        return len * HDMA_ROW_TICKS;
     }
 
     fn perform_vramdma_row(&mut self) {
@@ -389,3 +809,321 @@ impl<'a> MMU<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MMU;
+    use crate::cartridge::Cartridge;
+
+    fn cgb_mmu() -> MMU<'static> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        MMU::new_cgb(Cartridge::from_bytes(rom, true).unwrap(), None).unwrap()
+    }
+
+    #[test]
+    fn echo_ram() {
+        let mut mmu = cgb_mmu();
+
+        mmu.wb(0xC005, 0x12);
+        assert_eq!(mmu.rb(0xE005), 0x12);
+        mmu.wb(0xE006, 0x34);
+        assert_eq!(mmu.rb(0xC006), 0x34);
+
+        // The upper half follows the selected WRAM bank
+        mmu.wb(0xFF70, 3);
+        mmu.wb(0xD010, 0x56);
+        assert_eq!(mmu.rb(0xF010), 0x56);
+        mmu.wb(0xFF70, 4);
+        assert_ne!(mmu.rb(0xF010), 0x56);
+        mmu.wb(0xFDFF, 0x78);
+        assert_eq!(mmu.rb(0xDDFF), 0x78);
+    }
+
+    #[test]
+    fn unused_registers() {
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut mmu = MMU::new(cart, None).unwrap();
+
+        for &address in [0xFF03, 0xFF08, 0xFF0E, 0xFF4C, 0xFF4E, 0xFF50, 0xFF7F].iter() {
+            assert_eq!(mmu.rb(address), 0xFF, "{:04X}", address);
+        }
+        // CGB only registers are not mapped in classic mode
+        for &address in [0xFF4D, 0xFF4F, 0xFF55, 0xFF56, 0xFF68, 0xFF70].iter() {
+            assert_eq!(mmu.rb(address), 0xFF, "{:04X}", address);
+        }
+        assert_eq!(mmu.rb(0xFF0F) & 0xE0, 0xE0);
+
+        // The OAM DMA register reads back the last written value
+        assert_eq!(mmu.rb(0xFF46), 0xFF);
+        mmu.wb(0xFF46, 0xC1);
+        assert_eq!(mmu.rb(0xFF46), 0xC1);
+    }
+
+    #[test]
+    fn oam_dma_double_speed() {
+        for &double in [false, true].iter() {
+            let mut mmu = cgb_mmu();
+            mmu.wb(0xFF40, 0x11);
+            if double {
+                mmu.wb(0xFF4D, 0x01);
+                mmu.switch_speed();
+            }
+            mmu.wb(0xC000, 0x42);
+            mmu.wb(0xFF46, 0xC0);
+
+            let (mut cpu_ticks, mut gpu_ticks) = (0, 0);
+            while mmu.rb(0xFE00) == 0xFF {
+                gpu_ticks += mmu.do_cycle(4);
+                cpu_ticks += 4;
+            }
+            assert_eq!(mmu.rb(0xFE00), 0x42);
+            assert_eq!(cpu_ticks, 640);
+            assert_eq!(gpu_ticks, if double { 320 } else { 640 });
+        }
+    }
+
+    #[test]
+    fn key1_reads() {
+        let mut mmu = cgb_mmu();
+        assert_eq!(mmu.rb(0xFF4D), 0x7E);
+
+        mmu.wb(0xFF4D, 0xFF);
+        assert_eq!(mmu.rb(0xFF4D), 0x7F);
+        // Writing 0 disarms the switch again
+        mmu.wb(0xFF4D, 0x00);
+        assert_eq!(mmu.rb(0xFF4D), 0x7E);
+
+        mmu.wb(0xFF4D, 0x01);
+        mmu.switch_speed();
+        assert_eq!(mmu.rb(0xFF4D), 0xFE);
+        mmu.wb(0xFF4D, 0x01);
+        assert_eq!(mmu.rb(0xFF4D), 0xFF);
+        mmu.switch_speed();
+        assert_eq!(mmu.rb(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn undocumented_cgb_registers() {
+        let mut mmu = cgb_mmu();
+        for &address in [0xFF72, 0xFF73, 0xFF74].iter() {
+            mmu.wb(address, 0x5A);
+            assert_eq!(mmu.rb(address), 0x5A, "{:04X}", address);
+        }
+        mmu.wb(0xFF75, 0xFF);
+        assert_eq!(mmu.rb(0xFF75), 0xFF);
+        mmu.wb(0xFF75, 0x00);
+        assert_eq!(mmu.rb(0xFF75), 0x8F);
+
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut mmu = MMU::new(cart, None).unwrap();
+        mmu.wb(0xFF74, 0x5A);
+        assert_eq!(mmu.rb(0xFF74), 0xFF);
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut mmu = cgb_mmu();
+
+        for bank in 1..8 {
+            mmu.wb(0xFF70, bank);
+            mmu.wb(0xD000, bank * 0x11);
+        }
+        for bank in 1..8 {
+            mmu.wb(0xFF70, bank);
+            assert_eq!(mmu.wram_bank(), bank as usize);
+            assert_eq!(mmu.rb(0xD000), bank * 0x11);
+        }
+
+        // Bank 0 selects bank 1
+        mmu.wb(0xFF70, 0);
+        assert_eq!(mmu.wram_bank(), 1);
+        assert_eq!(mmu.rb(0xD000), 0x11);
+    }
+
+    #[test]
+    fn vram_banks() {
+        let mut mmu = cgb_mmu();
+
+        mmu.wb(0xFF4F, 1);
+        mmu.wb(0x8000, 0xAA);
+        assert_eq!(mmu.gpu.vram_bank(), 1);
+        mmu.wb(0xFF4F, 0);
+        mmu.wb(0x8000, 0x55);
+        assert_eq!(mmu.gpu.vram_bank(), 0);
+
+        assert_eq!(mmu.rb(0x8000), 0x55);
+        mmu.wb(0xFF4F, 1);
+        assert_eq!(mmu.rb(0x8000), 0xAA);
+    }
+
+    #[test]
+    fn vram_blocked_while_drawing() {
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut mmu = MMU::new(cart, None).unwrap();
+
+        mmu.wb(0xFF40, 0x11);
+        mmu.wb(0x8000, 0x42);
+        mmu.wb(0xFE00, 0x24);
+        mmu.wb(0xFF40, 0x91);
+
+        mmu.do_cycle(96);
+        assert_eq!(mmu.rb(0xFF41) & 0x03, 3);
+        assert_eq!(mmu.rb(0x8000), 0xFF);
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        mmu.wb(0x8000, 0x00);
+
+        mmu.strict_vram_access = false;
+        assert_eq!(mmu.rb(0x8000), 0x42);
+        mmu.strict_vram_access = true;
+
+        mmu.do_cycle(200);
+        assert_eq!(mmu.rb(0xFF41) & 0x03, 0);
+        assert_eq!(mmu.rb(0x8000), 0x42);
+        assert_eq!(mmu.rb(0xFE00), 0x24);
+    }
+
+    #[test]
+    fn stat_writes() {
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut mmu = MMU::new(cart, None).unwrap();
+        mmu.wb(0xFF45, 0x90);
+        mmu.wb(0xFF40, 0x91);
+        // Leave the vertical blank the boot ROM ends in
+        mmu.do_cycle(4);
+
+        // Mode and coincidence bits can not be written
+        mmu.wb(0xFF41, 0xFF);
+        assert_eq!(mmu.rb(0xFF41), 0xFA);
+        mmu.wb(0xFF41, 0x00);
+        assert_eq!(mmu.rb(0xFF41), 0x82);
+        assert_eq!(mmu.gpu.interrupt & 0x02, 0);
+
+        // Writing during HBlank raises the STAT interrupt on the DMG only
+        mmu.do_cycle(252);
+        assert_eq!(mmu.rb(0xFF41) & 0x03, 0);
+        mmu.intf = 0;
+        mmu.wb(0xFF41, 0x00);
+        mmu.do_cycle(4);
+        assert_eq!(mmu.intf & 0x02, 0x02);
+
+        let mut mmu = cgb_mmu();
+        mmu.wb(0xFF45, 0x90);
+        mmu.wb(0xFF40, 0x91);
+        mmu.do_cycle(252);
+        mmu.intf = 0;
+        mmu.wb(0xFF41, 0x00);
+        mmu.do_cycle(4);
+        assert_eq!(mmu.intf & 0x02, 0);
+    }
+
+    #[test]
+    fn object_priority() {
+        for &opri in [0x00, 0x01].iter() {
+            let mut mmu = cgb_mmu();
+            mmu.wb(0xFF40, 0x00);
+            assert_eq!(mmu.rb(0xFF6C), 0xFE);
+            mmu.wb(0xFF6C, opri);
+            assert_eq!(mmu.rb(0xFF6C), 0xFE | opri);
+
+            // Tile 1 is filled with colour 1, which is red in palette 0 and blue in palette 1
+            for row in 0..8 {
+                mmu.wb(0x8010 + row * 2, 0xFF);
+            }
+            mmu.wb(0xFF6A, 0x82);
+            mmu.wb(0xFF6B, 0x1F);
+            mmu.wb(0xFF6B, 0x00);
+            mmu.wb(0xFF6A, 0x8A);
+            mmu.wb(0xFF6B, 0x00);
+            mmu.wb(0xFF6B, 0x7C);
+            // The first sprite in OAM starts at x 4, the second one left of it at x 0
+            for (i, &byte) in [16, 12, 1, 0x00, 16, 8, 1, 0x01].iter().enumerate() {
+                mmu.wb(0xFE00 + i as u16, byte);
+            }
+            mmu.wb(0xFF40, 0x83);
+            mmu.do_cycle(456);
+
+            let pixel = |x: usize| mmu.gpu.data[x * 3..x * 3 + 3].to_vec();
+            assert_ne!(pixel(0), pixel(10));
+            let expected = if opri == 0 { pixel(10) } else { pixel(0) };
+            assert_eq!(pixel(4), expected, "OPRI {}", opri);
+        }
+    }
+
+    // Turns the LCD on with the given LCDC value and measures how long line 0 stays in mode 3
+    fn mode3_length(mmu: &mut MMU, lcdc: u8) -> u32 {
+        mmu.wb(0xFF40, lcdc);
+        while mmu.rb(0xFF41) & 0x03 != 3 {
+            mmu.do_cycle(1);
+        }
+        let mut length = 0;
+        while mmu.rb(0xFF41) & 0x03 == 3 {
+            mmu.do_cycle(1);
+            length += 1;
+        }
+        mmu.wb(0xFF40, 0x00);
+        length
+    }
+
+    #[test]
+    fn mode3_penalties() {
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut mmu = MMU::new(cart, None).unwrap();
+        mmu.wb(0xFF40, 0x00);
+        assert_eq!(mode3_length(&mut mmu, 0x93), 172);
+
+        mmu.wb(0xFF43, 3);
+        assert_eq!(mode3_length(&mut mmu, 0x93), 175);
+        mmu.wb(0xFF43, 0);
+
+        // A sprite at the left edge waits for the first background tile
+        mmu.wb(0xFE00, 16);
+        mmu.wb(0xFE01, 8);
+        assert_eq!(mode3_length(&mut mmu, 0x93), 183);
+        assert_eq!(mode3_length(&mut mmu, 0x91), 172);
+        // A second sprite in the same tile does not
+        mmu.wb(0xFE04, 16);
+        mmu.wb(0xFE05, 10);
+        assert_eq!(mode3_length(&mut mmu, 0x93), 189);
+        // Sprites on other lines are not fetched
+        mmu.wb(0xFE04, 40);
+        assert_eq!(mode3_length(&mut mmu, 0x93), 183);
+
+        mmu.wb(0xFE00, 0);
+        mmu.wb(0xFF4A, 0);
+        mmu.wb(0xFF4B, 7);
+        assert_eq!(mode3_length(&mut mmu, 0xB3), 178);
+    }
+
+    #[test]
+    fn oam_dma_from_echo_ram() {
+        let mut mmu = cgb_mmu();
+        for i in 0..0xA0 {
+            mmu.wb(0xC000 + i, i as u8);
+            mmu.wb(0xDF00 + i, !i as u8);
+        }
+        // Turn the LCD off so OAM can be read back
+        mmu.wb(0xFF40, 0x00);
+
+        mmu.wb(0xFF46, 0xE0);
+        assert_eq!(mmu.rb(0xFF46), 0xE0);
+        // Wait for the transfer to finish
+        mmu.do_cycle(640);
+        assert!((0..0xA0).all(|i| mmu.rb(0xFE00 + i) == i as u8));
+
+        mmu.wb(0xFF46, 0xFF);
+        mmu.do_cycle(640);
+        assert!((0..0xA0).all(|i| mmu.rb(0xFE00 + i) == !i as u8));
+    }
+
+    #[test]
+    fn banks_fixed_in_classic_mode() {
+        let cart = Cartridge::from_bytes(vec![0; 0x8000], true).unwrap();
+        let mut mmu = MMU::new(cart, None).unwrap();
+
+        mmu.wb(0xFF70, 3);
+        mmu.wb(0xFF4F, 1);
+        assert_eq!(mmu.wram_bank(), 1);
+        assert_eq!(mmu.gpu.vram_bank(), 0);
+    }
+}
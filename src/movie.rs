@@ -0,0 +1,101 @@
+//! Recorded button presses, to play back a session frame by frame.
+//!
+//! A movie is stored as text, one line per run of frames with the same buttons: the number of
+//! frames, a space, and the `KeypadState` bits as two hexadecimal digits. Lines starting with
+//! `#` are comments. Playing it back only gives the same result when the game starts from the
+//! same state, so without a boot ROM, a real time clock or different saved RAM.
+
+use crate::keypad::KeypadState;
+use crate::StrResult;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputMovie {
+    runs: Vec<(u32, KeypadState)>,
+}
+
+impl InputMovie {
+    pub fn new() -> InputMovie {
+        InputMovie::default()
+    }
+
+    pub fn parse(text: &str) -> StrResult<InputMovie> {
+        let mut movie = InputMovie::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (frames, bits) = line.split_once(' ').ok_or("Invalid line in input movie")?;
+            let frames = frames
+                .parse()
+                .map_err(|_| "Invalid frame count in input movie")?;
+            let bits = u8::from_str_radix(bits.trim(), 16)
+                .map_err(|_| "Invalid buttons in input movie")?;
+            movie.runs.push((frames, KeypadState::from_bits(bits)));
+        }
+        Ok(movie)
+    }
+
+    /// Appends one frame with the given buttons held
+    pub fn record(&mut self, state: KeypadState) {
+        match self.runs.last_mut() {
+            Some((frames, last)) if *last == state => *frames += 1,
+            _ => self.runs.push((1, state)),
+        }
+    }
+
+    /// The runs of frames, as taken by `Device::play_sequence`
+    pub fn runs(&self) -> &[(u32, KeypadState)] {
+        &self.runs
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.runs.iter().map(|&(frames, _)| frames as u64).sum()
+    }
+
+    /// The buttons of every frame in turn
+    pub fn frames(&self) -> impl Iterator<Item = KeypadState> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|&(frames, state)| core::iter::repeat_n(state, frames as usize))
+    }
+}
+
+impl fmt::Display for InputMovie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(frames, state) in self.runs.iter() {
+            writeln!(f, "{} {:02X}", frames, state.bits())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InputMovie;
+    use crate::keypad::KeypadState;
+
+    #[test]
+    fn record_and_parse() {
+        let mut movie = InputMovie::new();
+        for _ in 0..3 {
+            movie.record(KeypadState::empty());
+        }
+        movie.record(KeypadState::A | KeypadState::RIGHT);
+        movie.record(KeypadState::empty());
+
+        assert_eq!(movie.to_string(), "3 00\n1 11\n1 00\n");
+        assert_eq!(movie.frame_count(), 5);
+        assert_eq!(
+            movie.frames().nth(3),
+            Some(KeypadState::A | KeypadState::RIGHT)
+        );
+        assert_eq!(movie.frames().count(), 5);
+
+        let parsed = InputMovie::parse("# test\n3 00\n\n1 11\n1 00\n").unwrap();
+        assert_eq!(parsed, movie);
+        assert!(InputMovie::parse("3\n").is_err());
+        assert!(InputMovie::parse("3 1FF\n").is_err());
+    }
+}
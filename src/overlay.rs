@@ -0,0 +1,89 @@
+//! A few lines of debug information drawn on top of the screen.
+
+use crate::gpu::{SCREEN_H, SCREEN_W};
+use alloc::format;
+
+// Every glyph is 3 by 5 pixels, one row per byte with the leftmost pixel in bit 2
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        _ => [0; 5],
+    }
+}
+
+const CHAR_W: usize = 4;
+const LINE_H: usize = 6;
+
+/// The values shown by the overlay. `Device::debug_overlay` fills in everything but the frame
+/// rate, which only the host can measure.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DebugOverlay {
+    pub fps: f64,
+    pub rom_bank: u16,
+    pub ly: u8,
+    pub pc: u16,
+}
+
+impl DebugOverlay {
+    /// Draws white text on a black box in the top left corner of a `SCREEN_W * SCREEN_H` RGB
+    /// frame, as returned by `Device::get_gpu_data`
+    pub fn draw(&self, frame: &mut [u8]) {
+        let lines = [
+            format!("FPS {:.1}", self.fps),
+            format!("BANK {:03X}", self.rom_bank),
+            format!("LY {}", self.ly),
+            format!("PC {:04X}", self.pc),
+        ];
+        let width = lines.iter().map(|l| l.len()).max().unwrap_or(0) * CHAR_W + 1;
+        let height = lines.len() * LINE_H + 1;
+        fill(frame, width, height);
+        for (row, line) in lines.iter().enumerate() {
+            for (column, c) in line.chars().enumerate() {
+                draw_glyph(frame, 1 + column * CHAR_W, 1 + row * LINE_H, glyph(c));
+            }
+        }
+    }
+}
+
+fn fill(frame: &mut [u8], width: usize, height: usize) {
+    for y in 0..height.min(SCREEN_H) {
+        let start = y * SCREEN_W * 3;
+        for v in frame[start..start + width.min(SCREEN_W) * 3].iter_mut() {
+            *v = 0;
+        }
+    }
+}
+
+fn draw_glyph(frame: &mut [u8], x: usize, y: usize, rows: [u8; 5]) {
+    for (dy, bits) in rows.iter().enumerate() {
+        for dx in 0..3 {
+            let (px, py) = (x + dx, y + dy);
+            if bits & (0b100 >> dx) != 0 && px < SCREEN_W && py < SCREEN_H {
+                let offset = (py * SCREEN_W + px) * 3;
+                frame[offset..offset + 3].copy_from_slice(&[0xFF; 3]);
+            }
+        }
+    }
+}
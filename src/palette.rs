@@ -0,0 +1,329 @@
+//! Colours used to display classic Gameboy games.
+//!
+//! A classic game only picks one of four shades for every pixel. Which colour each shade ends up
+//! as is decided here, separately for the background and both sprite palettes.
+
+/// The colours of the four shades, from lightest to darkest, as RGB
+pub type Shades = [[u8; 3]; 4];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DmgPalette {
+    pub bg: Shades,
+    pub obj0: Shades,
+    pub obj1: Shades,
+}
+
+const fn rgb(v: u32) -> [u8; 3] {
+    [(v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+const fn shades(a: u32, b: u32, c: u32, d: u32) -> Shades {
+    [rgb(a), rgb(b), rgb(c), rgb(d)]
+}
+
+const fn uniform(s: Shades) -> DmgPalette {
+    DmgPalette {
+        bg: s,
+        obj0: s,
+        obj1: s,
+    }
+}
+
+pub const GRAYSCALE: DmgPalette = uniform(shades(0xFFFFFF, 0xC0C0C0, 0x606060, 0x000000));
+pub const DMG_GREEN: DmgPalette = uniform(shades(0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F));
+pub const POCKET: DmgPalette = uniform(shades(0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F));
+pub const LIGHT: DmgPalette = uniform(shades(0x00B581, 0x009A71, 0x00694A, 0x004F3B));
+
+// The colours of the CGB boot ROM as RGB555, four per palette
+const BOOT_COLOURS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB, 0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, 0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000, 0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000, 0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, 0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF, 0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000, 0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120, 0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000, 0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF, 0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+const fn rgb555(v: u16) -> [u8; 3] {
+    // Rounded to the nearest 8 bit value
+    const fn scale(c: u16) -> u8 {
+        (((c as u32 & 0x1F) * 255 + 15) / 31) as u8
+    }
+    [scale(v), scale(v >> 5), scale(v >> 10)]
+}
+
+const fn boot_shades(first: usize) -> Shades {
+    [
+        rgb555(BOOT_COLOURS[first]),
+        rgb555(BOOT_COLOURS[first + 1]),
+        rgb555(BOOT_COLOURS[first + 2]),
+        rgb555(BOOT_COLOURS[first + 3]),
+    ]
+}
+
+// The first colour of the OBJ0, OBJ1 and BG palettes of every combination the boot ROM can
+// pick. A few start in the middle of a palette, as they do in the boot ROM.
+const COMBINATIONS: [(u8, u8, u8); 51] = [
+    (16, 16, 116),
+    (72, 72, 72),
+    (80, 80, 80),
+    (96, 96, 96),
+    (36, 36, 36),
+    (0, 0, 0),
+    (108, 108, 108),
+    (20, 20, 20),
+    (48, 48, 48),
+    (104, 104, 104),
+    (64, 32, 32),
+    (16, 112, 112),
+    (16, 8, 8),
+    (12, 16, 16),
+    (16, 116, 116),
+    (112, 16, 112),
+    (8, 68, 8),
+    (64, 64, 32),
+    (16, 16, 28),
+    (16, 16, 72),
+    (16, 16, 80),
+    (76, 76, 36),
+    (15, 15, 44),
+    (68, 68, 8),
+    (16, 16, 8),
+    (16, 16, 12),
+    (112, 112, 0),
+    (12, 12, 0),
+    (0, 0, 4),
+    (72, 88, 72),
+    (80, 88, 80),
+    (96, 88, 96),
+    (64, 88, 32),
+    (68, 16, 52),
+    (111, 0, 56),
+    (111, 16, 60),
+    (76, 88, 36),
+    (64, 112, 40),
+    (16, 92, 112),
+    (68, 88, 8),
+    (16, 0, 8),
+    (16, 112, 12),
+    (112, 12, 0),
+    (12, 112, 16),
+    (84, 112, 16),
+    (12, 112, 0),
+    (100, 12, 112),
+    (0, 112, 32),
+    (16, 12, 112),
+    (112, 12, 24),
+    (16, 112, 116),
+];
+
+const fn combination(index: usize) -> DmgPalette {
+    let (obj0, obj1, bg) = COMBINATIONS[index];
+    DmgPalette {
+        bg: boot_shades(bg as usize),
+        obj0: boot_shades(obj0 as usize),
+        obj1: boot_shades(obj1 as usize),
+    }
+}
+
+// The palettes below are assigned by the CGB boot ROM, the first four can also be picked with
+// the direction buttons while it runs
+pub const CGB_BROWN: DmgPalette = combination(5);
+pub const CGB_RED: DmgPalette = combination(43);
+pub const CGB_BLUE: DmgPalette = combination(48);
+pub const CGB_GREEN: DmgPalette = combination(1);
+/// Used for every game the CGB boot ROM does not recognize
+pub const CGB_DEFAULT: DmgPalette = combination(0);
+
+pub const PRESETS: [(&str, DmgPalette); 9] = [
+    ("Grayscale", GRAYSCALE),
+    ("DMG green", DMG_GREEN),
+    ("Pocket", POCKET),
+    ("Light", LIGHT),
+    ("CGB brown", CGB_BROWN),
+    ("CGB red", CGB_RED),
+    ("CGB blue", CGB_BLUE),
+    ("CGB green", CGB_GREEN),
+    ("CGB default", CGB_DEFAULT),
+];
+
+// Title checksum, the fourth letter of the title for checksums shared by several games (or 0),
+// and the combination the CGB boot ROM picks, in the order the boot ROM searches them
+const COMPAT_TABLE: [(u8, u8, u8); 94] = [
+    (0x00, 0, 0),
+    (0x88, 0, 4),
+    (0x16, 0, 5),
+    (0x36, 0, 35),
+    (0xD1, 0, 34),
+    (0xDB, 0, 3),
+    (0xF2, 0, 31),
+    (0x3C, 0, 15),
+    (0x8C, 0, 10),
+    (0x92, 0, 5),
+    (0x3D, 0, 19),
+    (0x5C, 0, 36),
+    (0x58, 0, 7),
+    (0xC9, 0, 37),
+    (0x3E, 0, 30),
+    (0x70, 0, 44),
+    (0x1D, 0, 21),
+    (0x59, 0, 32),
+    (0x69, 0, 31),
+    (0x19, 0, 20),
+    (0x35, 0, 5),
+    (0xA8, 0, 33),
+    (0x14, 0, 13),
+    (0xAA, 0, 14),
+    (0x75, 0, 5),
+    (0x95, 0, 29),
+    (0x99, 0, 5),
+    (0x34, 0, 18),
+    (0x6F, 0, 9),
+    (0x15, 0, 3),
+    (0xFF, 0, 2),
+    (0x97, 0, 26),
+    (0x4B, 0, 25),
+    (0x90, 0, 25),
+    (0x17, 0, 41),
+    (0x10, 0, 42),
+    (0x39, 0, 26),
+    (0xF7, 0, 45),
+    (0xF6, 0, 42),
+    (0xA2, 0, 45),
+    (0x49, 0, 36),
+    (0x4E, 0, 38),
+    (0xC3, 0, 26),
+    (0x68, 0, 42),
+    (0xE0, 0, 30),
+    (0x8B, 0, 41),
+    (0xF0, 0, 34),
+    (0xCE, 0, 34),
+    (0x0C, 0, 5),
+    (0x29, 0, 42),
+    (0xE8, 0, 6),
+    (0xB7, 0, 5),
+    (0x86, 0, 33),
+    (0x9A, 0, 25),
+    (0x52, 0, 42),
+    (0x01, 0, 42),
+    (0x9D, 0, 40),
+    (0x71, 0, 2),
+    (0x9C, 0, 16),
+    (0xBD, 0, 25),
+    (0x5D, 0, 42),
+    (0x6D, 0, 42),
+    (0x67, 0, 5),
+    (0x3F, 0, 0),
+    (0x6B, 0, 39),
+    (0xB3, b'B', 36),
+    (0x46, b'E', 22),
+    (0x28, b'F', 25),
+    (0xA5, b'A', 6),
+    (0xC6, b'A', 32),
+    (0xD3, b'R', 12),
+    (0x27, b'B', 36),
+    (0x61, b'E', 11),
+    (0x18, b'K', 39),
+    (0x66, b'E', 18),
+    (0x6A, b'K', 39),
+    (0xBF, b' ', 24),
+    (0x0D, b'R', 31),
+    (0xF4, b'-', 50),
+    (0xB3, b'U', 17),
+    (0x46, b'R', 46),
+    (0x28, b'A', 6),
+    (0xA5, b'R', 27),
+    (0xC6, b' ', 0),
+    (0xD3, b'I', 47),
+    (0x27, b'N', 41),
+    (0x61, b'A', 41),
+    (0x18, b'I', 0),
+    (0x66, b'L', 0),
+    (0x6A, b'I', 19),
+    (0xBF, b'C', 34),
+    (0x0D, b'E', 23),
+    (0xF4, b' ', 18),
+    (0xB3, b'R', 29),
+];
+
+/// Picks the palette a CGB would use for a classic game, based on the cartridge header
+pub fn cgb_compat_palette(header: &[u8]) -> DmgPalette {
+    if header.len() < 0x150 {
+        return CGB_DEFAULT;
+    }
+
+    // Only games published by Nintendo are recognized
+    let nintendo = match header[0x14B] {
+        0x01 => true,
+        0x33 => &header[0x144..0x146] == b"01",
+        _ => false,
+    };
+    if !nintendo {
+        return CGB_DEFAULT;
+    }
+
+    title_palette(&header[0x134..0x144])
+}
+
+/// The palette the CGB boot ROM picks for a Nintendo game with the given title
+pub(crate) fn title_palette(title: &[u8]) -> DmgPalette {
+    let title = &title[..title.len().min(16)];
+    let checksum = title.iter().fold(0u8, |sum, &v| sum.wrapping_add(v));
+    let fourth = title.get(3).copied().unwrap_or(0);
+    COMPAT_TABLE
+        .iter()
+        .find(|&&(sum, letter, _)| sum == checksum && (letter == 0 || letter == fourth))
+        .map_or(CGB_DEFAULT, |&(_, _, index)| combination(index as usize))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cgb_compat_palette, combination, shades, CGB_DEFAULT};
+
+    fn header(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn compat_palette_by_title() {
+        let red = shades(0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000);
+        let green = shades(0xFFFFFF, 0x7BFF31, 0x008400, 0x000000);
+        let blue = shades(0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000);
+
+        let palette = cgb_compat_palette(&header(b"POKEMON RED", 0x01));
+        assert_eq!((palette.bg, palette.obj0, palette.obj1), (red, green, red));
+        let palette = cgb_compat_palette(&header(b"POKEMON BLUE", 0x01));
+        assert_eq!((palette.bg, palette.obj0, palette.obj1), (blue, red, blue));
+        assert_eq!(cgb_compat_palette(&header(b"TETRIS", 0x01)), combination(3));
+        assert_eq!(cgb_compat_palette(&header(b"ZELDA", 0x01)), combination(44));
+
+        // Told apart from SUPER MARIOLAND by the fourth letter
+        assert_eq!(
+            cgb_compat_palette(&header(b"METROID2", 0x01)),
+            combination(46)
+        );
+        // Its sprite palettes start with the last colour of another palette
+        let palette = cgb_compat_palette(&header(b"SUPER MARIOLAND", 0x01));
+        assert_eq!(palette.obj0[0], [0, 0, 0]);
+        assert_eq!(palette.obj0[1], [0xFF, 0xFF, 0xFF]);
+
+        // Same checksum as POKEMON BLUE, but a different fourth letter
+        assert_eq!(
+            cgb_compat_palette(&header(b"POKFMON BLUD", 0x01)),
+            CGB_DEFAULT
+        );
+        // Only Nintendo games get their own palette
+        assert_eq!(
+            cgb_compat_palette(&header(b"POKEMON RED", 0x08)),
+            CGB_DEFAULT
+        );
+    }
+}
@@ -1,3 +1,26 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrinterStatus {
+    /// Image data was received, but not printed yet
+    pub in_progress: bool,
+    /// Rows of pixels received for the current print
+    pub rows: usize,
+    /// The last packet was rejected because its checksum did not match
+    pub checksum_error: bool,
+}
+
+/// Printed output, one byte per pixel where 0 is black and 255 is white
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+const PAPER_WIDTH: usize = 160;
+
+// A margin is given as a number of line feeds, each of which we take to span one 16 pixel band
+const ROWS_PER_FEED: usize = 16;
+
 pub struct GbPrinter {
     status: u8,
     state: u32,
@@ -8,6 +31,10 @@ pub struct GbPrinter {
     datasize: usize,
     result: u8,
     printcount: u8,
+    printing: bool,
+    checksum_error: bool,
+    paper: Vec<u8>,
+    save_prints: bool,
 }
 
 impl GbPrinter {
@@ -22,6 +49,32 @@ impl GbPrinter {
             datasize: 0,
             result: 0,
             printcount: 0,
+            printing: false,
+            checksum_error: false,
+            paper: Vec::new(),
+            // Without file access the output is only available through take_printed_image
+            save_prints: cfg!(feature = "file-io"),
+        }
+    }
+
+    /// Returns everything printed since the last call, including margins between the prints
+    pub fn take_printed_image(&mut self) -> Option<PrintedImage> {
+        if self.paper.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.paper);
+        Some(PrintedImage {
+            width: PAPER_WIDTH,
+            height: data.len() / PAPER_WIDTH,
+            data,
+        })
+    }
+
+    pub fn status(&self) -> PrinterStatus {
+        PrinterStatus {
+            in_progress: self.printing,
+            rows: self.datacount / 40,
+            checksum_error: self.checksum_error,
         }
     }
 
@@ -46,10 +99,45 @@ impl GbPrinter {
         self.result = 0;
     }
 
+    fn print(&mut self) {
+        let margins = self.packet[7];
+
+        self.feed(margins >> 4);
+        for y in 0..(self.datacount / 40) {
+            for x in 0..PAPER_WIDTH {
+                let shade = self.shade(x, y);
+                self.paper.push(shade * 85);
+            }
+        }
+        self.feed(margins & 0x0F);
+
+        if self.save_prints {
+            self.show();
+        }
+    }
+
+    fn feed(&mut self, count: u8) {
+        let blank = count as usize * ROWS_PER_FEED * PAPER_WIDTH;
+        self.paper.resize(self.paper.len() + blank, 255);
+    }
+
+    // Returns the shade of a received pixel after applying the palette, where 3 is white
+    fn shade(&self, x: usize, y: usize) -> u8 {
+        let tilenumber = ((y >> 3) * 20) + (x >> 3);
+        let tileoffset = tilenumber * 16 + (y & 7) * 2;
+        let bx = 7 - (x & 7);
+
+        let colourindex =
+            ((self.data[tileoffset] >> bx) & 1) | (((self.data[tileoffset + 1] >> bx) << 1) & 2);
+
+        let palbyte = self.packet[8];
+        3 - ((palbyte >> (colourindex * 2)) & 3)
+    }
+
     fn show(&mut self) {
         match self._show() {
-            Ok(filename) => println!("Print saved successfully to {}", filename),
-            Err(e) => println!("Error saving print... {:?}", e),
+            Ok(filename) => log::info!("Print saved successfully to {}", filename),
+            Err(e) => log::warn!("Error saving print... {:?}", e),
         }
     }
 
@@ -73,24 +161,9 @@ impl GbPrinter {
 
         write!(f, "P5 160 {} 3\n", image_height)?;
 
-        let palbyte = self.packet[8];
-        let palette = [
-            3 - ((palbyte >> 0) & 3),
-            3 - ((palbyte >> 2) & 3),
-            3 - ((palbyte >> 4) & 3),
-            3 - ((palbyte >> 6) & 3),
-        ];
-
         for y in 0..image_height {
-            for x in 0..160 {
-                let tilenumber = ((y >> 3) * 20) + (x >> 3);
-                let tileoffset = tilenumber * 16 + (y & 7) * 2;
-                let bx = 7 - (x & 7);
-
-                let colourindex = ((self.data[tileoffset] >> bx) & 1)
-                    | (((self.data[tileoffset + 1] >> bx) << 1) & 2);
-
-                f.write_all(&[palette[colourindex as usize]])?;
+            for x in 0..PAPER_WIDTH {
+                f.write_all(&[self.shade(x, y)])?;
             }
         }
 
@@ -137,12 +210,15 @@ impl GbPrinter {
             0x01 => {
                 self.datacount = 0;
                 self.status = 0;
+                self.printing = false;
             }
             0x02 => {
-                self.show();
+                self.print();
+                self.printing = false;
             }
             0x04 => {
                 self.receive();
+                self.printing |= self.datasize > 0;
             }
             _ => (),
         }
@@ -186,7 +262,8 @@ impl GbPrinter {
                 self.state = 5;
             }
             5 => {
-                if self.check_crc() {
+                self.checksum_error = !self.check_crc();
+                if !self.checksum_error {
                     self.command();
                 }
                 self.state = 6;
@@ -205,3 +282,73 @@ impl GbPrinter {
         self.result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{GbPrinter, ROWS_PER_FEED};
+
+    fn send_packet(printer: &mut GbPrinter, command: u8, data: &[u8], checksum_offset: u16) {
+        let mut packet = vec![0x88, 0x33, command, 0x00];
+        packet.push(data.len() as u8);
+        packet.push((data.len() >> 8) as u8);
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, &v| sum.wrapping_add(v as u16))
+            .wrapping_add(checksum_offset);
+        packet.push(checksum as u8);
+        packet.push((checksum >> 8) as u8);
+        packet.extend_from_slice(&[0x00, 0x00]);
+
+        for v in packet {
+            printer.send(v);
+        }
+    }
+
+    #[test]
+    fn status_while_receiving() {
+        let mut printer = GbPrinter::new();
+        send_packet(&mut printer, 0x01, &[], 0);
+        assert!(!printer.status().in_progress);
+
+        send_packet(&mut printer, 0x04, &[0xAA; 0x280], 0);
+        let status = printer.status();
+        assert!(status.in_progress);
+        assert_eq!(status.rows, 16);
+        assert!(!status.checksum_error);
+
+        send_packet(&mut printer, 0x04, &[0x55; 0x280], 1);
+        let status = printer.status();
+        assert!(status.checksum_error);
+        assert_eq!(status.rows, 16);
+    }
+
+    #[test]
+    fn palette_and_margins() {
+        let mut printer = GbPrinter::new();
+        printer.save_prints = false;
+
+        // Every pixel uses colour 3
+        send_packet(&mut printer, 0x01, &[], 0);
+        send_packet(&mut printer, 0x04, &[0xFF; 0x280], 0);
+        // One sheet, no margin before, one feed after, colour 3 is black
+        send_packet(&mut printer, 0x02, &[0x01, 0x01, 0xE4, 0x40], 0);
+
+        send_packet(&mut printer, 0x01, &[], 0);
+        send_packet(&mut printer, 0x04, &[0xFF; 0x280], 0);
+        // Colour 3 is light grey
+        send_packet(&mut printer, 0x02, &[0x01, 0x00, 0x40, 0x40], 0);
+        assert!(!printer.status().in_progress);
+
+        let image = printer.take_printed_image().unwrap();
+        assert_eq!(image.width, 160);
+        assert_eq!(image.height, 16 + ROWS_PER_FEED + 16);
+
+        let row = |y: usize| &image.data[y * 160..(y + 1) * 160];
+        assert!(row(0).iter().all(|&v| v == 0));
+        assert!(row(16).iter().all(|&v| v == 255));
+        assert!(row(16 + ROWS_PER_FEED).iter().all(|&v| v == 170));
+
+        assert!(printer.take_printed_image().is_none());
+    }
+}
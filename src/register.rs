@@ -1,6 +1,7 @@
-use crate::gbmode::GbMode;
+use crate::gbmode::{GbMode, Model};
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Registers {
     pub a: u8,
     f: u8,
@@ -23,9 +24,22 @@ pub enum CpuFlag {
 }
 
 impl Registers {
-    pub fn new(mode: GbMode) -> Registers {
+    /// The registers the boot ROM of the model leaves behind
+    pub fn new(model: Model, mode: GbMode) -> Registers {
         use CpuFlag::*;
-        match mode {
+        let mut reg = match mode {
+            GbMode::Classic if model == Model::Sgb => Registers {
+                a: 0x01,
+                f: 0,
+                b: 0x00,
+                c: 0x14,
+                d: 0x00,
+                e: 0x00,
+                h: 0xC0,
+                l: 0x60,
+                pc: 0x0100,
+                sp: 0xFFFE,
+            },
             GbMode::Classic => Registers {
                 a: 0x01,
                 f: C as u8 | H as u8 | Z as u8,
@@ -62,7 +76,17 @@ impl Registers {
                 pc: 0x0100,
                 sp: 0xFFFE,
             },
+        };
+        match model {
+            Model::Mgb => reg.a = 0xFF,
+            // The AGB boot ROM ends with an extra INC B
+            Model::Agb => {
+                reg.b += 1;
+                reg.f = 0;
+            }
+            _ => {}
         }
+        reg
     }
 
     pub fn af(&self) -> u16 {
@@ -129,11 +153,11 @@ impl Registers {
 mod test {
     use super::CpuFlag::{C, H, N, Z};
     use super::Registers;
-    use crate::gbmode::GbMode;
+    use crate::gbmode::{GbMode, Model};
 
     #[test]
     fn wide_registers() {
-        let mut reg = Registers::new(GbMode::Classic);
+        let mut reg = Registers::new(Model::Dmg, GbMode::Classic);
         reg.a = 0x12;
         reg.setf(0x23);
         reg.b = 0x34;
@@ -159,7 +183,7 @@ mod test {
 
     #[test]
     fn flags() {
-        let mut reg = Registers::new(GbMode::Classic);
+        let mut reg = Registers::new(Model::Dmg, GbMode::Classic);
         let flags = [C, H, N, Z];
 
         // Check if initially the flags are good
@@ -178,7 +202,7 @@ mod test {
 
     #[test]
     fn hl_special() {
-        let mut reg = Registers::new(GbMode::Classic);
+        let mut reg = Registers::new(Model::Dmg, GbMode::Classic);
         reg.sethl(0x1234);
         assert_eq!(reg.hl(), 0x1234);
         assert_eq!(reg.hld(), 0x1234);
@@ -189,4 +213,31 @@ mod test {
         assert_eq!(reg.hli(), 0x1233);
         assert_eq!(reg.hl(), 0x1234);
     }
+
+    #[test]
+    fn initial_registers_per_model() {
+        // AF, BC, DE and HL as listed by the Pan Docs
+        let (classic, compat, color) = (GbMode::Classic, GbMode::ColorAsClassic, GbMode::Color);
+        let expected = [
+            (Model::Dmg, classic, [0x01B0, 0x0013, 0x00D8, 0x014D]),
+            (Model::Mgb, classic, [0xFFB0, 0x0013, 0x00D8, 0x014D]),
+            (Model::Sgb, classic, [0x0100, 0x0014, 0x0000, 0xC060]),
+            (Model::Cgb, color, [0x1180, 0x0000, 0xFF56, 0x000D]),
+            (Model::Cgb, compat, [0x1180, 0x0000, 0x0008, 0x007C]),
+            (Model::Agb, color, [0x1100, 0x0100, 0xFF56, 0x000D]),
+            (Model::Agb, compat, [0x1100, 0x0100, 0x0008, 0x007C]),
+        ];
+        for &(model, mode, [af, bc, de, hl]) in expected.iter() {
+            let reg = Registers::new(model, mode);
+            assert_eq!([reg.af(), reg.bc(), reg.de(), reg.hl()], [af, bc, de, hl]);
+            assert_eq!((reg.pc, reg.sp), (0x0100, 0xFFFE));
+        }
+    }
+
+    #[test]
+    fn initial_a_per_mode() {
+        assert_eq!(Registers::new(Model::Dmg, GbMode::Classic).a, 0x01);
+        assert_eq!(Registers::new(Model::Cgb, GbMode::ColorAsClassic).a, 0x11);
+        assert_eq!(Registers::new(Model::Cgb, GbMode::Color).a, 0x11);
+    }
 }
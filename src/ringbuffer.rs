@@ -0,0 +1,139 @@
+use crate::sound::AudioPlayer;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// An `AudioPlayer` that keeps the samples in a buffer for the host to pull from
+///
+/// Clones share the same buffer: give one to `Device::enable_audio` and call `pop` on another
+/// from the audio callback of the host. When the buffer is full, the oldest samples are dropped,
+/// so a host that falls behind only loses audio that would have been played late anyway.
+#[derive(Clone)]
+pub struct RingBufferAudioPlayer {
+    buffer: Arc<Mutex<VecDeque<(f32, f32)>>>,
+    capacity: Arc<Mutex<usize>>,
+    sample_rate: u32,
+}
+
+impl RingBufferAudioPlayer {
+    /// `capacity` is the number of stereo frames kept in the buffer
+    pub fn new(sample_rate: u32, capacity: usize) -> RingBufferAudioPlayer {
+        RingBufferAudioPlayer {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity: Arc::new(Mutex::new(capacity)),
+            sample_rate,
+        }
+    }
+
+    /// Fills `out` with interleaved left and right samples and returns how many values were
+    /// written. The rest of `out` is filled with silence.
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let mut buffer = self.buffer.lock().unwrap();
+        let frames = (out.len() / 2).min(buffer.len());
+        for (frame, (l, r)) in out.chunks_exact_mut(2).zip(buffer.drain(..frames)) {
+            frame[0] = l;
+            frame[1] = r;
+        }
+        for v in out[frames * 2..].iter_mut() {
+            *v = 0.0;
+        }
+        frames * 2
+    }
+
+    /// The number of stereo frames waiting in the buffer
+    pub fn len(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl AudioPlayer for RingBufferAudioPlayer {
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        debug_assert!(left_channel.len() == right_channel.len());
+
+        let capacity = *self.capacity.lock().unwrap();
+        let mut buffer = self.buffer.lock().unwrap();
+        for (&l, &r) in left_channel.iter().zip(right_channel) {
+            if buffer.len() >= capacity {
+                buffer.pop_front();
+            }
+            buffer.push_back((l, r));
+        }
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn set_buffer_target(&mut self, frames: usize) {
+        *self.capacity.lock().unwrap() = frames;
+        let mut buffer = self.buffer.lock().unwrap();
+        while buffer.len() > frames {
+            buffer.pop_front();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.buffer.lock().unwrap().clear();
+    }
+}
+
+/// Collects every sample for `Device::tick_audio_to`, which runs the emulation until enough are
+/// there. As the host asks for audio whenever it needs it, the player always counts as waiting.
+pub(crate) struct PullAudioPlayer {
+    pub buffer: Arc<Mutex<VecDeque<(f32, f32)>>>,
+    pub sample_rate: u32,
+}
+
+impl AudioPlayer for PullAudioPlayer {
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for (&l, &r) in left_channel.iter().zip(right_channel) {
+            buffer.push_back((l, r));
+        }
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RingBufferAudioPlayer;
+    use crate::sound::AudioPlayer;
+
+    #[test]
+    fn interleaved_pop() {
+        let mut player = RingBufferAudioPlayer::new(44100, 4);
+        let mut host = player.clone();
+
+        player.play(&[0.1, 0.2, 0.3], &[-0.1, -0.2, -0.3]);
+        let mut out = [1.0; 4];
+        assert_eq!(host.pop(&mut out), 4);
+        assert_eq!(out, [0.1, -0.1, 0.2, -0.2]);
+
+        // Only one frame is left, the rest is silence
+        let mut out = [1.0; 4];
+        assert_eq!(host.pop(&mut out), 2);
+        assert_eq!(out, [0.3, -0.3, 0.0, 0.0]);
+        assert!(player.underflowed());
+
+        // The oldest frames make room for new ones
+        player.play(&[1.0, 2.0, 3.0, 4.0, 5.0], &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(host.len(), 4);
+        let mut out = [0.0; 2];
+        host.pop(&mut out);
+        assert_eq!(out, [2.0, 2.0]);
+    }
+}
@@ -0,0 +1,138 @@
+//! File access for plugins, confined to a single root directory.
+//!
+//! Plugins are not trusted. A plugin that was granted the `filesystem` permission may read and
+//! write files, but only below the sandbox root. Every path it hands us is treated as relative to
+//! that root: absolute paths are rejected, `..` may not climb above the root, and the resolved
+//! location is canonicalized so a symlink inside the root cannot point outside of it.
+//!
+//! This does not protect against a plugin filling the disk, nor against another process swapping
+//! directories underneath us between validation and use.
+
+use crate::StrResult;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+pub struct FileSandbox {
+    root: PathBuf,
+}
+
+impl FileSandbox {
+    /// Creates the root directory if needed
+    pub fn new(root: &Path) -> StrResult<FileSandbox> {
+        fs::create_dir_all(root).map_err(|_| "Could not create sandbox directory")?;
+        let root = root
+            .canonicalize()
+            .map_err(|_| "Could not resolve sandbox directory")?;
+        Ok(FileSandbox { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn resolve(&self, path: &str) -> StrResult<PathBuf> {
+        let mut relative = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(c) => relative.push(c),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err("Path escapes the sandbox directory");
+                    }
+                }
+                Component::RootDir | Component::Prefix(..) => {
+                    return Err("Absolute paths are not allowed in the sandbox")
+                }
+            }
+        }
+        if relative.as_os_str().is_empty() {
+            return Err("Path does not name a file");
+        }
+
+        let resolved = self.root.join(relative);
+        self.check_inside(&resolved)?;
+        Ok(resolved)
+    }
+
+    pub fn read(&self, path: &str) -> StrResult<Vec<u8>> {
+        let resolved = self.resolve(path)?;
+        fs::read(resolved).map_err(|_| "Could not read file")
+    }
+
+    pub fn write(&self, path: &str, data: &[u8]) -> StrResult<()> {
+        let resolved = self.resolve(path)?;
+        if let Some(parent) = resolved.parent() {
+            fs::create_dir_all(parent).map_err(|_| "Could not create directory")?;
+        }
+        // The directories may only exist now, so check again for symlinks
+        self.check_inside(&resolved)?;
+        fs::write(resolved, data).map_err(|_| "Could not write file")
+    }
+
+    // Follows symlinks on the deepest existing ancestor of `path`. A dangling symlink counts as
+    // existing, and fails to resolve, so nothing can be created through it.
+    fn check_inside(&self, path: &Path) -> StrResult<()> {
+        let mut existing = path;
+        while fs::symlink_metadata(existing).is_err() {
+            existing = match existing.parent() {
+                Some(p) => p,
+                None => return Err("Path escapes the sandbox directory"),
+            };
+        }
+        let canonical = existing
+            .canonicalize()
+            .map_err(|_| "Could not resolve path")?;
+        match canonical.starts_with(&self.root) {
+            true => Ok(()),
+            false => Err("Path escapes the sandbox directory"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FileSandbox;
+    use std::fs;
+
+    #[test]
+    fn rejects_path_traversal() {
+        let base = std::env::temp_dir().join(format!("rboy_sandbox_{}", std::process::id()));
+        let root = base.join("plugin_data");
+        let sandbox = FileSandbox::new(&root).unwrap();
+
+        assert!(sandbox.write("../escaped.txt", b"nope").is_err());
+        assert!(sandbox.write("sub/../../escaped.txt", b"nope").is_err());
+        assert!(!base.join("escaped.txt").exists());
+
+        let absolute = base.join("absolute.txt");
+        assert!(sandbox.write(absolute.to_str().unwrap(), b"nope").is_err());
+        assert!(!absolute.exists());
+
+        sandbox.write("sub/../inside.txt", b"ok").unwrap();
+        assert_eq!(sandbox.read("inside.txt").unwrap(), b"ok");
+        assert!(root.join("inside.txt").exists());
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_dangling_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let base = std::env::temp_dir().join(format!("rboy_sandbox_link_{}", std::process::id()));
+        let root = base.join("plugin_data");
+        let sandbox = FileSandbox::new(&root).unwrap();
+        symlink(base.join("outside.txt"), root.join("link.txt")).unwrap();
+        symlink(base.join("outside"), root.join("dir")).unwrap();
+
+        assert!(sandbox.write("link.txt", b"nope").is_err());
+        assert!(sandbox.write("dir/file.txt", b"nope").is_err());
+        assert!(sandbox.read("link.txt").is_err());
+        assert!(!base.join("outside.txt").exists());
+        assert!(!base.join("outside").exists());
+
+        let _ = fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,27 @@
+//! Serde only implements its traits for arrays of up to 32 elements, this handles the larger
+//! memories with `#[serde(with = "crate::serde_array")]`.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn serialize<S, T, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    array[..].serialize(serializer)
+}
+
+pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let items = Vec::<T>::deserialize(deserializer)?;
+    let len = items.len();
+    items
+        .try_into()
+        .map_err(|_| D::Error::invalid_length(len, &"an array of the right size"))
+}
@@ -1,13 +1,33 @@
+use alloc::boxed::Box;
+
 pub type SerialCallback<'a> = Box<dyn FnMut(u8) -> Option<u8> + Send + 'a>;
 
+// Cycles per transferred bit, for the 8192 Hz and the CGB 262144 Hz internal clock
+const NORMAL_BIT_CYCLES: u32 = 512;
+const FAST_BIT_CYCLES: u32 = 16;
+
 fn noop(_: u8) -> Option<u8> {
     None
 }
 
+/// The registers and the transfer in progress, without what is connected
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SerialState {
+    data: u8,
+    control: u8,
+    incoming: Option<u8>,
+    remaining: u32,
+    interrupt: u8,
+}
+
 pub struct Serial<'a> {
     data: u8,
     control: u8,
     callback: SerialCallback<'a>,
+    // The byte received by the transfer in progress, and the cycles until it completes
+    incoming: Option<u8>,
+    remaining: u32,
     pub interrupt: u8,
 }
 
@@ -17,6 +37,8 @@ impl<'a> Serial<'a> {
             data: 0,
             control: 0,
             callback: cb,
+            incoming: None,
+            remaining: 0,
             interrupt: 0,
         }
     }
@@ -27,13 +49,12 @@ impl<'a> Serial<'a> {
             0xFF02 => {
                 self.control = v;
                 if v & 0x81 == 0x81 {
-                    match (self.callback)(self.data) {
-                        Some(v) => {
-                            self.data = v;
-                            self.interrupt = 0x8
-                        }
-                        None => {}
-                    }
+                    self.incoming = (self.callback)(self.data);
+                    let bit_cycles = match v & 0x02 {
+                        0x02 => FAST_BIT_CYCLES,
+                        _ => NORMAL_BIT_CYCLES,
+                    };
+                    self.remaining = 8 * bit_cycles;
                 }
             }
             _ => panic!("Serial does not handle address {:4X} (write)", a),
@@ -48,6 +69,54 @@ impl<'a> Serial<'a> {
         }
     }
 
+    /// How many CPU cycles until the transfer in progress finishes and requests an interrupt
+    pub fn cycles_until_interrupt(&self) -> Option<u32> {
+        self.incoming.map(|_| self.remaining)
+    }
+
+    /// Takes the number of CPU cycles, so transfers take half as long in double speed mode
+    pub fn do_cycle(&mut self, ticks: u32) {
+        if self.incoming.is_none() {
+            return;
+        }
+        if ticks < self.remaining {
+            self.remaining -= ticks;
+            return;
+        }
+        self.remaining = 0;
+        if let Some(v) = self.incoming.take() {
+            self.data = v;
+            self.control &= 0x7F;
+            self.interrupt = 0x8;
+        }
+    }
+
+    pub fn state(&self) -> SerialState {
+        SerialState {
+            data: self.data,
+            control: self.control,
+            incoming: self.incoming,
+            remaining: self.remaining,
+            interrupt: self.interrupt,
+        }
+    }
+
+    pub fn load_state(&mut self, state: SerialState) {
+        self.data = state.data;
+        self.control = state.control;
+        self.incoming = state.incoming;
+        self.remaining = state.remaining;
+        self.interrupt = state.interrupt;
+    }
+
+    /// A copy of the serial port with nothing connected
+    pub fn fork(&self) -> Serial<'a> {
+        Serial {
+            callback: Box::new(noop),
+            ..*self
+        }
+    }
+
     pub fn set_callback(&mut self, cb: SerialCallback<'static>) {
         self.callback = cb;
     }
@@ -63,7 +132,44 @@ impl Serial<'static> {
             data: 0,
             control: 0,
             callback: Box::new(noop),
+            incoming: None,
+            remaining: 0,
             interrupt: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Serial;
+
+    fn start_transfer(control: u8) -> Serial<'static> {
+        let mut serial = Serial::new_with_callback(Box::new(|v| Some(v ^ 0xFF)));
+        serial.wb(0xFF01, 0x12);
+        serial.wb(0xFF02, control);
+        serial
+    }
+
+    #[test]
+    fn internal_clock_transfer_duration() {
+        let mut serial = start_transfer(0x81);
+        serial.do_cycle(8 * 512 - 1);
+        assert_eq!(serial.interrupt, 0);
+        assert_eq!(serial.rb(0xFF01), 0x12);
+        assert_eq!(serial.rb(0xFF02) & 0x80, 0x80);
+
+        serial.do_cycle(1);
+        assert_eq!(serial.interrupt, 0x8);
+        assert_eq!(serial.rb(0xFF01), 0xED);
+        assert_eq!(serial.rb(0xFF02) & 0x80, 0);
+    }
+
+    #[test]
+    fn fast_clock_transfer_duration() {
+        let mut serial = start_transfer(0x83);
+        serial.do_cycle(8 * 16 - 1);
+        assert_eq!(serial.interrupt, 0);
+        serial.do_cycle(1);
+        assert_eq!(serial.interrupt, 0x8);
+    }
+}
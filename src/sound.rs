@@ -1,3 +1,4 @@
+use crate::StrResult;
 use blip_buf::BlipBuf;
 
 const WAVE_PATTERN: [[i32; 8]; 4] = [
@@ -15,10 +16,139 @@ const SWEEP_DELAY_ZERO_PERIOD: u8 = 8;
 // need 4 since we run the wave after delay == 0, instead of at delay == 0
 const WAVE_INITIAL_DELAY: u32 = 4;
 
+// Fraction of the high-pass capacitor charge that remains after one clock
+const HIGHPASS_CHARGE_DMG: f64 = 0.999958;
+const HIGHPASS_CHARGE_CGB: f64 = 0.998943;
+
+// Wave RAM after power on. It differs between DMG units, this is a commonly measured one.
+const WAVERAM_DMG: [u8; 16] = [
+    0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA,
+];
+const WAVERAM_CGB: [u8; 16] = [
+    0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF,
+];
+
 pub trait AudioPlayer: Send {
     fn play(&mut self, left_channel: &[f32], right_channel: &[f32]);
     fn samples_rate(&self) -> u32;
     fn underflowed(&self) -> bool;
+
+    /// Limits how many stereo frames the player keeps buffered. One second of audio is
+    /// `samples_rate()` frames, so a smaller target lowers latency at the risk of underflows.
+    fn set_buffer_target(&mut self, _frames: usize) {}
+
+    /// Drops the samples that were not played yet
+    fn clear(&mut self) {}
+}
+
+/// Receives the samples of a single channel before panning and master volume are applied
+pub type ChannelTap = Box<dyn FnMut(&[f32]) + Send>;
+
+/// The internal state of the sound hardware that is not visible through its registers
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SoundState {
+    pub on: bool,
+    /// Position in the 512 Hz frame sequencer, 0 to 7
+    pub frame_step: u8,
+    pub time: u32,
+    pub prev_time: u32,
+    pub next_time: u32,
+    pub channel1: SquareState,
+    pub channel2: SquareState,
+    pub channel3: WaveState,
+    pub channel4: NoiseState,
+    pub volume_left: u8,
+    pub volume_right: u8,
+    pub reg_vin_to_so: u8,
+    pub reg_ff25: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnvelopeState {
+    pub period: u8,
+    pub goes_up: bool,
+    pub delay: u8,
+    pub initial_volume: u8,
+    pub volume: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LengthState {
+    pub enabled: bool,
+    pub value: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SquareState {
+    pub active: bool,
+    pub dac_enabled: bool,
+    pub duty: u8,
+    pub phase: u8,
+    pub length: LengthState,
+    pub frequency: u16,
+    /// Clocks until the next step of the waveform
+    pub delay: u32,
+    pub sweep_enabled: bool,
+    pub sweep_frequency: u16,
+    pub sweep_delay: u8,
+    pub sweep_period: u8,
+    pub sweep_shift: u8,
+    pub sweep_negate: bool,
+    pub sweep_did_negate: bool,
+    pub envelope: EnvelopeState,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WaveState {
+    pub active: bool,
+    pub dac_enabled: bool,
+    pub length: LengthState,
+    pub frequency: u16,
+    pub delay: u32,
+    pub volume_shift: u8,
+    pub waveram: [u8; 16],
+    /// Position of the sample being played, 0 to 31
+    pub current_wave: u8,
+    pub sample_recently_accessed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoiseState {
+    pub active: bool,
+    pub dac_enabled: bool,
+    pub reg_ff22: u8,
+    pub length: LengthState,
+    pub envelope: EnvelopeState,
+    /// The linear feedback shift register
+    pub lfsr: u16,
+    pub delay: u32,
+}
+
+struct HighPassFilter {
+    charge_factor: f32,
+    capacitor: f32,
+}
+
+impl HighPassFilter {
+    fn new(charge_per_clock: f64, samples_rate: u32) -> HighPassFilter {
+        HighPassFilter {
+            charge_factor: charge_per_clock.powf(CLOCKS_PER_SECOND as f64 / samples_rate as f64)
+                as f32,
+            capacitor: 0.0,
+        }
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
 }
 
 struct VolumeEnvelope {
@@ -40,6 +170,24 @@ impl VolumeEnvelope {
         }
     }
 
+    fn state(&self) -> EnvelopeState {
+        EnvelopeState {
+            period: self.period,
+            goes_up: self.goes_up,
+            delay: self.delay,
+            initial_volume: self.initial_volume,
+            volume: self.volume,
+        }
+    }
+
+    fn load_state(&mut self, state: &EnvelopeState) {
+        self.period = state.period;
+        self.goes_up = state.goes_up;
+        self.delay = state.delay;
+        self.initial_volume = state.initial_volume;
+        self.volume = state.volume;
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF12 | 0xFF17 | 0xFF21 => {
@@ -100,6 +248,18 @@ impl LengthCounter {
         self.value > 0
     }
 
+    fn state(&self) -> LengthState {
+        LengthState {
+            enabled: self.enabled,
+            value: self.value,
+        }
+    }
+
+    fn load_state(&mut self, state: &LengthState) {
+        self.enabled = state.enabled;
+        self.value = state.value;
+    }
+
     fn extra_step(frame_step: u8) -> bool {
         // if the last 'step' was a length step return true
         // this is equivalent to (next) frame_step is not a length step
@@ -182,10 +342,57 @@ impl SquareChannel {
         }
     }
 
+    fn state(&self) -> SquareState {
+        SquareState {
+            active: self.active,
+            dac_enabled: self.dac_enabled,
+            duty: self.duty,
+            phase: self.phase,
+            length: self.length.state(),
+            frequency: self.frequency,
+            delay: self.delay,
+            sweep_enabled: self.sweep_enabled,
+            sweep_frequency: self.sweep_frequency,
+            sweep_delay: self.sweep_delay,
+            sweep_period: self.sweep_period,
+            sweep_shift: self.sweep_shift,
+            sweep_negate: self.sweep_negate,
+            sweep_did_negate: self.sweep_did_negate,
+            envelope: self.volume_envelope.state(),
+        }
+    }
+
+    fn load_state(&mut self, state: &SquareState) {
+        self.active = state.active;
+        self.dac_enabled = state.dac_enabled;
+        self.duty = state.duty & 3;
+        self.phase = state.phase % 8;
+        self.length.load_state(&state.length);
+        self.frequency = state.frequency;
+        self.calculate_period();
+        self.delay = state.delay;
+        self.sweep_enabled = state.sweep_enabled;
+        self.sweep_frequency = state.sweep_frequency;
+        self.sweep_delay = state.sweep_delay;
+        self.sweep_period = state.sweep_period;
+        self.sweep_shift = state.sweep_shift;
+        self.sweep_negate = state.sweep_negate;
+        self.sweep_did_negate = state.sweep_did_negate;
+        self.volume_envelope.load_state(&state.envelope);
+        // The output buffer starts out empty, so the next sample is a full step from silence
+        self.last_amp = 0;
+        self.blip.clear();
+    }
+
     fn on(&self) -> bool {
         self.active
     }
 
+    // The 4-bit output before the DAC, as read back through PCM12 and PCM34
+    fn digital_output(&self) -> u8 {
+        self.last_amp.max(0) as u8
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF10 => {
@@ -369,7 +576,10 @@ impl WaveChannel {
             last_amp: 0,
             delay: 0,
             volume_shift: 0,
-            waveram: [0; 16],
+            waveram: match dmg_mode {
+                true => WAVERAM_DMG,
+                false => WAVERAM_CGB,
+            },
             current_wave: 0,
             dmg_mode: dmg_mode,
             sample_recently_accessed: false,
@@ -377,6 +587,35 @@ impl WaveChannel {
         }
     }
 
+    fn state(&self) -> WaveState {
+        WaveState {
+            active: self.active,
+            dac_enabled: self.dac_enabled,
+            length: self.length.state(),
+            frequency: self.frequency,
+            delay: self.delay,
+            volume_shift: self.volume_shift,
+            waveram: self.waveram,
+            current_wave: self.current_wave,
+            sample_recently_accessed: self.sample_recently_accessed,
+        }
+    }
+
+    fn load_state(&mut self, state: &WaveState) {
+        self.active = state.active;
+        self.dac_enabled = state.dac_enabled;
+        self.length.load_state(&state.length);
+        self.frequency = state.frequency;
+        self.calculate_period();
+        self.delay = state.delay;
+        self.volume_shift = state.volume_shift & 0b11;
+        self.waveram = state.waveram;
+        self.current_wave = state.current_wave % 32;
+        self.sample_recently_accessed = state.sample_recently_accessed;
+        self.last_amp = 0;
+        self.blip.clear();
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF1A => (if self.dac_enabled { 0x80 } else { 0 }) | 0x7F,
@@ -456,6 +695,11 @@ impl WaveChannel {
         self.active
     }
 
+    // The wave amplitude is scaled by 4, see `run`
+    fn digital_output(&self) -> u8 {
+        (self.last_amp.max(0) >> 2) as u8
+    }
+
     fn run(&mut self, start_time: u32, end_time: u32) {
         self.sample_recently_accessed = false;
         if !self.active || self.period == 0 {
@@ -569,6 +813,31 @@ impl NoiseChannel {
         }
     }
 
+    fn state(&self) -> NoiseState {
+        NoiseState {
+            active: self.active,
+            dac_enabled: self.dac_enabled,
+            reg_ff22: self.reg_ff22,
+            length: self.length.state(),
+            envelope: self.volume_envelope.state(),
+            lfsr: self.state,
+            delay: self.delay,
+        }
+    }
+
+    fn load_state(&mut self, state: &NoiseState) {
+        self.active = state.active;
+        self.dac_enabled = state.dac_enabled;
+        // Recomputes the period and shift width from the register
+        self.wb(0xFF22, state.reg_ff22, 0);
+        self.length.load_state(&state.length);
+        self.volume_envelope.load_state(&state.envelope);
+        self.state = state.lfsr;
+        self.delay = state.delay;
+        self.last_amp = 0;
+        self.blip.clear();
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF20 => 0xFF,
@@ -619,6 +888,10 @@ impl NoiseChannel {
         self.active
     }
 
+    fn digital_output(&self) -> u8 {
+        self.last_amp.max(0) as u8
+    }
+
     fn run(&mut self, start_time: u32, end_time: u32) {
         if !self.active {
             if self.last_amp != 0 {
@@ -656,6 +929,23 @@ impl NoiseChannel {
     }
 }
 
+// Where the sound of a fork goes
+struct SilentPlayer {
+    samples_rate: u32,
+}
+
+impl AudioPlayer for SilentPlayer {
+    fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        false
+    }
+}
+
 pub struct Sound {
     on: bool,
     time: u32,
@@ -673,6 +963,13 @@ pub struct Sound {
     reg_ff25: u8,
     need_sync: bool,
     dmg_mode: bool,
+    highpass: bool,
+    muted: bool,
+    speed: f32,
+    output_credit: f32,
+    highpass_left: HighPassFilter,
+    highpass_right: HighPassFilter,
+    taps: [Option<ChannelTap>; 4],
     player: Box<dyn AudioPlayer>,
 }
 
@@ -693,6 +990,11 @@ impl Sound {
 
         let output_period =
             (OUTPUT_SAMPLE_COUNT as u64 * CLOCKS_PER_SECOND as u64) / player.samples_rate() as u64;
+        let highpass_charge = if dmg_mode {
+            HIGHPASS_CHARGE_DMG
+        } else {
+            HIGHPASS_CHARGE_CGB
+        };
 
         Sound {
             on: false,
@@ -711,10 +1013,32 @@ impl Sound {
             reg_ff25: 0x00,
             need_sync: false,
             dmg_mode: dmg_mode,
+            highpass: true,
+            muted: false,
+            speed: 1.0,
+            output_credit: 0.0,
+            highpass_left: HighPassFilter::new(highpass_charge, player.samples_rate()),
+            highpass_right: HighPassFilter::new(highpass_charge, player.samples_rate()),
+            taps: [None, None, None, None],
             player: player,
         }
     }
 
+    /// The same sound hardware, playing to a player that drops all samples. Channel taps are
+    /// not copied.
+    pub fn fork(&self) -> Sound {
+        let player = SilentPlayer {
+            samples_rate: self.player.samples_rate(),
+        };
+        let mut sound = Sound::new_internal(Box::new(player), self.dmg_mode);
+        // The state of a sound with the same sample rate always loads
+        let _ = sound.load_state(&self.save_state());
+        sound.highpass = self.highpass;
+        sound.muted = self.muted;
+        sound.speed = self.speed;
+        sound
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
         // self.run();
         let v = match a {
@@ -722,7 +1046,7 @@ impl Sound {
             0xFF16..=0xFF19 => self.channel2.rb(a),
             0xFF1A..=0xFF1E => self.channel3.rb(a),
             0xFF20..=0xFF23 => self.channel4.rb(a),
-            0xFF24 => ((self.volume_right & 7) << 4) | (self.volume_left & 7) | self.reg_vin_to_so,
+            0xFF24 => ((self.volume_left & 7) << 4) | (self.volume_right & 7) | self.reg_vin_to_so,
             0xFF25 => self.reg_ff25,
             0xFF26 => {
                 (if self.on { 0x80 } else { 0x00 }
@@ -733,6 +1057,9 @@ impl Sound {
                     | if self.channel1.on() { 0x1 } else { 0x0 })
             }
             0xFF30..=0xFF3F => self.channel3.rb(a),
+            // PCM12 and PCM34, only mapped on the color models
+            0xFF76 => self.channel1.digital_output() | (self.channel2.digital_output() << 4),
+            0xFF77 => self.channel3.digital_output() | (self.channel4.digital_output() << 4),
             _ => 0xFF,
         };
         return v;
@@ -762,8 +1089,8 @@ impl Sound {
             0xFF1A..=0xFF1E => self.channel3.wb(a, v, self.frame_step),
             0xFF20..=0xFF23 => self.channel4.wb(a, v, self.frame_step),
             0xFF24 => {
-                self.volume_left = v & 0x7;
-                self.volume_right = (v >> 4) & 0x7;
+                self.volume_left = (v >> 4) & 0x7;
+                self.volume_right = v & 0x7;
                 self.reg_vin_to_so = v & 0x88;
             }
             0xFF25 => self.reg_ff25 = v,
@@ -798,10 +1125,118 @@ impl Sound {
         }
     }
 
+    /// Runs the channels up to the current time, which is otherwise only done on writes and
+    /// for every chunk of output
+    pub fn catch_up(&mut self) {
+        self.run();
+    }
+
+    /// Drops the samples that were not played yet, and waits for the player to run out if it
+    /// can not drop them
     pub fn sync(&mut self) {
+        self.clear_buffers();
+        self.player.clear();
         self.need_sync = true;
     }
 
+    pub fn set_highpass(&mut self, enabled: bool) {
+        self.highpass = enabled;
+    }
+
+    pub fn set_buffer_target(&mut self, frames: usize) {
+        self.player.set_buffer_target(frames);
+    }
+
+    pub fn clear_player(&mut self) {
+        self.player.clear();
+    }
+
+    /// While muted the player keeps receiving samples, but only silence
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Whether the sound hardware is powered on with NR52. While off, no samples are produced.
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// When running faster than real time, only every `speed`th chunk of samples is played so
+    /// the pitch stays the same. At 0.0, for an uncapped speed, chunks are only played when the
+    /// player runs out. Below 1.0 everything is played and the player underflows.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+        self.output_credit = 0.0;
+    }
+
+    fn keep_output(&mut self) -> bool {
+        if self.speed == 0.0 {
+            return self.player.underflowed();
+        }
+        self.output_credit += 1.0 / self.speed;
+        if self.output_credit < 1.0 {
+            return false;
+        }
+        self.output_credit = (self.output_credit - 1.0).min(1.0);
+        true
+    }
+
+    pub fn set_channel_tap(&mut self, channel: usize, tap: ChannelTap) {
+        self.taps[channel] = Some(tap);
+    }
+
+    pub fn unset_channel_tap(&mut self, channel: usize) {
+        self.taps[channel] = None;
+    }
+
+    pub fn save_state(&self) -> SoundState {
+        SoundState {
+            on: self.on,
+            frame_step: self.frame_step,
+            time: self.time,
+            prev_time: self.prev_time,
+            next_time: self.next_time,
+            channel1: self.channel1.state(),
+            channel2: self.channel2.state(),
+            channel3: self.channel3.state(),
+            channel4: self.channel4.state(),
+            volume_left: self.volume_left,
+            volume_right: self.volume_right,
+            reg_vin_to_so: self.reg_vin_to_so,
+            reg_ff25: self.reg_ff25,
+        }
+    }
+
+    /// Samples that were not yet handed to the player are dropped
+    pub fn load_state(&mut self, state: &SoundState) -> StrResult<()> {
+        if state.prev_time > state.time
+            || state.time > self.output_period
+            || state.next_time < state.prev_time
+            || state.next_time > state.prev_time + CLOCKS_PER_FRAME
+        {
+            return Err("Invalid sound state timing");
+        }
+
+        self.on = state.on;
+        self.frame_step = state.frame_step % 8;
+        self.time = state.time;
+        self.prev_time = state.prev_time;
+        self.next_time = state.next_time;
+        self.channel1.load_state(&state.channel1);
+        self.channel2.load_state(&state.channel2);
+        self.channel3.load_state(&state.channel3);
+        self.channel4.load_state(&state.channel4);
+        self.volume_left = state.volume_left & 7;
+        self.volume_right = state.volume_right & 7;
+        self.reg_vin_to_so = state.reg_vin_to_so & 0x88;
+        self.reg_ff25 = state.reg_ff25;
+        Ok(())
+    }
+
     fn do_output(&mut self) {
         self.run();
         debug_assert!(self.time == self.prev_time);
@@ -813,7 +1248,9 @@ impl Sound {
         self.time = 0;
         self.prev_time = 0;
 
-        if !self.need_sync || self.player.underflowed() {
+        if !self.keep_output() {
+            self.clear_buffers();
+        } else if !self.need_sync || self.player.underflowed() {
             self.need_sync = false;
             self.mix_buffers();
         } else {
@@ -868,8 +1305,12 @@ impl Sound {
 
         let mut outputted = 0;
 
-        let left_vol = (self.volume_left as f32 / 7.0) * (1.0 / 15.0) * 0.25;
-        let right_vol = (self.volume_right as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let tap_vol = (1.0 / 15.0) * 0.25;
+
+        // A master volume of 0 is not muted, but 1/8th of the full volume
+        let mute = if self.muted { 0.0 } else { 1.0 };
+        let left_vol = ((self.volume_left + 1) as f32 / 8.0) * (1.0 / 15.0) * 0.25 * mute;
+        let right_vol = ((self.volume_right + 1) as f32 / 8.0) * (1.0 / 15.0) * 0.25 * mute;
 
         while outputted < sample_count {
             let buf_left = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
@@ -877,6 +1318,7 @@ impl Sound {
             let buf = &mut [0i16; OUTPUT_SAMPLE_COUNT + 10];
 
             let count1 = self.channel1.blip.read_samples(buf, false);
+            feed_tap(&mut self.taps[0], &buf[..count1], tap_vol);
             for (i, v) in buf[..count1].iter().enumerate() {
                 if self.reg_ff25 & 0x10 == 0x10 {
                     buf_left[i] += *v as f32 * left_vol;
@@ -887,6 +1329,7 @@ impl Sound {
             }
 
             let count2 = self.channel2.blip.read_samples(buf, false);
+            feed_tap(&mut self.taps[1], &buf[..count2], tap_vol);
             for (i, v) in buf[..count2].iter().enumerate() {
                 if self.reg_ff25 & 0x20 == 0x20 {
                     buf_left[i] += *v as f32 * left_vol;
@@ -899,6 +1342,7 @@ impl Sound {
             // channel3 is the WaveChannel, that outputs samples with a 4x
             // increase in amplitude in order to avoid a loss of precision.
             let count3 = self.channel3.blip.read_samples(buf, false);
+            feed_tap(&mut self.taps[2], &buf[..count3], tap_vol / 4.0);
             for (i, v) in buf[..count3].iter().enumerate() {
                 if self.reg_ff25 & 0x40 == 0x40 {
                     buf_left[i] += ((*v as f32) / 4.0) * left_vol;
@@ -909,6 +1353,7 @@ impl Sound {
             }
 
             let count4 = self.channel4.blip.read_samples(buf, false);
+            feed_tap(&mut self.taps[3], &buf[..count4], tap_vol);
             for (i, v) in buf[..count4].iter().enumerate() {
                 if self.reg_ff25 & 0x80 == 0x80 {
                     buf_left[i] += *v as f32 * left_vol;
@@ -922,6 +1367,15 @@ impl Sound {
             debug_assert!(count1 == count3);
             debug_assert!(count1 == count4);
 
+            if self.highpass {
+                for v in buf_left[..count1].iter_mut() {
+                    *v = self.highpass_left.filter(*v);
+                }
+                for v in buf_right[..count1].iter_mut() {
+                    *v = self.highpass_right.filter(*v);
+                }
+            }
+
             self.player.play(&buf_left[..count1], &buf_right[..count1]);
 
             outputted += count1;
@@ -936,6 +1390,16 @@ impl Sound {
     }
 }
 
+fn feed_tap(tap: &mut Option<ChannelTap>, samples: &[i16], volume: f32) {
+    if let Some(ref mut tap) = tap {
+        let buf = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
+        for (out, v) in buf.iter_mut().zip(samples) {
+            *out = *v as f32 * volume;
+        }
+        tap(&buf[..samples.len()]);
+    }
+}
+
 fn create_blipbuf(samples_rate: u32) -> BlipBuf {
     // Create a BlipBuf which can hold OUTPUT_SAMPLE_COUNT + 1 samples.
     // Not sure why the +1 is needed. May need to correct the constant instead.
@@ -943,3 +1407,225 @@ fn create_blipbuf(samples_rate: u32) -> BlipBuf {
     blipbuf.set_rates(CLOCKS_PER_SECOND as f64, samples_rate as f64);
     blipbuf
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AudioPlayer, HighPassFilter, Sound, CLOCKS_PER_SECOND, HIGHPASS_CHARGE_DMG};
+    use std::sync::{Arc, Mutex};
+
+    type Samples = Arc<Mutex<(Vec<f32>, Vec<f32>)>>;
+
+    struct CapturePlayer {
+        samples: Samples,
+        target: Option<usize>,
+    }
+
+    impl AudioPlayer for CapturePlayer {
+        fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+            let mut samples = self.samples.lock().unwrap();
+            for (l, r) in left_channel.iter().zip(right_channel) {
+                if self.target.is_some_and(|t| samples.0.len() >= t) {
+                    return;
+                }
+                samples.0.push(*l);
+                samples.1.push(*r);
+            }
+        }
+
+        fn set_buffer_target(&mut self, frames: usize) {
+            self.target = Some(frames);
+        }
+
+        fn samples_rate(&self) -> u32 {
+            44100
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    fn capture_sound() -> (Sound, Samples) {
+        let samples = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+        let player = CapturePlayer {
+            samples: samples.clone(),
+            target: None,
+        };
+        let mut sound = Sound::new_cgb(Box::new(player));
+        sound.set_highpass(false);
+        sound.wb(0xFF26, 0x80);
+        (sound, samples)
+    }
+
+    fn run_tenth_second(sound: &mut Sound) {
+        for _ in 0..(CLOCKS_PER_SECOND / 10 / 16) {
+            sound.do_cycle(16);
+        }
+    }
+
+    fn play_channel2(sound: &mut Sound) {
+        sound.wb(0xFF16, 0x80);
+        sound.wb(0xFF17, 0xF0);
+        sound.wb(0xFF18, 0x00);
+        sound.wb(0xFF19, 0x87);
+        run_tenth_second(sound);
+    }
+
+    #[test]
+    fn pcm_registers() {
+        let (mut sound, _) = capture_sound();
+        assert_eq!(sound.rb(0xFF76), 0);
+        sound.wb(0xFF16, 0x80);
+        sound.wb(0xFF17, 0xF0);
+        sound.wb(0xFF19, 0x87);
+        sound.wb(0xFF21, 0xF0);
+        sound.wb(0xFF23, 0x80);
+        let (mut pcm12, mut pcm34) = (0, 0);
+        for _ in 0..(CLOCKS_PER_SECOND / 10 / 16) {
+            sound.do_cycle(16);
+            pcm12 |= sound.rb(0xFF76);
+            pcm34 |= sound.rb(0xFF77);
+        }
+        assert_eq!(pcm12, 0xF0);
+        assert_eq!(pcm34, 0xF0);
+    }
+
+    #[test]
+    fn initial_wave_ram() {
+        let (sound, _) = capture_sound();
+        let waveram: Vec<u8> = (0xFF30..=0xFF3F).map(|a| sound.rb(a)).collect();
+        assert_eq!(waveram, [0x00, 0xFF].repeat(8));
+    }
+
+    #[test]
+    fn panning_left_only() {
+        let (mut sound, samples) = capture_sound();
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x20);
+        play_channel2(&mut sound);
+
+        let samples = samples.lock().unwrap();
+        assert!(samples.0.iter().any(|v| *v != 0.0));
+        assert!(samples.1.iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn master_volume_per_side() {
+        let (mut sound, samples) = capture_sound();
+        sound.wb(0xFF24, 0x07);
+        sound.wb(0xFF25, 0x22);
+        assert_eq!(sound.rb(0xFF24), 0x07);
+        play_channel2(&mut sound);
+
+        let samples = samples.lock().unwrap();
+        let left: f32 = samples.0.iter().map(|v| v.abs()).sum();
+        let right: f32 = samples.1.iter().map(|v| v.abs()).sum();
+        assert!(left > 0.0);
+        assert!((right / left - 8.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn muted_plays_silence() {
+        let (mut sound, samples) = capture_sound();
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x22);
+        sound.set_muted(true);
+        play_channel2(&mut sound);
+        {
+            let samples = samples.lock().unwrap();
+            assert!(!samples.0.is_empty());
+            assert!(samples.0.iter().chain(&samples.1).all(|v| *v == 0.0));
+        }
+
+        sound.set_muted(false);
+        run_tenth_second(&mut sound);
+        let samples = samples.lock().unwrap();
+        assert!(samples.0.iter().any(|v| *v != 0.0));
+    }
+
+    #[test]
+    fn highpass_removes_dc_offset() {
+        let mut filter = HighPassFilter::new(HIGHPASS_CHARGE_DMG, 44100);
+
+        let first: f32 = (0..4410).map(|_| filter.filter(0.5)).sum::<f32>() / 4410.0;
+        let last: f32 = (0..4410).map(|_| filter.filter(0.5)).sum::<f32>() / 4410.0;
+
+        assert!(first > last);
+        assert!(last.abs() < 0.05, "DC offset not removed: {}", last);
+    }
+
+    #[test]
+    fn buffer_target_caps_player() {
+        let (mut sound, samples) = capture_sound();
+        sound.set_buffer_target(441);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x22);
+        play_channel2(&mut sound);
+
+        let samples = samples.lock().unwrap();
+        assert_eq!(samples.0.len(), 441);
+        assert_eq!(samples.1.len(), 441);
+    }
+
+    #[test]
+    fn channel_tap_receives_samples() {
+        let (mut sound, _) = capture_sound();
+        let tapped = Arc::new(Mutex::new(Vec::new()));
+        let tap_target = tapped.clone();
+        sound.set_channel_tap(
+            0,
+            Box::new(move |samples: &[f32]| tap_target.lock().unwrap().extend_from_slice(samples)),
+        );
+
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x00);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF13, 0x00);
+        sound.wb(0xFF14, 0x87);
+        run_tenth_second(&mut sound);
+
+        let tapped = tapped.lock().unwrap();
+        assert!(!tapped.is_empty());
+        assert!(tapped.iter().any(|v| *v != 0.0));
+    }
+
+    #[test]
+    fn state_round_trip_mid_note() {
+        let (mut sound, _) = capture_sound();
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x22);
+        // A slowly decaying note on channel 2, with the noise channel running alongside
+        sound.wb(0xFF16, 0x80);
+        sound.wb(0xFF17, 0xF3);
+        sound.wb(0xFF18, 0x34);
+        sound.wb(0xFF19, 0x85);
+        sound.wb(0xFF21, 0xF0);
+        sound.wb(0xFF22, 0x21);
+        sound.wb(0xFF23, 0x80);
+        for _ in 0..1234 {
+            sound.do_cycle(16);
+        }
+
+        let state = sound.save_state();
+        assert!(state.channel2.active);
+        assert_eq!(state.channel2.frequency, 0x534);
+        assert!(state.channel2.envelope.volume > 0);
+
+        let (mut restored, samples) = capture_sound();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+
+        // Both continue in lockstep: same phase, envelope and noise register
+        for _ in 0..10 {
+            run_tenth_second(&mut sound);
+            run_tenth_second(&mut restored);
+            assert_eq!(restored.save_state(), sound.save_state());
+        }
+        assert!(samples.lock().unwrap().1.iter().any(|v| *v != 0.0));
+
+        let mut broken = state.clone();
+        broken.prev_time = broken.time + 1;
+        assert!(restored.load_state(&broken).is_err());
+    }
+}
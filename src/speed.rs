@@ -0,0 +1,179 @@
+use crate::CPU_FREQUENCY;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Achieved emulation speed over the measurement window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpeedReport {
+    pub fps: f64,
+    /// Emulated time divided by real time, where 1.0 is full speed
+    pub speed: f64,
+}
+
+/// Measures frame rate and emulation speed over a rolling window, so a single slow frame does not
+/// make the numbers jump around
+pub struct SpeedMeter {
+    window: Duration,
+    samples: VecDeque<(Instant, u32, u32)>,
+}
+
+impl SpeedMeter {
+    pub fn new(window: Duration) -> SpeedMeter {
+        SpeedMeter {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Starts measuring again from `now`, for example after the emulator was paused
+    pub fn reset(&mut self, now: Instant) {
+        self.samples.clear();
+        self.samples.push_back((now, 0, 0));
+    }
+
+    /// Records that `cycles` were emulated and `frames` were drawn since the previous call
+    pub fn add(&mut self, now: Instant, cycles: u32, frames: u32) {
+        self.samples.push_back((now, cycles, frames));
+        while let Some(&(start, _, _)) = self.samples.front() {
+            if now.duration_since(start) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Returns `None` until enough time has passed to measure anything
+    pub fn report(&self) -> Option<SpeedReport> {
+        let (start, _, _) = *self.samples.front()?;
+        let (end, _, _) = *self.samples.back()?;
+        let elapsed = end.duration_since(start).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+
+        // The first sample covers the time before the window starts
+        let (cycles, frames) = self
+            .samples
+            .iter()
+            .skip(1)
+            .fold((0u64, 0u64), |(c, f), &(_, cycles, frames)| {
+                (c + cycles as u64, f + frames as u64)
+            });
+        Some(SpeedReport {
+            fps: frames as f64 / elapsed,
+            speed: cycles as f64 / (elapsed * CPU_FREQUENCY),
+        })
+    }
+}
+
+/// Display refresh rates this close to the emulated frame rate show every frame for one refresh
+pub const LOCK_TOLERANCE: f64 = 0.01;
+
+/// Paces frames to the refresh rate of the display instead of the emulated frame rate. When the
+/// two are close, every frame is shown for exactly one refresh and the game runs that much too
+/// fast or too slow. Otherwise frames are repeated or skipped to keep the emulated speed.
+#[derive(Clone, Debug)]
+pub struct FramePacer {
+    frame: Duration,
+    refresh: Duration,
+    locked: bool,
+    // Display time that was not used up by a frame yet
+    credit: Duration,
+}
+
+impl FramePacer {
+    pub fn new(frame: Duration, refresh: Duration) -> FramePacer {
+        let refresh = refresh.max(Duration::from_millis(1));
+        let ratio = refresh.as_secs_f64() / frame.as_secs_f64();
+        FramePacer {
+            frame,
+            refresh,
+            locked: (ratio - 1.0).abs() <= LOCK_TOLERANCE,
+            credit: Duration::ZERO,
+        }
+    }
+
+    /// Whether every frame is shown for one refresh
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// How many refreshes to wait for before the next frame. At 0 the next frame follows right
+    /// away, so only one of the two is seen.
+    pub fn refreshes_until_next_frame(&mut self) -> u32 {
+        if self.locked {
+            return 1;
+        }
+        let mut refreshes = 0;
+        while self.credit < self.frame {
+            self.credit += self.refresh;
+            refreshes += 1;
+        }
+        self.credit -= self.frame;
+        refreshes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FramePacer, SpeedMeter};
+    use crate::CPU_FREQUENCY;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rolling_window() {
+        let mut meter = SpeedMeter::new(Duration::from_secs(1));
+        let start = Instant::now();
+        let frame = Duration::from_millis(50);
+        let full_speed = (CPU_FREQUENCY * 0.05) as u32;
+
+        meter.reset(start);
+        assert!(meter.report().is_none());
+
+        // Start out at full speed, one frame per 50 ms
+        for i in 1..=20 {
+            meter.add(start + frame * i, full_speed, 1);
+        }
+        let report = meter.report().unwrap();
+        assert!((report.fps - 20.0).abs() < 0.01);
+        assert!((report.speed - 1.0).abs() < 0.01);
+
+        // Slow down to half speed until the old samples have left the window
+        for i in 21..=60 {
+            meter.add(start + frame * i, full_speed / 2, 1);
+        }
+        let report = meter.report().unwrap();
+        assert!((report.speed - 0.5).abs() < 0.01);
+
+        meter.reset(start + frame * 100);
+        assert!(meter.report().is_none());
+    }
+
+    #[test]
+    fn frame_pacing() {
+        let frame = Duration::from_micros(16_743);
+
+        // 60 Hz is close enough to lock
+        let mut pacer = FramePacer::new(frame, Duration::from_micros(16_667));
+        assert!(pacer.is_locked());
+        assert!((0..100).all(|_| pacer.refreshes_until_next_frame() == 1));
+
+        // 120 Hz shows every frame twice, and a third time now and then to keep the speed
+        let mut pacer = FramePacer::new(frame, Duration::from_micros(8_333));
+        assert!(!pacer.is_locked());
+        let refreshes: Vec<u32> = (0..1000)
+            .map(|_| pacer.refreshes_until_next_frame())
+            .collect();
+        assert!(refreshes.iter().all(|&n| n == 2 || n == 3));
+        let shown = Duration::from_micros(8_333) * refreshes.iter().sum::<u32>();
+        assert!((shown.as_secs_f64() - frame.as_secs_f64() * 1000.0).abs() < 0.01);
+
+        // 50 Hz skips a frame now and then
+        let mut pacer = FramePacer::new(frame, Duration::from_millis(20));
+        let refreshes: Vec<u32> = (0..100)
+            .map(|_| pacer.refreshes_until_next_frame())
+            .collect();
+        assert!(refreshes.contains(&0));
+        assert_eq!(refreshes.iter().sum::<u32>(), 84);
+    }
+}
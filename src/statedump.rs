@@ -0,0 +1,101 @@
+//! A summary of the machine for humans, to see where a game ended up when it crashed or hung.
+
+use crate::cpu::{InterruptState, TraceLine};
+use core::fmt;
+
+/// The number of bytes shown from the top of the stack
+pub const STACK_BYTES: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateDump {
+    pub registers: TraceLine,
+    pub interrupts: InterruptState,
+    pub halted: bool,
+    pub rom_bank: u16,
+    pub ram_bank: u8,
+    pub wram_bank: usize,
+    pub vram_bank: usize,
+    pub ly: u8,
+    /// The bytes starting at SP
+    pub stack: [u8; STACK_BYTES],
+}
+
+impl fmt::Display for StateDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+        writeln!(
+            f,
+            "AF:{:02X}{:02X} BC:{:02X}{:02X} DE:{:02X}{:02X} HL:{:02X}{:02X} SP:{:04X} PC:{:04X}{}",
+            r.a,
+            r.f,
+            r.b,
+            r.c,
+            r.d,
+            r.e,
+            r.h,
+            r.l,
+            r.sp,
+            r.pc,
+            if self.halted { " (halted)" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "IE:{:02X} IF:{:02X} IME:{}",
+            self.interrupts.enabled, self.interrupts.requested, self.interrupts.ime as u8
+        )?;
+        writeln!(
+            f,
+            "ROM bank:{:03X} RAM bank:{:X} WRAM bank:{} VRAM bank:{} LY:{}",
+            self.rom_bank, self.ram_bank, self.wram_bank, self.vram_bank, self.ly
+        )?;
+        write!(f, "Stack:")?;
+        for v in self.stack.iter() {
+            write!(f, " {:02X}", v)?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StateDump;
+    use crate::cpu::{InterruptState, TraceLine};
+
+    #[test]
+    fn format_dump() {
+        let dump = StateDump {
+            registers: TraceLine {
+                a: 0x01,
+                f: 0xB0,
+                b: 0x00,
+                c: 0x13,
+                d: 0x00,
+                e: 0xD8,
+                h: 0x01,
+                l: 0x4D,
+                sp: 0xDFF0,
+                pc: 0x0150,
+                pcmem: [0x76, 0, 0, 0],
+            },
+            interrupts: InterruptState {
+                enabled: 0x01,
+                requested: 0xE0,
+                ime: false,
+            },
+            halted: true,
+            rom_bank: 1,
+            ram_bank: 0,
+            wram_bank: 1,
+            vram_bank: 0,
+            ly: 144,
+            stack: [0x50, 0x01, 0x00, 0x00, 0x12, 0x34, 0xFF, 0x00],
+        };
+        assert_eq!(
+            dump.to_string(),
+            "AF:01B0 BC:0013 DE:00D8 HL:014D SP:DFF0 PC:0150 (halted)\n\
+             IE:01 IF:E0 IME:0\n\
+             ROM bank:001 RAM bank:0 WRAM bank:1 VRAM bank:0 LY:144\n\
+             Stack: 50 01 00 00 12 34 FF 00\n"
+        );
+    }
+}
@@ -1,3 +1,23 @@
+use crate::StrResult;
+
+// The cycles TIMA reads 0 after it overflows
+const RELOAD_DELAY: u32 = 4;
+
+/// A snapshot of the timer registers
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimerState {
+    /// The whole internal divider, of which DIV (0xFF04) is the upper byte
+    pub div_counter: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    /// Whether TIMA overflowed and reads 0 until it is reloaded from TMA, four cycles later
+    pub overflow_pending: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Timer {
     divider: u8,
     counter: u8,
@@ -6,6 +26,8 @@ pub struct Timer {
     step: u32,
     internalcnt: u32,
     internaldiv: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    overflow_pending: bool,
     pub interrupt: u8,
 }
 
@@ -19,6 +41,7 @@ impl Timer {
             step: 1024,
             internalcnt: 0,
             internaldiv: 0,
+            overflow_pending: false,
             interrupt: 0,
         }
     }
@@ -44,10 +67,15 @@ impl Timer {
     pub fn wb(&mut self, a: u16, v: u8) {
         match a {
             0xFF04 => {
+                // Resets the whole internal divider, which also drives TIMA
                 self.divider = 0;
+                self.internaldiv = 0;
+                self.internalcnt = 0;
             }
             0xFF05 => {
+                // Cancels a pending reload and its interrupt
                 self.counter = v;
+                self.overflow_pending = false;
             }
             0xFF06 => {
                 self.modulo = v;
@@ -60,11 +88,53 @@ impl Timer {
                     3 => 256,
                     _ => 1024,
                 };
+                // A shorter period does not make up for the time already counted
+                self.internalcnt %= self.step;
             }
             _ => panic!("Timer does not handler write {:4X}", a),
         };
     }
 
+    pub fn state(&self) -> TimerState {
+        TimerState {
+            div_counter: (self.divider as u16) << 8 | self.internaldiv as u16,
+            tima: self.counter,
+            tma: self.modulo,
+            tac: self.rb(0xFF07),
+            overflow_pending: self.overflow_pending,
+        }
+    }
+
+    /// Whether a timer from a save state can be used
+    pub fn check_state(&self) -> StrResult<()> {
+        if !matches!(self.step, 16 | 64 | 256 | 1024)
+            || self.internalcnt >= self.step
+            || self.internaldiv >= 256
+            || self.interrupt & !0x04 != 0
+            || (self.overflow_pending && self.counter != 0)
+        {
+            return Err("Invalid timer state");
+        }
+        Ok(())
+    }
+
+    /// Sets DIV without resetting the internal divider, as a write to 0xFF04 would
+    pub fn set_divider(&mut self, v: u8) {
+        self.divider = v;
+    }
+
+    /// How many CPU cycles until TIMA overflows and requests an interrupt
+    pub fn cycles_until_interrupt(&self) -> Option<u32> {
+        if self.overflow_pending {
+            return Some(RELOAD_DELAY.saturating_sub(self.internalcnt));
+        }
+        if !self.enabled {
+            return None;
+        }
+        let increments = 0xFF - self.counter as u32;
+        Some(self.step - self.internalcnt + increments * self.step + RELOAD_DELAY)
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
         self.internaldiv += ticks;
         while self.internaldiv >= 256 {
@@ -74,15 +144,79 @@ impl Timer {
 
         if self.enabled {
             self.internalcnt += ticks;
-
-            while self.internalcnt >= self.step {
-                self.counter = self.counter.wrapping_add(1);
-                if self.counter == 0 {
-                    self.counter = self.modulo;
-                    self.interrupt |= 0x04;
-                }
-                self.internalcnt -= self.step;
+        }
+        loop {
+            // The overflow was the last increment, so the time since then is in internalcnt
+            if self.overflow_pending && (self.internalcnt >= RELOAD_DELAY || !self.enabled) {
+                self.overflow_pending = false;
+                self.counter = self.modulo;
+                self.interrupt |= 0x04;
             }
+            if !self.enabled || self.internalcnt < self.step {
+                break;
+            }
+            self.counter = self.counter.wrapping_add(1);
+            self.overflow_pending = self.counter == 0;
+            self.internalcnt -= self.step;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Timer;
+
+    #[test]
+    fn invalid_state() {
+        let mut timer = Timer::new();
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(100);
+        assert!(timer.check_state().is_ok());
+
+        // With no period TIMA would never stop counting
+        let broken = Timer {
+            step: 0,
+            ..timer.clone()
+        };
+        assert!(broken.check_state().is_err());
+        let broken = Timer {
+            internalcnt: 16,
+            ..timer.clone()
+        };
+        assert!(broken.check_state().is_err());
+        let broken = Timer {
+            internaldiv: 256,
+            ..timer
+        };
+        assert!(broken.check_state().is_err());
+    }
+
+    #[test]
+    fn delayed_reload() {
+        let mut timer = Timer::new();
+        timer.wb(0xFF05, 0xFF);
+        timer.wb(0xFF06, 0x80);
+        timer.wb(0xFF07, 0x05);
+        timer.do_cycle(16);
+        assert_eq!((timer.rb(0xFF05), timer.interrupt), (0, 0));
+        assert!(timer.state().overflow_pending);
+        assert_eq!(timer.cycles_until_interrupt(), Some(4));
+        timer.do_cycle(4);
+        assert_eq!((timer.rb(0xFF05), timer.interrupt), (0x80, 0x04));
+        assert!(!timer.state().overflow_pending);
+
+        // Writing TIMA in between cancels the reload and the interrupt
+        timer.interrupt = 0;
+        timer.wb(0xFF04, 0);
+        timer.wb(0xFF05, 0xFF);
+        timer.do_cycle(16);
+        timer.wb(0xFF05, 0x12);
+        timer.do_cycle(4);
+        assert_eq!((timer.rb(0xFF05), timer.interrupt), (0x12, 0));
+
+        // Larger steps still reload at the right time
+        timer.wb(0xFF05, 0xFE);
+        timer.do_cycle(40);
+        assert_eq!((timer.rb(0xFF05), timer.interrupt), (0x80, 0x04));
+    }
+}
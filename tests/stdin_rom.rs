@@ -0,0 +1,35 @@
+#![cfg(feature = "gui")]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn run_with_stdin_rom(rom: &[u8]) -> Option<i32> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rboy"))
+        .args(["--test-mode", "--skip-checksum", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(rom).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    // Test mode keeps running, as there are no commands to read
+    let status = child.try_wait().unwrap();
+    if status.is_none() {
+        child.kill().unwrap();
+        let _ = child.wait();
+    }
+    status.map(|status| status.code().unwrap())
+}
+
+#[test]
+fn rom_from_stdin() {
+    // An endless loop at the entry point
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+    assert_eq!(run_with_stdin_rom(&rom), None);
+
+    assert_eq!(run_with_stdin_rom(&[0; 0x100]), Some(2));
+}